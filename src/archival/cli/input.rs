@@ -3,6 +3,9 @@ use std::fmt;
 use std::fmt::Formatter;
 use std::path::PathBuf;
 use std::str::Chars;
+use crate::archival::files::solid::DEFAULT_BLOCK_SIZE;
+use crate::constants::{parse_bytes, GIGABYTE, KILOBYTE, MEGABYTE, TERABYTE};
+use crate::format_bytes;
 
 #[derive(PartialEq, Debug)]
 pub enum Token {
@@ -17,6 +20,14 @@ pub enum Mode {
     Add,
     Extract,
     Profile,
+    List,
+    Verify,
+    Remove,
+    Keygen,
+    Share,
+    Rekey,
+    VerifySignature,
+    Repair,
 }
 
 impl Mode {
@@ -25,6 +36,14 @@ impl Mode {
             "add" | "a" => Mode::Add,
             "extract" | "x" => Mode::Extract,
             "profile" | "p" => Mode::Profile,
+            "list" | "l" => Mode::List,
+            "verify" | "v" => Mode::Verify,
+            "remove" | "r" => Mode::Remove,
+            "keygen" | "g" => Mode::Keygen,
+            "share" | "s" => Mode::Share,
+            "rekey" | "k" => Mode::Rekey,
+            "verify-signature" => Mode::VerifySignature,
+            "repair" => Mode::Repair,
             _ => {
                 todo!("incorrect mode specification error")
             }
@@ -32,13 +51,58 @@ impl Mode {
     }
 }
 
+#[derive(Default)]
 pub struct Command {
     // whether to add the input path to an output archive,
     // or to extract the input archive into the output path
     pub mode: Option<Mode>,
     pub input: Option<PathBuf>,
     pub output: Option<PathBuf>,
-    pub key: Option<String>
+    /// the password the archive is encrypted with, given with `-k`
+    pub key: Option<String>,
+    /// the password an archive is re-keyed with, given with `--new-key`
+    pub new_key: Option<String>,
+    /// argon2id memory a re-keyed password is derived with, given with `--kdf-memory <size>`
+    pub kdf_memory: Option<u64>,
+    /// argon2id passes a re-keyed password is derived with, given with `--kdf-passes <n>`
+    pub kdf_passes: Option<u32>,
+    /// public key files of those the archive is encrypted for, given with `-r`
+    pub recipients: Vec<PathBuf>,
+    /// public key files of recipients to remove from an archive, given with `--revoke`
+    pub revoked: Vec<PathBuf>,
+    /// secret key file an archive is opened with, given with `-i`
+    pub identity: Option<PathBuf>,
+    /// secret key file the archive is signed with, given with `--sign`
+    pub sign: Option<PathBuf>,
+    /// public key file a signature is checked against, given with `--trust`
+    pub trust: Option<PathBuf>,
+    /// generate a key pair for signing rather than encryption, given with `--signing`
+    pub signing: bool,
+    /// percentage of the archive covered by parity, given with `--recovery <percent>`
+    pub recovery: Option<u8>,
+    /// encrypt the names and sizes of entries along with their data, given with `--encrypt-headers`
+    pub encrypt_headers: bool,
+    /// whether a missing password may be asked for on the terminal,
+    /// set for commands given on the command line
+    pub prompt: bool,
+    /// paths or glob patterns selecting which entries to operate on,
+    /// given with `-f`. when empty, every entry is selected
+    pub filters: Vec<String>,
+    /// skip restoring the owner and group of extracted files, given with `--no-owner`.
+    /// only root can give files away to other users, so they are never restored otherwise
+    pub no_owner: bool,
+    /// keep extracting past damaged entries and report what was lost, given with `--salvage`
+    pub salvage: bool,
+    /// split files into content defined chunks which are only stored once, given with `--dedup`
+    pub dedup: bool,
+    /// split the output archive into volumes no larger than this, given with `-v <size>`
+    pub volume_size: Option<u64>,
+    /// compress small files together in blocks of this size, given with `--solid [size]`
+    pub solid_block_size: Option<u64>,
+    /// how many threads compress files at once, given with `-j <n>`
+    pub jobs: Option<usize>,
+    /// what was wrong with the command when it couldn't be parsed, reported instead of running it
+    pub error: Option<String>,
     // todo : more options when the archiver become more advanced
 }
 
@@ -84,14 +148,17 @@ impl Command {
     fn tokenize(s: String) -> Vec<Token> {
         let mut chars = s.chars();
         let mut tokens: Vec<Token> = vec![];
+        // how many of the words to come are the values of the last flag
+        let mut values: usize = 0;
 
         while chars.clone().count() > 0 {
             match chars.next() {
                 // starts with '-', this is a flag
                 Some('-') => {
-                    tokens.push(Token::Flag(
-                        Self::consume_until_whitespace(&mut chars)
-                    ));
+                    let mut flag = String::from("-");
+                    flag.push_str(&Self::consume_until_whitespace(&mut chars));
+                    values = Self::flag_values(&flag);
+                    tokens.push(Token::Flag(flag));
                 }
 
                 // starts with '"' this is a quoted string
@@ -100,40 +167,88 @@ impl Command {
                         Self::consume_until(&mut chars, '"')
                     ));
                     Self::consume_until_whitespace(&mut chars);
+                    values = values.saturating_sub(1);
                 }
 
-                // starts with any other char either Ark or Mode
+                // starts with any other char either Ark or Mode,
+                // unless it is the value of a flag, such as the password in `-k l`
                 Some(c) => {
                     let mut res = String::new();
                     res.push(c);
                     res.push_str(&Self::consume_until_whitespace(&mut chars));
-
-                    match res.to_lowercase().as_str() {
-                        "ark" => tokens.push(Token::Ark),
-                        "a" | "add" => tokens.push(Token::Mode(String::from("add"))),
-                        "x" | "extract" => tokens.push(
-                            Token::Mode(String::from("extract"))
-                        ),
-                        "p" | "profile" => tokens.push(
-                            Token::Mode(String::from("profile"))
-                        ),
-                        _ => tokens.push(Token::GenericString(res))
-                    }
+                    tokens.push(match values {
+                        0 => Self::word_token(res),
+                        _ => Token::GenericString(res),
+                    });
+                    values = values.saturating_sub(1);
                 }
                 _ => {
                     // eof, tokenization complete
                 }
             }
         }
-        println!("Tokens: \n\t{:?}", tokens);
         tokens
     }
 
+    /// how many words following `flag` are its values. every word after `-f` is a
+    /// path or pattern, and `--solid` may or may not be given a size
+    fn flag_values(flag: &str) -> usize {
+        match flag {
+            "-f" => usize::MAX,
+            "-k" | "--new-key" | "--kdf-memory" | "--kdf-passes" | "-r" | "--revoke" | "-i"
+            | "--sign" | "--trust" | "--recovery" | "-v" | "-j" | "--solid" => 1,
+            _ => 0,
+        }
+    }
+
+    /// classify an unquoted word as either Ark, a Mode, or a generic string
+    fn word_token(word: String) -> Token {
+        match word.to_lowercase().as_str() {
+            "ark" => Token::Ark,
+            "a" | "add" => Token::Mode(String::from("add")),
+            "x" | "extract" => Token::Mode(String::from("extract")),
+            "p" | "profile" => Token::Mode(String::from("profile")),
+            "l" | "list" => Token::Mode(String::from("list")),
+            "v" | "verify" => Token::Mode(String::from("verify")),
+            "r" | "remove" => Token::Mode(String::from("remove")),
+            "g" | "keygen" => Token::Mode(String::from("keygen")),
+            "s" | "share" => Token::Mode(String::from("share")),
+            "k" | "rekey" => Token::Mode(String::from("rekey")),
+            "verify-signature" => Token::Mode(String::from("verify-signature")),
+            "repair" => Token::Mode(String::from("repair")),
+            _ => Token::GenericString(word)
+        }
+    }
+
     /// parse an input string into usable information for the archiver
     /// source string should be of the following format:
-    /// `Ark (add/a | extract/x) "input/file/path" "output/file/path" -options...`
+    /// `Ark (add/a | extract/x | list/l | verify/v | remove/r | repair) "input/file/path" "output/file/path" -options...`
+    ///
+    /// keys are managed with `keygen/g`, `share/s`, `rekey/k` and `verify-signature`,
+    /// which take only an input path.
+    ///
+    /// when listing or verifying, the output path may be omitted.
+    /// when removing or repairing, it defaults to replacing the input archive
     pub fn new(src: String) -> Command {
-        let mut toks = Self::tokenize(src)
+        Self::from_tokens(Self::tokenize(src))
+    }
+
+    /// build a command from process arguments, which the shell has already
+    /// split and unquoted. e.g. `Ark verify backup.ark` gives `["verify", "backup.ark"]`
+    pub fn from_args(args: Vec<String>) -> Command {
+        let mut tokens = vec![Token::Ark];
+        for (i, arg) in args.into_iter().enumerate() {
+            tokens.push(match i {
+                0 => Self::word_token(arg),
+                _ if arg.len() > 1 && arg.starts_with('-') => Token::Flag(arg),
+                _ => Token::QuotedString(arg),
+            });
+        }
+        Self::from_tokens(tokens)
+    }
+
+    fn from_tokens(tokens: Vec<Token>) -> Command {
+        let mut toks = tokens
             .into_iter()
             .rev()
             .collect::<Vec<Token>>();
@@ -157,16 +272,21 @@ impl Command {
             _ => None
         };
 
-        let output = match toks.pop() {
-            Some(Token::QuotedString(s)) => Some(PathBuf::from(s)),
+        // the output is optional, so leave any flag in its place for later
+        let output = match toks.last() {
+            Some(Token::QuotedString(_)) => match toks.pop() {
+                Some(Token::QuotedString(s)) => Some(PathBuf::from(s)),
+                _ => unreachable!()
+            },
             _ => None
         };
 
-        let reader = Command {
+        let mut reader = Command {
             mode,
             input,
             output,
-            key: None
+            prompt: true,
+            ..Default::default()
         };
 
         // drain all remaining options
@@ -179,13 +299,136 @@ impl Command {
 
             match flag.as_ref() {
                 "-k" => {
-                    todo!("get the provided key")
+                    match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => reader.key = Some(s),
+                        _ => return reader.invalid("-k needs the archive's password")
+                    }
+                }
+                "--new-key" => {
+                    match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => reader.new_key = Some(s),
+                        _ => return reader.invalid("--new-key needs the new password")
+                    }
+                }
+                "--kdf-memory" => {
+                    let size = match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => parse_bytes(&s),
+                        _ => None
+                    };
+                    match size {
+                        Some(size) => reader.kdf_memory = Some(size),
+                        None => return reader.invalid("--kdf-memory needs an amount of memory such as 64MB")
+                    }
+                }
+                "--kdf-passes" => {
+                    let passes = match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => s.parse().ok(),
+                        _ => None
+                    };
+                    match passes {
+                        Some(passes) => reader.kdf_passes = Some(passes),
+                        None => return reader.invalid("--kdf-passes needs a number of passes")
+                    }
+                }
+                "-f" => {
+                    // every string following the flag is a path or pattern
+                    while let Some(Token::QuotedString(_) | Token::GenericString(_)) = toks.last() {
+                        match toks.pop() {
+                            Some(Token::QuotedString(s) | Token::GenericString(s)) => {
+                                reader.filters.push(s)
+                            }
+                            _ => unreachable!()
+                        }
+                    }
+                    if reader.filters.is_empty() {
+                        return reader.invalid("-f needs at least one path or pattern")
+                    }
+                }
+                "-r" | "--revoke" | "-i" | "--sign" | "--trust" => {
+                    let path = match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => PathBuf::from(s),
+                        _ => return reader.invalid(format!("{} needs the path of a key file", flag))
+                    };
+                    match flag.as_ref() {
+                        "-r" => reader.recipients.push(path),
+                        "--revoke" => reader.revoked.push(path),
+                        "--sign" => reader.sign = Some(path),
+                        "--trust" => reader.trust = Some(path),
+                        _ => reader.identity = Some(path),
+                    }
+                }
+                "--recovery" => {
+                    let percent = match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => {
+                            s.trim_end_matches('%').parse::<u8>().ok()
+                        }
+                        _ => None
+                    };
+                    match percent {
+                        Some(percent @ 1..=100) => reader.recovery = Some(percent),
+                        _ => return reader.invalid("--recovery needs a percentage from 1 to 100")
+                    }
+                }
+                "--signing" => {
+                    reader.signing = true;
+                }
+                "--encrypt-headers" => {
+                    reader.encrypt_headers = true;
+                }
+                "--no-owner" => {
+                    reader.no_owner = true;
+                }
+                "--salvage" => {
+                    reader.salvage = true;
+                }
+                "--dedup" => {
+                    reader.dedup = true;
+                }
+                "-v" => {
+                    let size = match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => parse_bytes(&s),
+                        _ => None
+                    };
+                    match size {
+                        Some(size) => reader.volume_size = Some(size),
+                        None => return reader.invalid("-v needs a volume size such as 700MB")
+                    }
+                }
+                "-j" => {
+                    let jobs = match toks.pop() {
+                        Some(Token::QuotedString(s) | Token::GenericString(s)) => s.parse::<usize>().ok(),
+                        _ => None
+                    };
+                    match jobs {
+                        Some(jobs) if jobs > 0 => reader.jobs = Some(jobs),
+                        _ => return reader.invalid("-j needs a number of jobs, at least 1")
+                    }
+                }
+                "--solid" => {
+                    // the block size is optional
+                    let size = match toks.last() {
+                        Some(Token::QuotedString(_) | Token::GenericString(_)) => match toks.pop() {
+                            Some(Token::QuotedString(s) | Token::GenericString(s)) => parse_bytes(&s),
+                            _ => unreachable!()
+                        },
+                        _ => Some(DEFAULT_BLOCK_SIZE)
+                    };
+                    match size {
+                        Some(size) => reader.solid_block_size = Some(size),
+                        None => return reader.invalid("--solid takes a block size such as 4MB")
+                    }
                 }
                 _ => { todo!("unknown flag error") }
             }
         }
         reader
     }
+
+    /// stop parsing, recording why the command can't be run
+    fn invalid(mut self, error: impl Into<String>) -> Command {
+        self.error = Some(error.into());
+        self
+    }
 }
 
 impl fmt::Display for Command {
@@ -193,6 +436,14 @@ impl fmt::Display for Command {
         let mode = match &self.mode {
             Some(Mode::Add) => "add",
             Some(Mode::Extract) => "extract",
+            Some(Mode::List) => "list",
+            Some(Mode::Verify) => "verify",
+            Some(Mode::Remove) => "remove",
+            Some(Mode::Keygen) => "keygen",
+            Some(Mode::Share) => "share",
+            Some(Mode::Rekey) => "rekey",
+            Some(Mode::VerifySignature) => "verify-signature",
+            Some(Mode::Repair) => "repair",
             _ => "None"
         };
         let input = match &self.input {
@@ -207,6 +458,61 @@ impl fmt::Display for Command {
         writeln!(f, "Input:")?;
         writeln!(f, "  Mode   : {}", mode)?;
         writeln!(f, "  Input  : {}", input)?;
-        writeln!(f, "  Output : {}", output)
+        writeln!(f, "  Output : {}", output)?;
+        if !self.filters.is_empty() {
+            writeln!(f, "  Filter : {}", self.filters.join(", "))?;
+        }
+        if self.key.is_some() {
+            writeln!(f, "  Key    : given")?;
+        }
+        if self.new_key.is_some() {
+            writeln!(f, "  New key: given")?;
+        }
+        if let Some(memory) = self.kdf_memory {
+            writeln!(f, "  Memory : {}", format_bytes!(memory))?;
+        }
+        if let Some(passes) = self.kdf_passes {
+            writeln!(f, "  Passes : {}", passes)?;
+        }
+        for recipient in &self.recipients {
+            writeln!(f, "  For    : {}", recipient.display())?;
+        }
+        for revoked in &self.revoked {
+            writeln!(f, "  Revoke : {}", revoked.display())?;
+        }
+        if let Some(identity) = &self.identity {
+            writeln!(f, "  Key    : {}", identity.display())?;
+        }
+        if let Some(sign) = &self.sign {
+            writeln!(f, "  Sign   : {}", sign.display())?;
+        }
+        if let Some(trust) = &self.trust {
+            writeln!(f, "  Trust  : {}", trust.display())?;
+        }
+        if let Some(percent) = self.recovery {
+            writeln!(f, "  Parity : {}%", percent)?;
+        }
+        if self.encrypt_headers {
+            writeln!(f, "  Names  : encrypted")?;
+        }
+        if self.no_owner {
+            writeln!(f, "  Owner  : not restored")?;
+        }
+        if self.salvage {
+            writeln!(f, "  Salvage: enabled")?;
+        }
+        if self.dedup {
+            writeln!(f, "  Dedup  : enabled")?;
+        }
+        if let Some(size) = self.volume_size {
+            writeln!(f, "  Volume : {}", format_bytes!(size))?;
+        }
+        if let Some(size) = self.solid_block_size {
+            writeln!(f, "  Solid  : {} blocks", format_bytes!(size))?;
+        }
+        if let Some(jobs) = self.jobs {
+            writeln!(f, "  Jobs   : {}", jobs)?;
+        }
+        Ok(())
     }
 }
\ No newline at end of file
@@ -34,6 +34,6 @@ pub trait FmtProgress {
             progress,
             message
         );
-        println!("{}", res);
+        eprintln!("{}", res);
     }
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::io::Read;
+use sha2::{Digest, Sha256};
+use crate::constants::{GIGABYTE, KILOBYTE, MEGABYTE, TERABYTE};
+use crate::format_bytes;
+
+/// the identity of a chunk, the sha-256 of its uncompressed contents
+pub type ChunkHash = [u8; 32];
+
+/// chunks are never cut shorter than this, unless the data runs out
+pub const MIN_CHUNK_SIZE: usize = 2 * 1024;
+/// chunks are always cut once they reach this size
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// number of hash bits which must be zero to cut a chunk, giving 8kb chunks on average
+const BOUNDARY_BITS: u32 = 13;
+const BOUNDARY_MASK: u64 = ((1 << BOUNDARY_BITS) - 1) << (64 - BOUNDARY_BITS);
+
+/// a fixed table of pseudo random values, one per byte value, used by the rolling hash.
+/// it must never change, or the same data would be cut into different chunks
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x4172_6b20_4368_756e;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// split `data` into content defined chunks.
+///
+/// boundaries are chosen by a rolling (gear) hash over the data itself rather than at
+/// fixed offsets, so an insertion near the start of a file only changes the chunks
+/// around it and every later chunk is still found again
+pub fn chunk(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+
+    while start < data.len() {
+        let end = next_boundary(&data[start..]) + start;
+        chunks.push(&data[start..end]);
+        start = end;
+    }
+
+    chunks
+}
+
+/// cuts everything read from a reader into the same chunks `chunk` would,
+/// while only holding a few chunks worth of it in memory
+pub struct StreamChunker<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    /// where the next chunk starts within the buffer
+    start: usize,
+    /// whether the reader has run out
+    done: bool,
+}
+
+impl<R: Read> StreamChunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, buffer: Vec::with_capacity(4 * MAX_CHUNK_SIZE), start: 0, done: false }
+    }
+
+    /// the next chunk of the data, or `None` once it has all been chunked
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        // a boundary is never looked for further ahead than the largest chunk,
+        // so as long as one fits in the buffer it is cut exactly where `chunk` cuts it
+        if self.buffer.len() - self.start < MAX_CHUNK_SIZE && !self.done {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+            let wanted = (4 * MAX_CHUNK_SIZE - self.buffer.len()) as u64;
+            let read = self.reader.by_ref().take(wanted).read_to_end(&mut self.buffer)?;
+            self.done = (read as u64) < wanted;
+        }
+        if self.start == self.buffer.len() {
+            return Ok(None)
+        }
+
+        let end = self.start + next_boundary(&self.buffer[self.start..]);
+        let range = self.start..end;
+        self.start = end;
+        Ok(Some(&self.buffer[range]))
+    }
+}
+
+/// the length of the first chunk in `data`
+fn next_boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len()
+    }
+
+    let mut hash = 0u64;
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    for (i, byte) in data[..end].iter().enumerate().skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if hash & BOUNDARY_MASK == 0 {
+            return i + 1
+        }
+    }
+    end
+}
+
+/// calculate the identity of a chunk
+pub fn hash(chunk: &[u8]) -> ChunkHash {
+    Sha256::digest(chunk).into()
+}
+
+/// how much space deduplicating a set of files is expected to save
+#[derive(Debug, Default)]
+pub struct DedupEstimate {
+    pub files: usize,
+    pub chunks: usize,
+    pub unique_chunks: usize,
+    pub total_bytes: u64,
+    pub unique_bytes: u64,
+}
+
+impl DedupEstimate {
+    /// the bytes which would not need to be stored, as a percentage of the total
+    pub fn savings(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0
+        }
+        (self.total_bytes - self.unique_bytes) as f64 / self.total_bytes as f64 * 100.0
+    }
+}
+
+impl fmt::Display for DedupEstimate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Deduplication estimate for {} files:", self.files)?;
+        writeln!(f, "  - chunks  -> {} ({} unique)", self.chunks, self.unique_chunks)?;
+        writeln!(
+            f, "  - size    -> {} ({} once deduplicated)",
+            format_bytes!(self.total_bytes), format_bytes!(self.unique_bytes)
+        )?;
+        write!(f, "  - savings -> {:.1}%", self.savings())
+    }
+}
@@ -1,4 +1,12 @@
-use std::path::PathBuf;
+use std::io;
+use std::io::Read;
+use crate::archival::files::dedup::CHUNKED;
+use crate::archival::files::solid::SOLID;
+use crate::constants::MEGABYTE;
+
+/// files are compressed in blocks of this many bytes, each on its own,
+/// so no more than a block of a file is ever held in memory
+pub const BLOCK_SIZE: u64 = MEGABYTE;
 
 pub struct Compressor {
     pub data: Vec<u8>,
@@ -15,6 +23,38 @@ impl Compressor {
         }
     }
 
+    /// a short human readable description of a compression method
+    pub fn method_name(method: u8) -> String {
+        let mut names = vec![];
+        if method & (1 << 7) != 0 && method & (1 << 6) == 0 { names.push("rle"); }
+        if method & (1 << 6) != 0 { names.push("rle2"); }
+
+        if names.is_empty() {
+            names.push("stored");
+        }
+        if method & CHUNKED != 0 { names.push("chunked"); }
+        if method & SOLID != 0 { names.push("solid"); }
+        names.join("+")
+    }
+
+    /// read the next block of a file from `reader`, which is only
+    /// shorter than `BLOCK_SIZE` at the end of the file and empty past it
+    pub fn read_block(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+        let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+        reader.take(BLOCK_SIZE).read_to_end(&mut block)?;
+        Ok(block)
+    }
+
+    /// the number of blocks `size` bytes are compressed as
+    pub fn block_count(size: u64) -> u64 {
+        size.div_ceil(BLOCK_SIZE)
+    }
+
+    /// the size of the block at `index` once decompressed, for a file of `size` bytes
+    pub fn block_len(size: u64, index: u64) -> u64 {
+        BLOCK_SIZE.min(size - index * BLOCK_SIZE)
+    }
+
     pub fn compress(&mut self) -> Vec<u8>{
         let (
             rle,
@@ -39,7 +79,7 @@ impl Compressor {
         if rle && !rle2 { self.rle(); }
         if rle2 { self.rle_two_byte(); }
 
-        self.data.clone()
+        std::mem::take(&mut self.data)
     }
 
     pub fn decompress(&mut self) -> Vec<u8> {
@@ -66,6 +106,6 @@ impl Compressor {
         if rle && !rle2 { self.decompress_rle(); }
         if rle2 { self.decompress_rle_two_byte(); }
 
-        self.data.clone()
+        std::mem::take(&mut self.data)
     }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 pub mod profiler;
+pub mod chunker;
 pub mod file_compressor;
 pub mod rle;
 pub mod arithmetic;
\ No newline at end of file
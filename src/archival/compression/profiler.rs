@@ -2,6 +2,9 @@ use std::{fmt, fs};
 use std::cmp::min;
 use std::ffi::OsStr;
 use std::fmt::Formatter;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::time::Instant;
 use crate::constants::{GIGABYTE, MEGABYTE};
@@ -9,7 +12,6 @@ use crate::constants::{GIGABYTE, MEGABYTE};
 /// a profiler for determining useful methods for compressing a file
 pub struct Profiler {
     pub file: PathBuf,
-    pub data: Vec<u8>,
     pub start: Option<Instant>,
 
     // RLE data
@@ -22,7 +24,6 @@ impl Default for Profiler {
     fn default() -> Self {
         Self {
             file: PathBuf::new(),
-            data: vec![],
             start: None,
             rle: false,
             two_byte_rle: false,
@@ -43,14 +44,26 @@ impl fmt::Debug for Profiler {
 
 impl Profiler {
     pub fn new(file: PathBuf) -> Self {
-        let data = fs::read(&file).unwrap();
         Self {
             file,
-            data,
             ..Default::default()
         }
     }
 
+    /// read `len` bytes of the file starting at each of `offsets`,
+    /// so large files are profiled without reading all of them
+    fn read_samples(&self, offsets: &[u64], len: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut file = File::open(&self.file)?;
+        let mut samples = vec![];
+        for offset in offsets {
+            file.seek(SeekFrom::Start(*offset))?;
+            let mut sample = vec![0; len];
+            file.read_exact(&mut sample)?;
+            samples.push(sample);
+        }
+        Ok(samples)
+    }
+
     /// Checks the file data to determine whether
     /// run length encoding is worth using.
     ///
@@ -87,33 +100,35 @@ impl Profiler {
             _ => return,
         }
 
-        self.avg_run_len = if self.data.len() > MEGABYTE as usize {
-            let sample_size: usize = min(2048, self.data.len() / 4);
-            let mut start = self.data[..sample_size].to_vec();
-            let mid = self.data.len() / 2;
-            let mut mid = self.data[mid..sample_size + mid].to_vec();
-            let mut end = self.data[self.data.len() - sample_size..].to_vec();
-
-            let size = start.len() + mid.len() + end.len();
-
-            start.dedup();
-            mid.dedup();
-            end.dedup();
+        let len = self.file.metadata().map(|m| m.len()).unwrap_or(0);
+        self.avg_run_len = if len > MEGABYTE {
+            let sample_size = min(2048, len / 4);
+            let samples = self.read_samples(&[0, len / 2, len - sample_size], sample_size as usize);
+            let mut samples = match samples {
+                Ok(samples) => samples,
+                Err(_) => return,
+            };
 
-            let runs = start.len() + mid.len() + end.len();
+            let size = samples.iter().map(Vec::len).sum::<usize>();
+            samples.iter_mut().for_each(Vec::dedup);
+            let runs = samples.iter().map(Vec::len).sum::<usize>();
 
             size as f32 / runs.max(1) as f32
         } else {
+            let data = match fs::read(&self.file) {
+                Ok(data) => data,
+                Err(_) => return,
+            };
             let mut current = None;
             let mut runs = 0u64;
-            for byte in &self.data {
+            for byte in &data {
                 if current.is_none() || byte != current.unwrap() {
                     runs += 1;
                     current = Some(byte);
                 }
             }
 
-            self.data.len() as f32 / runs.max(1) as f32
+            data.len() as f32 / runs.max(1) as f32
         };
 
 
@@ -59,9 +59,9 @@ impl Compressor {
         let mut to_decode = self.data.iter().cloned().rev().collect::<Vec<u8>>();
         let mut res = Vec::with_capacity(to_decode.len() * 2);
 
-        while !to_decode.is_empty() {
-            let freq = to_decode.pop().unwrap();
-            let symbol = to_decode.pop().unwrap();
+        // a damaged stream may end part way through a run, which is dropped
+        // here and left for the size and checksum checks to report
+        while let (Some(freq), Some(symbol)) = (to_decode.pop(), to_decode.pop()) {
             res.extend(vec![symbol; freq as usize])
         }
 
@@ -73,11 +73,9 @@ impl Compressor {
         let mut to_decode = self.data.iter().cloned().rev().collect::<Vec<u8>>();
         let mut res = Vec::with_capacity(to_decode.len() * 3);
 
-        while !to_decode.is_empty() {
-            let freq_upper = to_decode.pop().unwrap();
-            let freq_lower = to_decode.pop().unwrap();
+        while let (Some(freq_upper), Some(freq_lower), Some(symbol)) =
+            (to_decode.pop(), to_decode.pop(), to_decode.pop()) {
             let freq = (freq_upper as u16) << 8 | freq_lower as u16;
-            let symbol = to_decode.pop().unwrap();
             res.extend(vec![symbol; freq as usize])
         }
 
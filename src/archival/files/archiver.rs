@@ -1,16 +1,37 @@
 use crate::archival::cli::input::{Command, Mode};
 use crate::archival::cli::output::FmtProgress;
+use crate::archival::compression::chunker::ChunkHash;
 use crate::archival::compression::file_compressor::Compressor;
 use crate::archival::compression::profiler::Profiler;
-use crate::archival::files::header::{ArchiveHeader, FileHeader, Header};
+use crate::archival::files::dedup::CHUNKED;
+use crate::archival::files::crypto::{Cipher, KeySlot};
+use crate::archival::files::directory::{CentralDirectory, DirectoryEntry, EntryKind, Footer};
+use crate::archival::files::filter::EntryFilter;
+use crate::archival::files::forward::HeldData;
+use crate::archival::files::header::{read_array, ArchiveHeader, FileHeader, Header};
+use crate::archival::files::integrity;
+use crate::archival::files::integrity::ChecksumWriter;
+use crate::archival::files::keys;
 use crate::archival::files::indexer::{ArchiveIndexer, FileRange};
+use crate::archival::files::metadata;
+use crate::archival::files::metadata::EntryMetadata;
+use crate::archival::files::paths;
+use crate::archival::files::pipeline::{ExtractPool, MemoryBudget, Pipeline, Plan, EXTRACT_MEMORY_BUDGET};
+use crate::archival::files::recovery::RECOVERY_MAGIC;
+use crate::archival::files::signature::SIGNATURE_MAGIC;
+use crate::archival::files::solid::{SolidBlock, SOLID};
+use crate::archival::files::stream::DESCRIBED;
+use crate::archival::files::volume::{is_stdio, split_base, volume_path, VolumeReader, VolumeWriter};
 use crate::constants::{GIGABYTE, KILOBYTE, MEGABYTE, TERABYTE};
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{copy, BufRead, BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::io::{stdin, stdout, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
 use std::time::Instant;
-use std::{cmp, fs};
+use std::fs;
 use crate::format_bytes;
+use ed25519_dalek::SigningKey;
 
 pub struct Archiver {
     pub mode: Mode,
@@ -29,15 +50,148 @@ pub struct Archiver {
 
     pub ranges: Vec<FileRange>,
     pub buffer_size: usize,
-
-    pub archive_reader: Option<BufReader<File>>,
-    pub archive_writer: Option<BufWriter<File>>,
+    /// input paths which are hard links to another input path
+    pub hard_links: HashMap<PathBuf, PathBuf>,
+
+    /// paths or glob patterns selecting which entries to operate on
+    pub filters: Vec<String>,
+    /// whether extracted files are given back to their original owner and group,
+    /// only when running as root and `--no-owner` wasn't given
+    pub restore_owner: bool,
+    /// whether extraction keeps going past damaged entries, given with `--salvage`
+    pub salvage: bool,
+    /// whether files are split into chunks which are only stored once
+    pub dedup: bool,
+    /// when set, files smaller than this are compressed together in solid blocks of this size
+    pub solid_block_size: Option<u64>,
+    /// how many threads compress files at once, given with `-j <n>`
+    pub jobs: usize,
+    /// the password the archive is encrypted with, given with `-k`
+    pub password: Option<String>,
+    /// the password an archive is re-keyed with, given with `--new-key`
+    pub new_password: Option<String>,
+    /// argon2id memory in bytes and passes a re-keyed password is derived with
+    pub kdf_memory: Option<u64>,
+    pub kdf_passes: Option<u32>,
+    /// secret key file the archive is signed with, given with `--sign`
+    pub sign: Option<PathBuf>,
+    /// the key read from `sign` once the archive is being written
+    pub signing_key: Option<SigningKey>,
+    /// public key file a signature is checked against, given with `--trust`
+    pub trust: Option<PathBuf>,
+    /// generate a key pair for signing rather than encryption, given with `--signing`
+    pub signing: bool,
+    /// percentage of the archive covered by parity, given with `--recovery <percent>`
+    pub recovery: Option<u8>,
+    /// public key files of those the archive is encrypted for, given with `-r`
+    pub recipients: Vec<PathBuf>,
+    /// public key files of recipients to remove from the archive, given with `--revoke`
+    pub revoked: Vec<PathBuf>,
+    /// secret key file the archive is opened with, given with `-i`
+    pub identity: Option<PathBuf>,
+    /// the key slots of the archive being read, when it is encrypted
+    pub encryption: Option<Vec<KeySlot>>,
+    /// the key data is sealed and opened with
+    pub cipher: Option<Cipher>,
+    /// whether every header and the central directory are sealed along with the data
+    pub encrypt_headers: bool,
+    /// whether a missing password may be asked for on the terminal
+    pub prompt: bool,
+
+    pub archive_reader: Option<BufReader<VolumeReader>>,
+    /// why the archive to be read couldn't be opened, reported once it is operated on
+    pub open_error: Option<String>,
+    pub archive_writer: Option<BufWriter<Box<dyn Write>>>,
+    /// when set, the output archive is split into volumes of this many bytes
+    pub volume_size: Option<u64>,
+    /// set when the volumes of a new archive were only partly swapped into place and
+    /// couldn't be put back, so what is left of them must not be removed
+    pub keep_temporary_output: bool,
+    /// the current write position within the output archive
+    pub archive_offset: u64,
+    /// the entries written so far, emitted at the end of the archive
+    pub directory: CentralDirectory,
+    /// file headers to write over those first written without their compressed size
+    /// and checksum, by their offset in the output archive
+    pub patches: Vec<(u64, Vec<u8>)>,
+    /// where each chunk of the archive being read can be found
+    pub chunks: HashMap<ChunkHash, u64>,
+    /// the chunks written to the output archive so far
+    pub written_chunks: HashSet<ChunkHash>,
+    /// where each solid block of the archive being read can be found
+    pub blocks: HashMap<u64, u64>,
+    /// the solid blocks written to the output archive so far
+    pub written_blocks: HashSet<u64>,
+    /// the files waiting to be written as the next solid block
+    pub solid_block: SolidBlock,
+    /// the last solid block read, along with its id
+    pub solid_cache: Option<(u64, Vec<u8>)>,
+    /// the chunks and solid blocks read from a stream, by their offset
+    pub held: HashMap<u64, HeldData>,
+    /// the entry whose header was just read from a stream, which can't go back to read it again
+    pub stream_entry: Option<DirectoryEntry>,
+    /// the compressed size and checksum found after the blocks of the last described
+    /// file read from its header alone, which didn't give them
+    pub described: Option<(u64, u32)>,
 
     pub files_compressed: usize,
 }
 
 pub struct ArchivalError(pub String);
 
+/// the entries which are only created once every file has been extracted
+#[derive(Default)]
+pub(crate) struct Deferred {
+    /// the names of the files extracted, which hard links can be made to
+    pub files: HashSet<String>,
+    pub directories: Vec<(PathBuf, DirectoryEntry)>,
+    pub symlinks: Vec<(PathBuf, DirectoryEntry)>,
+    pub hard_links: Vec<(PathBuf, DirectoryEntry)>,
+}
+
+/// create the file at `path` and write its contents with `write`,
+/// removing it again when they couldn't all be written
+pub(crate) fn write_file(
+    path: &PathBuf, write: impl FnOnce(&mut BufWriter<File>) -> Result<(), ArchivalError>
+) -> Result<(), ArchivalError>
+{
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    let file = match File::create(path) {
+        Ok(file) => file,
+        Err(e) => return Err(ArchivalError(
+            format!("Could not write \"{}\": {}", path.display(), e)
+        ))
+    };
+
+    let mut writer = BufWriter::new(file);
+    let res = write(&mut writer)
+        .and_then(|_| writer.flush().map_err(|e| ArchivalError(
+            format!("Could not write \"{}\": {}", path.display(), e)
+        )));
+    drop(writer);
+    if res.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    res
+}
+
+/// give an extracted entry the metadata it was archived with
+pub(crate) fn restore_entry_metadata(
+    path: &Path, entry: &DirectoryEntry, restore_owner: bool
+) -> Result<(), ArchivalError>
+{
+    if let Err(e) = entry.metadata.apply(path, restore_owner) {
+        return Err(ArchivalError(format!(
+            "Could not restore the metadata of \"{}\": {}{}",
+            path.display(), e,
+            if restore_owner { "\nuse --no-owner to skip restoring ownership" } else { "" }
+        )))
+    }
+    Ok(())
+}
+
 impl Archiver {
     /// construct a new `Archiver` from a `Command`
     pub fn new(command: Command) -> Archiver {
@@ -51,33 +205,67 @@ impl Archiver {
             None => {todo!("return error that no input was given")}
         };
 
-        let output = match command.output {
-            Some(output) => output,
-            None => { todo!("return error that no output was given") }
+        let output = match (command.output, &mode) {
+            (Some(output), _) => output,
+            // listing and verifying only read the archive, so no output is needed
+            (None, Mode::List | Mode::Verify | Mode::VerifySignature) => PathBuf::new(),
+            // keys are only ever changed in place
+            (None, Mode::Share | Mode::Rekey | Mode::Keygen) => PathBuf::new(),
+            // entries are removed from the archive in place
+            (None, Mode::Remove | Mode::Repair) => split_base(&input).unwrap_or(input.clone()),
+            (None, _) => { todo!("return error that no output was given") }
         };
 
 
+        // only the files being archived are indexed, every other
+        // mode reads its input as an archive, which may be a pipe
         let mut index = ArchiveIndexer::new(input.clone());
-        index.index_files();
+        if let Mode::Add | Mode::Profile = mode {
+            index.index_files();
+        }
         let files = index.contents;
         let file_count = index.file_count;
-        let total_bytes = index.bytes_count;
         let ranges = index.ranges;
+        let hard_links = index.hard_links;
+
+        // if archive is being read, create a buffer for reading it,
+        // reading every volume after the first of a split archive,
+        // or stdin from start to end when the input is "-"
+        let (archive_reader, open_error) = match mode {
+            Mode::Extract | Mode::List | Mode::Verify | Mode::VerifySignature
+            | Mode::Remove | Mode::Repair | Mode::Share | Mode::Rekey => {
+                let reader = match is_stdio(&input) {
+                    true => Ok(VolumeReader::from_stream(stdin().lock())),
+                    false => VolumeReader::open(&input),
+                };
+                match reader {
+                    Ok(reader) => (Some(BufReader::new(reader)), None),
+                    Err(e) => (None, Some(
+                        format!("Could not open archive \"{}\"\nreason: {}", input.display(), e)
+                    )),
+                }
+            },
+            _ => (None, None)
+        };
 
-        // if archive is being extracted, get the total archive size
-        let archive_size = match mode {
-            Mode::Extract => {
-                input.metadata().unwrap().len()
-            }
-            _ => 0
+        // if archive is being read, get the total archive size,
+        // which progress is then measured against
+        let archive_size = match &archive_reader {
+            Some(reader) => reader.get_ref().size(),
+            None => 0
+        };
+        let total_bytes = match &archive_reader {
+            Some(_) => archive_size as usize,
+            None => index.bytes_count,
         };
 
-        // if archive is being extracted, create a buffer for reading it
-        let archive_reader = match mode {
-            Mode::Extract => {
-                Some(BufReader::new(File::open(&input).unwrap()))
+        // a split archive keeps its volume size when rewritten in place
+        let volume_size = match (&archive_reader, &mode) {
+            (_, Mode::Add) | (None, _) => command.volume_size,
+            (Some(reader), _) => match reader.get_ref().is_split() {
+                true => command.volume_size.or(Some(reader.get_ref().volume_size())),
+                false => command.volume_size,
             },
-            _ => None
         };
 
         let archive_writer = match mode {
@@ -99,17 +287,58 @@ impl Archiver {
             speed: 0,
             ranges,
             buffer_size: 0,
+            hard_links,
+            filters: command.filters,
+            restore_owner: !command.no_owner && metadata::can_restore_owner(),
+            salvage: command.salvage,
+            dedup: command.dedup,
+            solid_block_size: command.solid_block_size,
+            jobs: command.jobs.unwrap_or(1),
+            password: command.key,
+            new_password: command.new_key,
+            kdf_memory: command.kdf_memory,
+            kdf_passes: command.kdf_passes,
+            sign: command.sign,
+            signing_key: None,
+            trust: command.trust,
+            signing: command.signing,
+            recovery: command.recovery,
+            recipients: command.recipients,
+            revoked: command.revoked,
+            identity: command.identity,
+            encryption: None,
+            cipher: None,
+            encrypt_headers: command.encrypt_headers,
+            prompt: command.prompt,
             archive_reader,
+            open_error,
             archive_writer,
+            volume_size,
+            keep_temporary_output: false,
+            archive_offset: 0,
+            directory: CentralDirectory::default(),
+            patches: vec![],
+            chunks: HashMap::new(),
+            written_chunks: HashSet::new(),
+            blocks: HashMap::new(),
+            written_blocks: HashSet::new(),
+            solid_block: SolidBlock::default(),
+            solid_cache: None,
+            held: HashMap::new(),
+            stream_entry: None,
+            described: None,
             files_compressed: 0,
         }
     }
 
-    fn open_output_file(&self) -> Result<File, ArchivalError> {
+    /// create the file the new archive is written to,
+    /// which replaces the output once it is complete
+    pub(crate) fn open_output_file(&self) -> Result<File, ArchivalError> {
         let output_file = OpenOptions::new()
             .create(true)
-            .append(true)
-            .open(&self.output);
+            .write(true)
+            .truncate(true)
+            .open(self.temporary_output());
         if output_file.is_err() {
             return Err(
                 ArchivalError(String::from("Could not open output file"))
@@ -118,6 +347,40 @@ impl Archiver {
         Ok(output_file.unwrap())
     }
 
+    /// start writing the new archive, either to a single file, split into volumes or to stdout
+    pub(crate) fn open_archive_writer(&mut self) -> Result<(), ArchivalError> {
+        let output: Box<dyn Write> = match self.volume_size {
+            Some(_) if is_stdio(&self.output) => return Err(ArchivalError(
+                String::from("an archive written to stdout can't be split into volumes")
+            )),
+            _ if self.sign.is_some() && is_stdio(&self.output) => return Err(ArchivalError(
+                String::from("an archive written to stdout can't be signed, as it has to be read back")
+            )),
+            _ if self.recovery.is_some() && is_stdio(&self.output) => return Err(ArchivalError(
+                String::from("an archive written to stdout can't be given a recovery record, as it has to be read back")
+            )),
+            None if is_stdio(&self.output) => Box::new(stdout().lock()),
+            Some(volume_size) => Box::new(VolumeWriter::new(self.temporary_output(), volume_size)),
+            None => Box::new(self.open_output_file()?),
+        };
+        self.archive_writer = Some(BufWriter::with_capacity(MEGABYTE as usize * 8, output));
+
+        // a missing key should fail the archive before anything is compressed
+        if let Some(path) = &self.sign {
+            self.signing_key = Some(keys::read_signing_key(path)?);
+        }
+        Ok(())
+    }
+
+    /// flush everything written to the new archive so far and open it to be read back
+    pub(crate) fn read_back_output(&mut self) -> std::io::Result<VolumeReader> {
+        self.archive_writer.as_mut().unwrap().flush()?;
+        match self.volume_size {
+            Some(_) => VolumeReader::open(&volume_path(&self.temporary_output(), 1)),
+            None => VolumeReader::open(&self.temporary_output()),
+        }
+    }
+
     fn get_file_range(&self, pos: usize) -> Result<FileRange, ArchivalError> {
         let current_range = self.ranges.get(
             self.ranges.iter().position(|fr| {
@@ -132,21 +395,43 @@ impl Archiver {
         Ok(current_range.unwrap().clone())
     }
 
-    fn build_archive_header(&self) -> Result<Header, ArchivalError> {
+    pub(crate) fn build_archive_header(&self, total_files: usize, version: usize) -> Result<Header, ArchivalError> {
         Ok(Header::Archive {
-            total_files: self.file_count,
-            // todo : update this value if the archive is being updated,
-            //   otherwise it is zero when the archive is created
-            version: 0,
-            encrypted: false,
+            total_files,
+            version,
+            encrypted: self.cipher.is_some(),
+            encrypted_headers: self.encrypt_headers,
         })
     }
 
-    fn build_file_header(
-        &self, path: &PathBuf, compressed: usize, method: u8,
+    /// the name a file is stored under, relative to the input path
+    pub(crate) fn relative_name(&self, path: &Path) -> String {
+        // a single file is stored under its own name
+        let root = match self.input.is_file() {
+            true => self.input.parent().unwrap_or(&self.input),
+            false => &self.input,
+        };
+        path.strip_prefix(root)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// determine the kind of entry `path` should be archived as,
+    /// taking hard links found while indexing into account
+    pub(crate) fn entry_kind(&self, path: &PathBuf) -> std::io::Result<EntryKind> {
+        if let Some(first) = self.hard_links.get(path) {
+            return Ok(EntryKind::HardLink(self.relative_name(first)))
+        }
+        EntryKind::of(path)
+    }
+
+    pub(crate) fn build_file_header(
+        &self, path: &Path, compressed: usize, method: u8, checksum: u32,
     ) -> Result<Header, ArchivalError>
     {
-        let input_file = File::open(path.clone());
+        let input_file = File::open(path);
         if input_file.is_err() {
             return Err(ArchivalError(
                 format!(
@@ -156,11 +441,7 @@ impl Archiver {
             ))
         }
 
-        let relative_path = path.strip_prefix(&self.input)
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
+        let relative_path = self.relative_name(path);
 
         let metadata = input_file.unwrap().metadata().unwrap();
 
@@ -169,180 +450,342 @@ impl Archiver {
             method,
             compressed_size: compressed as u64,
             decompressed_size: metadata.len(),
+            checksum,
+            metadata: EntryMetadata::from_metadata(&metadata),
         })
     }
 
     /// reads an archive header and returns its data
     pub fn read_archive_header(&mut self) -> Result<ArchiveHeader, ArchivalError> {
-        // FIXME : this code is ugly as fuck
-        //   & must be cleaned up for the love of all that is holy
-        let mut lines = Vec::<String>::with_capacity(Header::ARCHIVE_HEADER_SIZE);
-
-        for _ in 0..Header::ARCHIVE_HEADER_SIZE {
-            let mut line = String::new();
-            let bytes_read = self.archive_reader
-                .as_mut()
-                .unwrap()
-                .read_line(&mut line);
-            if bytes_read.is_err() {
+        let header = Header::read_archive(self.archive_reader.as_mut().unwrap())?;
+        self.bytes_processed += header.encoded_len();
+
+        match header {
+            Header::Archive { total_files, version, encrypted, encrypted_headers } => {
+                self.encrypt_headers = encrypted_headers;
+                if encrypted {
+                    self.read_key_slots()?;
+                }
+                Ok(ArchiveHeader(total_files, version, encrypted))
+            }
+            _ => unreachable!("Header::read_archive only returns archive headers")
+        }
+    }
+
+    /// reads a file header and returns its data
+    pub fn read_file_header(&mut self) -> Result<FileHeader, ArchivalError> {
+        let header = Header::read_file(self.archive_reader.as_mut().unwrap())?;
+        self.bytes_processed += header.encoded_len();
+
+        match header {
+            Header::File
+            { name, method, compressed_size, decompressed_size, checksum, metadata } => {
+                Ok(FileHeader(name, method, compressed_size, decompressed_size, checksum, metadata))
+            }
+            _ => unreachable!("Header::read_file only returns file headers")
+        }
+    }
+
+    /// reads the header of any kind of entry
+    pub fn read_entry_header(&mut self) -> Result<Header, ArchivalError> {
+        if self.encrypt_headers {
+            return self.read_sealed_header()
+        }
+        let header = Header::read_entry(self.archive_reader.as_mut().unwrap())?;
+        self.bytes_processed += header.encoded_len();
+        Ok(header)
+    }
+
+    /// reads the central directory from the end of the archive,
+    /// returns `None` if the archive was written without one.
+    ///
+    /// the reader is returned to its original position afterwards
+    pub fn read_directory(&mut self) -> Result<Option<CentralDirectory>, ArchivalError> {
+        if self.archive_size < (Header::ARCHIVE_HEADER_SIZE + Footer::SIZE) as u64 {
+            return Ok(None)
+        }
+
+        let reader = self.archive_reader.as_mut().unwrap();
+        let seek_err = |e| ArchivalError(format!("failed to seek within archive: {}", e));
+        let position = reader.stream_position().map_err(seek_err)?;
+
+        reader.seek(SeekFrom::End(-(Footer::SIZE as i64))).map_err(seek_err)?;
+        let footer = match Footer::read(reader) {
+            Ok(footer) => footer,
+            // every split archive ends in a footer, so without one a volume is missing
+            Err(_) if reader.get_ref().is_split() => {
+                let missing = reader.get_ref().next_volume().unwrap_or_default();
                 return Err(ArchivalError(
-                    String::from(format!(
-                        "failed to read archive header: {}",
-                        bytes_read.err().unwrap()
-                    ))
+                    format!("the archive is incomplete, volume \"{}\" is missing", missing.display())
                 ))
             }
-            self.bytes_processed += bytes_read.unwrap();
-            lines.push(line.trim().to_string());
+            Err(_) => {
+                reader.seek(SeekFrom::Start(position)).map_err(seek_err)?;
+                return Ok(None)
+            }
+        };
+
+        // the directory must end exactly where the footer begins, or where the signature does
+        let footer_start = self.archive_size - Footer::SIZE as u64;
+        let directory_end = match footer.directory_offset.checked_add(footer.directory_size) {
+            Some(end) if end <= footer_start => end,
+            _ => return Err(ArchivalError(
+                "archive footer points outside of the archive".to_owned()
+            ))
+        };
+        if directory_end < footer_start {
+            reader.seek(SeekFrom::Start(directory_end)).map_err(seek_err)?;
+            let magic = read_array::<4>(reader).ok();
+            if magic != Some(SIGNATURE_MAGIC) && magic != Some(RECOVERY_MAGIC) {
+                return Err(ArchivalError(
+                    "archive footer points outside of the archive".to_owned()
+                ))
+            }
+        }
+
+        reader.seek(SeekFrom::Start(footer.directory_offset)).map_err(seek_err)?;
+        let mut directory = vec![0; footer.directory_size as usize];
+        if let Err(e) = reader.read_exact(&mut directory) {
+            return Err(ArchivalError(format!("failed to read the central directory: {}", e)))
         }
+        reader.seek(SeekFrom::Start(position)).map_err(seek_err)?;
 
-        let data = lines.iter().map(|l| {
-            l.split(':').collect::<Vec<&str>>()
-        }).collect::<Vec<Vec<&str>>>();
-        let total_files = data[0][1].parse::<usize>().unwrap();
-        let version = data[1][1].parse::<usize>().unwrap();
-        let encrypted = data[2][1].parse::<bool>().unwrap();
+        let directory = self.open_directory(directory)?;
+        let directory = CentralDirectory::read(&mut directory.as_slice())?;
 
-        Ok(ArchiveHeader(total_files, version, encrypted))
+        Ok(Some(directory))
     }
 
-    /// reads a file header and returns its data
-    pub fn read_file_header(&mut self) -> Result<FileHeader, ArchivalError> {
-        // read header lines
-        let mut lines = Vec::with_capacity(Header::FILE_HEADER_SIZE);
-        for _ in 0..Header::FILE_HEADER_SIZE {
-            let mut line = String::new();
-            let bytes_read = self.archive_reader
-                .as_mut()
-                .unwrap()
-                .read_line(&mut line);
-            if bytes_read.is_err() {
+    /// collect the details of every entry in the archive, using the
+    /// central directory when present and otherwise walking each file header.
+    /// the location of every chunk and solid block is recorded along the way.
+    ///
+    /// expects the archive header to have already been read
+    pub fn read_entries(&mut self, files: usize) -> Result<Vec<DirectoryEntry>, ArchivalError> {
+        if let Some(directory) = self.read_directory()? {
+            self.chunks = directory.chunks.into_iter()
+                .map(|chunk| (chunk.hash, chunk.offset))
+                .collect();
+            self.blocks = directory.blocks.into_iter()
+                .map(|block| (block.id, block.offset))
+                .collect();
+            return Ok(directory.entries)
+        }
+        // only the directory vouches for the entries without any data
+        if self.encryption.is_some() {
+            return Err(ArchivalError(String::from(
+                "the archive is encrypted but its central directory is missing"
+            )))
+        }
+
+        let mut entries = Vec::with_capacity(files);
+        while entries.len() < files {
+            let offset = self.archive_reader.as_mut().unwrap()
+                .stream_position()
+                .map_err(|e| ArchivalError(format!("failed to seek within archive: {}", e)))?;
+
+            let (name, skip) = match self.read_entry_header()? {
+                Header::Chunk { hash, compressed_size, .. } => {
+                    self.chunks.insert(hash, offset);
+                    ("chunk".to_owned(), compressed_size as u64)
+                }
+                Header::Block { id, compressed_size, .. } => {
+                    self.blocks.insert(id, offset);
+                    ("solid block".to_owned(), compressed_size)
+                }
+                header => {
+                    let mut entry = DirectoryEntry::from_header(&header, offset);
+                    // a described file is walked to its descriptor, so there is nothing left to skip
+                    let skip = match entry.method & DESCRIBED {
+                        0 => entry.compressed_size,
+                        _ => {
+                            self.measure_described(&mut entry)?;
+                            0
+                        }
+                    };
+                    let name = entry.name.clone();
+                    entries.push(entry);
+                    (name, skip)
+                }
+            };
+
+            // skip over the file data to reach the next header
+            if let Err(e) = self.archive_reader.as_mut().unwrap().seek_relative(skip as i64) {
                 return Err(ArchivalError(
-                    String::from(format!(
-                        "failed to read file header: {}\n\
-                        current line contents: \"{}\"\n\
-                        current line bytes: \"{:?}\"\n\
-                        lines read: {:?}",
-                        bytes_read.err().unwrap(), line, line.as_bytes(), lines
-                    ))
+                    format!("failed to skip file data for \"{}\": {}", name, e)
                 ))
             }
-            self.bytes_processed += bytes_read.unwrap();
-            lines.push(line.trim().to_string());
         }
+        Ok(entries)
+    }
 
-        // parse lines
-        let data = lines.iter().map(|l| {
-            l.split(':').collect::<Vec<&str>>()
-        }).collect::<Vec<Vec<&str>>>();
-        let name = data[0][1].parse::<String>().unwrap();
-        let method = data[1][1].parse::<u8>().unwrap();
-        let compressed = data[2][1].parse::<u64>().unwrap();
-        let decompressed = data[3][1].parse::<u64>().unwrap();
+    /// jump to an entry, then read and decompress its data in memory.
+    ///
+    /// the data is checked against the size and checksum in the entry's header,
+    /// so anything returned is exactly what was archived
+    pub fn read_entry_data(&mut self, entry: &DirectoryEntry) -> Result<Vec<u8>, ArchivalError> {
+        let mut data = vec![];
+        self.read_entry_to(entry, &mut data)?;
+        Ok(data)
+    }
 
-        // return header data
-        Ok(FileHeader(name, method, compressed, decompressed))
+    /// jump to an entry, then read and decompress its data into `out` a block at a time.
+    ///
+    /// the size and checksum in the entry's header are only known to match once
+    /// everything has been written, so `out` must be discarded if this fails.
+    /// directories and symbolic links have no data, so only their header is checked
+    pub fn read_entry_to(&mut self, entry: &DirectoryEntry, out: &mut impl Write) -> Result<(), ArchivalError> {
+        let found = self.read_entry_start(entry)?;
+        if found.kind != EntryKind::File {
+            return Ok(())
+        }
+        let (name, method, compressed, decompressed, checksum) = (
+            &found.name, found.method, found.compressed_size, found.decompressed_size, found.checksum
+        );
+        let mut out = ChecksumWriter::new(out);
+
+        // deduplicated files are stored as a list of chunks to reassemble,
+        // solid files as a reference into a shared block, and every other file as its blocks
+        let mut checksum = checksum;
+        if method & (CHUNKED | SOLID) == 0 {
+            checksum = self.read_compressed_blocks(&found, &mut out)?;
+        } else {
+            // read 'n' bytes specified by the header
+            let mut buffer = vec![0; compressed as usize];
+            let read_res = self.archive_reader.as_mut().unwrap().read_exact(&mut buffer);
+            if let Err(e) = read_res {
+                return Err(ArchivalError(
+                    format!("failed to read file data for \"{}\": {}", name, e)
+                ))
+            }
+            let buffer = self.open_data(buffer, &found.header().to_bytes(), name)?;
+
+            if method & CHUNKED != 0 {
+                self.write_chunked_data(name, &buffer, &mut out)?;
+            } else if let Err(e) = out.write_all(&self.read_solid_data(name, &buffer, decompressed)?) {
+                return Err(ArchivalError(
+                    format!("Could not write the data of \"{}\": {}", name, e)
+                ))
+            }
+        }
+
+        // never hand out data that doesn't match what was archived
+        out.verify(name, decompressed, checksum)?;
+        Ok(())
     }
 
-    fn archive_uncompressed_file(&mut self, path: &PathBuf) -> Result<u64, ArchivalError> {
-        let input_file = File::open(path);
-        if input_file.is_err() {
+    /// read the header of an entry, leaving the archive at the start of its data,
+    /// and check it is the entry the directory says should be there
+    pub(crate) fn read_entry_start(&mut self, entry: &DirectoryEntry) -> Result<DirectoryEntry, ArchivalError> {
+        let found = match self.stream_entry.take() {
+            Some(found) if found.offset == entry.offset => found,
+            _ => {
+                self.seek_archive(entry.offset)?;
+                DirectoryEntry::from_header(&self.read_entry_header()?, entry.offset)
+            }
+        };
+        if found.name != entry.name || found.kind != entry.kind {
             return Err(ArchivalError(
                 format!(
-                    "Could not open input file: \"{}\"\nreason: {}",
-                    path.display(),
-                    input_file.err().unwrap())
+                    "expected the header of \"{}\" at offset {} but found \"{}\"",
+                    entry.name, entry.offset, found.name
+                )
             ))
         }
-        let input_file = input_file.unwrap();
-        let metadata = input_file.metadata().unwrap();
-
-        let relative_path = path.strip_prefix(&self.input)
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
-        let header = Header::File {
-            name: relative_path,
-            method: 0,
-            compressed_size: metadata.len(),
-            decompressed_size: metadata.len(),
-        };
-
-        let write_res = self.archive_writer.as_mut().unwrap().write(header.to_bytes().as_slice());
-        if write_res.is_err() {
+        // the header and the directory are authenticated separately, so they must agree
+        if found != DirectoryEntry::from_header(&entry.header(), entry.offset) {
             return Err(ArchivalError(
-                format!(
-                    "Could not write to output file: {}",
-                    write_res.err().unwrap())
+                format!("the header of \"{}\" doesn't match its entry in the central directory", entry.name)
             ))
         }
+        Ok(entry.clone())
+    }
 
-        // read file
-        let reader_size = cmp::min(
-            self.buffer_size,
-            metadata.len() as usize
-        );
-        let mut reader = BufReader::with_capacity(
-            reader_size,
-            input_file
-        );
+    /// extract a file entry to `path`, leaving nothing behind if its data is corrupt
+    pub(crate) fn extract_file(&mut self, entry: &DirectoryEntry, path: &PathBuf) -> Result<(), ArchivalError> {
+        write_file(path, |writer| self.read_entry_to(entry, writer))
+    }
 
-        // stream copy instead of loading everything into memory
-        let bytes_copied = copy(&mut reader, &mut self.archive_writer.as_mut().unwrap());
-        if bytes_copied.is_err() {
-            return Err(ArchivalError(
-                String::from("failed to copy to buffer")
+    /// move the archive reader to an absolute position in the archive.
+    ///
+    /// the move is made relative to where the reader is, so moving ahead within what
+    /// is already buffered never asks a stream to go back for the rest of the buffer
+    pub(crate) fn seek_archive(&mut self, offset: u64) -> Result<(), ArchivalError> {
+        let reader = self.archive_reader.as_mut().unwrap();
+        let res = reader.stream_position()
+            .and_then(|position| reader.seek_relative(offset as i64 - position as i64));
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ArchivalError(
+                format!("failed to seek within archive: {}", e)
             ))
         }
-        let bytes_copied = bytes_copied.unwrap();
+    }
 
-        // logging
-        self.files_processed += 1;
-        self.bytes_processed += bytes_copied as usize;
-        self.speed = (self.bytes_processed as f64 /
-            self.start_time.unwrap().elapsed().as_secs_f64()
-        ) as usize;
+    /// write `bytes` to the output archive, keeping track of the current write position
+    pub(crate) fn write_archive_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.archive_writer.as_mut().unwrap().write_all(bytes)?;
+        self.archive_offset += bytes.len() as u64;
+        Ok(())
+    }
 
-        self.format_progress(format!("{}", path.display()));
+    /// write an entry header to the output archive and record it in the central directory
+    pub(crate) fn write_entry_header(&mut self, header: &Header) -> Result<(), ArchivalError> {
+        self.directory.entries.push(DirectoryEntry::from_header(header, self.archive_offset));
 
-        Ok(0)
+        match self.write_archive_bytes(self.encode_header(header).as_slice()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ArchivalError(
+                format!("Failed to write file header: {}", e)
+            ))
+        }
     }
 
-    fn archive_compressed_file(
-        &mut self, method: u8, path: &PathBuf
-    ) -> Result<u64, ArchivalError>
-    {
-        let mut file_compressor = Compressor::new(
-            // FIXME : reading the whole fine in at once is a flawless idea
-            //   that wont overuse memory or cause any crashes :)
-            fs::read(path).unwrap(), method
-        );
+    /// write the central directory and footer, completing the archive,
+    /// with the signature and then the recovery record between them when asked for
+    pub(crate) fn write_directory(&mut self) -> Result<(), ArchivalError> {
+        self.patch_headers()?;
+        let directory_offset = self.archive_offset;
+        let directory = self.seal_directory(self.directory.to_bytes());
+        let footer = Footer {
+            directory_offset,
+            directory_size: directory.len() as u64,
+        };
 
-        let new_data = file_compressor.compress();
+        if let Err(e) = self.write_archive_bytes(directory.as_slice()) {
+            return Err(ArchivalError(
+                format!("Failed to write central directory: {}", e)
+            ))
+        }
+        self.write_signature(directory_offset)?;
+        self.write_recovery_record(&footer)?;
+        if let Err(e) = self.write_archive_bytes(footer.to_bytes().as_slice()) {
+            return Err(ArchivalError(
+                format!("Failed to write central directory: {}", e)
+            ))
+        }
+        Ok(())
+    }
 
-        // create and write file header
-        let header = self.build_file_header(path, new_data.len(), method)?;
-        match self.archive_writer.as_mut().unwrap().write(header.to_bytes().as_slice()) {
-            Err(e) => {
-                return Err(
-                    ArchivalError(
-                        format!("Failed to write file header: {}", e)
-                    )
-                )
-            }
-            _ => {}
+    /// archive a directory or link, which is made up of only a header
+    fn archive_special_entry(&mut self, path: &PathBuf, kind: EntryKind) -> Result<u64, ArchivalError> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => EntryMetadata::from_metadata(&metadata),
+            Err(e) => return Err(ArchivalError(
+                format!("Could not read metadata of \"{}\"\nreason: {}", path.display(), e)
+            ))
         };
 
-        self.archive_writer.as_mut().unwrap().write_all(new_data.as_slice()).unwrap();
+        let name = self.relative_name(path);
+        let header = match kind {
+            EntryKind::Directory => Header::Directory { name, metadata },
+            EntryKind::Symlink(target) => Header::Symlink { name, target, metadata },
+            EntryKind::HardLink(target) => Header::HardLink { name, target, metadata },
+            EntryKind::File => unreachable!("files are archived with their data"),
+        };
+        self.write_entry_header(&header)?;
 
-        // logging
         self.files_processed += 1;
-        self.bytes_processed += new_data.len();
-        self.speed = (self.bytes_processed as f64 /
-            self.start_time.unwrap().elapsed().as_secs_f64()
-        ) as usize;
-
         self.format_progress(format!("{}", path.display()));
         Ok(0)
     }
@@ -352,147 +795,525 @@ impl Archiver {
     /// otherwise it will simply be created from
     /// all files contained in the input path
     fn add(&mut self) -> Result<u64, ArchivalError>{
-        let output_file = self.open_output_file()?;
-        self.archive_writer = Some(BufWriter::with_capacity(MEGABYTE as usize * 8, output_file));
+        if self.dedup && self.solid_block_size.is_some() {
+            return Err(ArchivalError(
+                String::from("--dedup and --solid can't be used together")
+            ))
+        }
+        let res = self.write_archive();
+        if res.is_err() {
+            // never leave a half written archive behind
+            self.remove_temporary_output();
+        }
+        res
+    }
 
-        // create and write the archive header
-        let header = self.build_archive_header()?;
-        match self.archive_writer.as_mut().unwrap().write(header.to_bytes().as_slice()) {
-            Err(e) => {
-                return Err(
-                    ArchivalError(
-                        format!("Failed to write archive header: {}", e)
-                    )
-                )
-            }
-            _ => {}
+    fn write_archive(&mut self) -> Result<u64, ArchivalError> {
+        // the entries of an existing archive are carried over into the new one,
+        // which is written alongside it and then swapped into its place
+        let (version, existing) = match self.read_existing_archive()? {
+            Some((version, entries)) => (version + 1, entries),
+            None => (1, vec![]),
         };
 
-        // write data from all files into one
-        for (_i, path) in self.files.clone().iter().enumerate() {
-            // profile the file to determine the best method to compress it
-            let mut file_profile = Profiler::new(path.clone());
-            match file_profile.profile() {
-                0 => {
-                    self.archive_uncompressed_file(path)?;
-                }
-                m => {
-                    self.files_compressed += 1;
-                    self.archive_compressed_file(m, path)?;
-                }
+        self.open_archive_writer()?;
+
+        let names = self.files.iter()
+            .map(|path| self.relative_name(path))
+            .collect::<Vec<String>>();
+        let kept = existing.iter()
+            .filter(|entry| !names.contains(&entry.name))
+            .cloned()
+            .collect::<Vec<DirectoryEntry>>();
+        let existing = existing.into_iter()
+            .map(|entry| (entry.name.clone(), entry))
+            .collect::<HashMap<String, DirectoryEntry>>();
+
+        // create and write the archive header
+        self.prepare_cipher(version > 1)?;
+        self.write_archive_header(kept.len() + names.len(), version)?;
+
+        // entries which aren't being replaced are kept as they are
+        for entry in &kept {
+            self.copy_entry(entry)?;
+        }
+
+        // profile every file up front, then write them in the order they were given,
+        // with the files compressed block by block handed to the workers ahead of the writer
+        let plans = self.plan_files(&names, &existing);
+        let compressed = self.files.iter()
+            .zip(&plans)
+            .filter_map(|(path, plan)| match plan {
+                Ok(Plan::Compressed(method)) => Some((path.clone(), *method)),
+                _ => None,
+            })
+            .collect::<Vec<(PathBuf, u8)>>();
+        let jobs = self.jobs;
+        let (added, replaced, unchanged) = thread::scope(|scope| {
+            let mut pipeline = match jobs {
+                1 => None,
+                jobs => Some(Pipeline::start(scope, jobs, compressed)),
             };
+            self.write_planned_files(plans, names, &existing, pipeline.as_mut())
+        })?;
+
+        self.flush_solid_block()?;
+
+        // the central directory lets readers find any entry without a full scan
+        self.write_directory()?;
+
+        if let Err(e) = self.archive_writer.as_mut().unwrap().flush() {
+            return Err(ArchivalError(
+                format!("Failed to flush output file: {}", e)
+            ))
         }
+        self.replace_output()?;
 
         let speed = format_bytes!(self.speed);
 
-        println!(
+        eprintln!(
             "Archival Completed in {:.2}s with a speed of {} per second\
             \n{} files compressed, for about {:.2}% of files",
             self.start_time.unwrap().elapsed().as_secs_f64(),
             speed, self.files_compressed,
             self.files_compressed as f64 / self.files_processed as f64
         );
+        if version > 1 {
+            eprintln!(
+                "Updated to version {}: {} added, {} replaced, {} unchanged, {} kept",
+                version, added, replaced, unchanged, kept.len()
+            );
+        }
 
         Ok(self.archive_size)
     }
 
+    /// write each file given to `add` as it was planned, counting how many were
+    /// added, replaced and left unchanged
+    fn write_planned_files(
+        &mut self, plans: Vec<Result<Plan, ArchivalError>>, names: Vec<String>,
+        existing: &HashMap<String, DirectoryEntry>, mut pipeline: Option<&mut Pipeline>,
+    ) -> Result<(usize, usize, usize), ArchivalError>
+    {
+        let (mut added, mut replaced, mut unchanged) = (0, 0, 0);
+        for ((path, name), plan) in self.files.clone().iter().zip(names).zip(plans) {
+            let plan = plan?;
+            match existing.get(&name) {
+                Some(entry) if plan == Plan::Unchanged => {
+                    self.copy_entry(entry)?;
+                    unchanged += 1;
+                    continue
+                }
+                Some(_) => replaced += 1,
+                None => added += 1,
+            }
+
+            let method = match plan {
+                Plan::Special(kind) => {
+                    self.archive_special_entry(path, kind)?;
+                    continue
+                }
+                Plan::Solid(m, _) | Plan::Chunked(m) | Plan::Compressed(m) => m,
+                Plan::Unchanged => unreachable!("only existing entries are unchanged"),
+            };
+            if method != 0 {
+                self.files_compressed += 1;
+            }
+            match plan {
+                Plan::Solid(m, block_size) => self.archive_solid_file(m, path, block_size)?,
+                Plan::Chunked(m) => self.archive_chunked_file(m, path)?,
+                _ => match pipeline.as_deref_mut() {
+                    Some(pipeline) => self.write_compressed_file(method, path, pipeline)?,
+                    None => self.archive_compressed_file(method, path)?,
+                },
+            };
+        }
+        Ok((added, replaced, unchanged))
+    }
+
+    /// check whether a file still matches the entry it was archived as,
+    /// both in contents and in its stored metadata, given the kind of entry it would be now
+    pub(crate) fn is_unchanged(path: &PathBuf, kind: Option<&EntryKind>, entry: &DirectoryEntry) -> bool {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return false
+        };
+        if kind != Some(&entry.kind) {
+            return false
+        }
+        if entry.kind != EntryKind::File {
+            return EntryMetadata::from_metadata(&metadata).same_file_as(&entry.metadata)
+        }
+        metadata.len() == entry.decompressed_size
+            && EntryMetadata::from_metadata(&metadata).same_file_as(&entry.metadata)
+            && integrity::checksum_file(path).ok() == Some(entry.checksum)
+    }
+
     /// Extract the contents of an archive into the output path
     fn extract(&mut self) -> Result<u64, ArchivalError> {
         let ArchiveHeader(files, _ver, _encrypted) = self.read_archive_header()?;
+        let filter = EntryFilter::new(&self.filters)?;
 
-        for i in 0..files {
-            // read header
-            let FileHeader(name, method, compressed, _decompressed) = self.read_file_header()?;
+        match self.is_streamed() {
+            true => self.extract_stream(files, &filter)?,
+            false => self.extract_entries(files, &filter)?,
+        }
 
-            // read 'n' bytes specified by the header
-            // we reserve an extra byte to account for the
-            // leading newline into the next file header
-            let mut buffer = if i == files - 1 {
-                vec![0; compressed as usize]
-            } else {
-                vec![0; compressed as usize + 1]
-            };
+        let speed = format_bytes!(self.speed);
 
-            let read_res = self.archive_reader.as_mut().unwrap().read_exact(&mut buffer);
-            if let Err(e) = read_res {
+        eprintln!(
+            "Extraction Completed in {:.2}s with a speed of {} per second",
+            self.start_time.unwrap().elapsed().as_secs_f64(),
+            speed,
+        );
+
+        Ok(self.archive_size)
+    }
+
+    /// extract the entries selected by `filter`, found through the central directory
+    fn extract_entries(&mut self, files: usize, filter: &EntryFilter) -> Result<(), ArchivalError> {
+        let entries = self.read_entries(files)?;
+        let selected = entries.iter()
+            .filter(|entry| filter.matches(&entry.name))
+            .cloned()
+            .collect::<Vec<DirectoryEntry>>();
+        if !filter.is_empty() {
+            if selected.is_empty() {
                 return Err(ArchivalError(
-                    format!("failed to read file data: {}", e)
+                    format!("no entries matched {}", self.filters.join(", "))
                 ))
             }
+            eprintln!("{} of {} entries selected", selected.len(), files);
+        }
+
+        // progress is measured against the selected entries only
+        self.total_bytes = selected.iter()
+            .map(|e| (self.entry_data_offset(e) - e.offset + e.compressed_size) as usize)
+            .sum();
+        self.bytes_processed = 0;
+
+        // refuse anything that could be written outside of the output before writing at all
+        let links = selected.iter()
+            .filter(|entry| matches!(entry.kind, EntryKind::Symlink(_)))
+            .map(|entry| PathBuf::from(&entry.name))
+            .collect::<HashSet<PathBuf>>();
+        for entry in &selected {
+            paths::entry_path(&self.output, &entry.name)?;
+            match &entry.kind {
+                EntryKind::Symlink(target) => {
+                    paths::check_link_target(&entry.name, target, &links)?;
+                }
+                EntryKind::HardLink(target) => {
+                    let found = entries.iter()
+                        .any(|e| &e.name == target && e.kind == EntryKind::File);
+                    if !found {
+                        return Err(ArchivalError(format!(
+                            "\"{}\" is a hard link to \"{}\", which is not a file in the archive",
+                            entry.name, target
+                        )))
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut deferred = Deferred {
+            files: selected.iter()
+                .map(|entry| entry.name.clone())
+                .collect::<HashSet<String>>(),
+            ..Deferred::default()
+        };
 
-            let buffer = if i == files - 1 {
-                buffer.as_ref()
-            } else {
-                &buffer[0..compressed as usize]
+        // files small enough to fit in the memory budget are read here and handed to
+        // workers, which decompress and write them while the next ones are read
+        let (encrypted, cipher, restore_owner) = (self.encryption.is_some(), self.cipher.clone(), self.restore_owner);
+        let budget = MemoryBudget::new(EXTRACT_MEMORY_BUDGET);
+        let jobs = self.jobs;
+        thread::scope(|scope| {
+            let mut pool = match jobs {
+                1 => None,
+                jobs => Some(ExtractPool::start(scope, jobs, &budget, encrypted, cipher.as_ref(), restore_owner)),
             };
+            // anything that wasn't selected is skipped over without being read
+            for entry in selected {
+                self.extract_entry(pool.as_mut(), entry, &mut deferred)?;
+            }
+            // links are only made once every file they could point to is written
+            match pool {
+                Some(pool) => self.finish_extraction(pool),
+                None => Ok(()),
+            }
+        })?;
 
-            let mut decompressor = Compressor::new(buffer.to_vec(), method);
-            let decompressed_data = decompressor.decompress();
+        self.create_deferred(deferred, &entries)
+    }
 
-            // reconstruct the files into a dir with the same name as the archive
-            let path = self.output.clone().join(PathBuf::from(&name));
-            fs::create_dir_all(&path.parent().unwrap()).unwrap();
-            fs::write(&path, decompressed_data).unwrap();
-            self.format_progress(format!("{}", path.display()));
+    /// extract a single selected entry, either straight away or by handing it to `pool`.
+    ///
+    /// links are only created once everything else is in place, so nothing
+    /// is ever written through one. directories are finished last of all,
+    /// as adding to them changes their times and they may be read only
+    pub(crate) fn extract_entry(
+        &mut self, pool: Option<&mut ExtractPool>, entry: DirectoryEntry, deferred: &mut Deferred
+    ) -> Result<(), ArchivalError>
+    {
+        let path = paths::entry_path(&self.output, &entry.name)?;
+
+        match &entry.kind {
+            EntryKind::Directory => {
+                self.read_entry_data(&entry)?;
+                if let Err(e) = fs::create_dir_all(&path) {
+                    return Err(ArchivalError(
+                        format!("Could not create directory \"{}\": {}", path.display(), e)
+                    ))
+                }
+                deferred.directories.push((path, entry));
+                return Ok(())
+            }
+            EntryKind::Symlink(_) => {
+                self.read_entry_data(&entry)?;
+                deferred.symlinks.push((path, entry));
+                return Ok(())
+            }
+            EntryKind::HardLink(_) => {
+                self.read_entry_data(&entry)?;
+                deferred.hard_links.push((path, entry));
+                return Ok(())
+            }
+            EntryKind::File => {}
+        }
+
+        if let Some(pool) = pool.filter(|pool| pool.fits(&entry)) {
+            return self.queue_extraction(pool, entry, path)
+        }
+        self.extract_file(&entry, &path)?;
+        self.restore_metadata(&path, &entry)?;
+        self.file_extracted(&path, entry.compressed_size);
+        Ok(())
+    }
 
-            // logging
-            self.bytes_processed += buffer.len();
+    /// create the links and finish the directories left until every file was extracted
+    pub(crate) fn create_deferred(&mut self, deferred: Deferred, entries: &[DirectoryEntry]) -> Result<(), ArchivalError> {
+        let Deferred { files, mut directories, symlinks, hard_links } = deferred;
+
+        for (path, entry) in hard_links {
+            if let EntryKind::HardLink(target) = &entry.kind {
+                self.extract_hard_link(&path, target, &files, entries)?;
+            }
             self.files_processed += 1;
-            self.speed = (self.bytes_processed as f64 /
-                self.start_time.unwrap().elapsed().as_secs_f64()
-            ) as usize;
         }
 
-        let speed = format_bytes!(self.speed);
+        for (path, entry) in symlinks {
+            if let EntryKind::Symlink(target) = &entry.kind {
+                if let Some(parent) = path.parent() {
+                    let _ = fs::create_dir_all(parent);
+                }
+                if let Err(e) = Self::create_symlink(target, &path) {
+                    return Err(ArchivalError(
+                        format!("Could not create link \"{}\": {}", path.display(), e)
+                    ))
+                }
+            }
+            self.files_processed += 1;
+        }
+
+        // deepest directories first, so parents are only finished once their children are
+        directories.sort_by(|(a, _), (b, _)| b.cmp(a));
+        for (path, entry) in directories {
+            self.restore_metadata(&path, &entry)?;
+            self.files_processed += 1;
+        }
+        Ok(())
+    }
+
+    /// link `path` to the already extracted file `target`. when the target
+    /// wasn't selected for extraction its data is written to `path` instead
+    pub(crate) fn extract_hard_link(
+        &mut self, path: &PathBuf, target: &str,
+        selected: &HashSet<String>, entries: &[DirectoryEntry]
+    ) -> Result<(), ArchivalError>
+    {
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        // replace whatever is already there, as extracting a file would
+        let _ = fs::remove_file(path);
+
+        if selected.contains(target) {
+            let target_path = paths::entry_path(&self.output, target)?;
+            if let Err(e) = fs::hard_link(&target_path, path) {
+                return Err(ArchivalError(
+                    format!("Could not create link \"{}\": {}", path.display(), e)
+                ))
+            }
+            return Ok(())
+        }
+
+        let entry = entries.iter().find(|e| e.name == target).unwrap();
+        self.extract_file(entry, path)?;
+        self.restore_metadata(path, entry)
+    }
+
+    /// apply the stored metadata of an entry to the file or directory extracted from it
+    pub(crate) fn restore_metadata(&self, path: &Path, entry: &DirectoryEntry) -> Result<(), ArchivalError> {
+        restore_entry_metadata(path, entry, self.restore_owner)
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn create_symlink(target: &str, path: &PathBuf) -> std::io::Result<()> {
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn create_symlink(target: &str, path: &PathBuf) -> std::io::Result<()> {
+        let resolved = path.parent().unwrap_or(path).join(target);
+        match resolved.is_dir() {
+            true => std::os::windows::fs::symlink_dir(target, path),
+            false => std::os::windows::fs::symlink_file(target, path),
+        }
+    }
+
+    /// print the details of every entry in the archive without extracting anything
+    fn list(&mut self) -> Result<u64, ArchivalError> {
+        let ArchiveHeader(files, version, encrypted) = self.read_archive_header()?;
+        let entries = match self.is_streamed() {
+            true => self.walk_stream(files, false, |_, _| Ok(()))?,
+            false => self.read_entries(files)?,
+        };
 
         println!(
-            "Extraction Completed in {:.2}s with a speed of {} per second",
-            self.start_time.unwrap().elapsed().as_secs_f64(),
-            speed,
+            "{} :: {} files, version {}{}",
+            self.input.display(), files, version,
+            if encrypted { ", encrypted" } else { "" }
+        );
+        println!(
+            "{:>12}  {:>12}  {:>7}  {:<8}  path",
+            "compressed", "original", "ratio", "method"
+        );
+
+        let (mut total_compressed, mut total_decompressed) = (0u64, 0u64);
+        for entry in &entries {
+            total_compressed += entry.compressed_size;
+            total_decompressed += entry.decompressed_size;
+
+            let (method, name) = match &entry.kind {
+                EntryKind::File => (Compressor::method_name(entry.method), entry.name.clone()),
+                EntryKind::Directory => ("dir".to_owned(), entry.name.clone()),
+                EntryKind::Symlink(target) => {
+                    ("link".to_owned(), format!("{} -> {}", entry.name, target))
+                }
+                EntryKind::HardLink(target) => {
+                    ("hardlink".to_owned(), format!("{} => {}", entry.name, target))
+                }
+            };
+            println!(
+                "{:>12}  {:>12}  {:>6.1}%  {:<8}  {}",
+                format_bytes!(entry.compressed_size),
+                format_bytes!(entry.decompressed_size),
+                Self::ratio(entry.compressed_size, entry.decompressed_size),
+                method,
+                name,
+            );
+        }
+
+        println!(
+            "{:>12}  {:>12}  {:>6.1}%  {} entries",
+            format_bytes!(total_compressed),
+            format_bytes!(total_decompressed),
+            Self::ratio(total_compressed, total_decompressed),
+            entries.len(),
         );
 
         Ok(self.archive_size)
     }
 
-    /// profile the input path and log the details
+    /// the compressed size as a percentage of the original size
+    fn ratio(compressed: u64, decompressed: u64) -> f64 {
+        if decompressed == 0 {
+            return 100.0
+        }
+        compressed as f64 / decompressed as f64 * 100.0
+    }
+
+    /// profile the input path and log the details,
+    /// along with how much deduplicating it would save
     pub fn profile(&mut self) -> Result<u64, ArchivalError> {
-        if self.input.is_dir() {
-            return Err(ArchivalError(
-                "Profiling target must be a file!".to_owned()
-            ))
+        if self.input.is_file() {
+            let mut profiler = Profiler::new(self.input.clone());
+            let _ = profiler.profile();
+            println!("Profile: \n{:?}", profiler);
         }
-        let mut profiler = Profiler::new(self.input.clone());
-        let _ = profiler.profile();
-        println!("Profile: \n{:?}", profiler);
+        println!("{}", self.dedup_estimate()?);
         Ok(0)
     }
 
     /// general function to run all operations specified by the command
     // todo : maybe a better name ig
     pub fn operate(&mut self) -> Result<u64, ArchivalError> {
+        if let Some(error) = &self.open_error {
+            return Err(ArchivalError(error.clone()))
+        }
+        // a stream is read once from start to end, which is all these modes need
+        if self.is_streamed() && (self.salvage || !matches!(self.mode, Mode::Extract | Mode::List | Mode::Verify)) {
+            return Err(ArchivalError(String::from(
+                "an archive read from stdin can only be extracted, listed or verified, \
+                as it can't be gone back over"
+            )))
+        }
         self.start_time = Some(Instant::now());
         match self.mode {
             Mode::Add => {
                 self.add()
             },
+            Mode::Extract if self.salvage => {
+                self.salvage()
+            }
             Mode::Extract => {
                 self.extract()
             }
             Mode::Profile => {
                 self.profile()
             }
+            Mode::List => {
+                self.list()
+            }
+            Mode::Verify => {
+                self.verify()
+            }
+            Mode::Remove => {
+                self.remove()
+            }
+            Mode::Keygen => {
+                self.keygen()
+            }
+            Mode::Share => {
+                self.share()
+            }
+            Mode::Rekey => {
+                self.rekey()
+            }
+            Mode::VerifySignature => {
+                self.verify_signature()
+            }
+            Mode::Repair => {
+                self.repair()
+            }
         }
     }
 }
 
 impl FmtProgress for Archiver {
     fn get_progress_percentage(&self) -> Option<f64> {
+        // the size of an archive read from a stream isn't known
+        if self.total_bytes == 0 {
+            return None
+        }
         Some((self.bytes_processed as f64 / self.total_bytes as f64) * 100.0)
     }
 
     fn get_estimated_time_remaining(&self) -> Option<f64> {
         let remaining_bytes = self.total_bytes.saturating_sub(self.bytes_processed) as f64;
-        if self.speed == 0 {
+        if self.speed == 0 || self.total_bytes == 0 {
             return None
         }
         Some(remaining_bytes / self.speed as f64)
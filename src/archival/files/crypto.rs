@@ -0,0 +1,711 @@
+use std::io::{Read, Seek};
+use std::path::Path;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{AeadCore, KeyInit, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::archival::compression::chunker;
+use crate::archival::compression::chunker::ChunkHash;
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::directory::DirectoryEntry;
+use crate::archival::files::header::{read_array, Header, HeaderError};
+use crate::archival::files::keys;
+use crate::constants::{GIGABYTE, KILOBYTE, MEGABYTE, TERABYTE};
+use crate::format_bytes;
+
+/// size in bytes of the random salt a password key is derived with
+pub const SALT_SIZE: usize = 16;
+/// size in bytes of the random nonce stored in front of every sealed piece of data
+pub const NONCE_SIZE: usize = 24;
+/// size in bytes of the authentication tag stored after every sealed piece of data
+pub const TAG_SIZE: usize = 16;
+/// the extra bytes sealing adds to any piece of data
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+/// size in bytes of the content key, and of the keys wrapping it
+pub const KEY_SIZE: usize = 32;
+/// size in bytes of a sealed content key
+pub const WRAPPED_KEY_SIZE: usize = KEY_SIZE + SEAL_OVERHEAD;
+/// the most key slots an archive can hold. the table of slots is always
+/// this large, so it can be rewritten without moving anything after it
+pub const MAX_KEY_SLOTS: usize = 8;
+
+/// argon2id costs used for new passwords, 64mb of memory over 3 passes
+pub const DEFAULT_MEMORY_KB: u32 = 64 * 1024;
+pub const DEFAULT_ITERATIONS: u32 = 3;
+const DEFAULT_LANES: u32 = 1;
+/// the most argon2id costs a password slot may ask for, 4gb of memory over 64 passes
+/// in 16 lanes. the slot table is read before anything can be authenticated, so
+/// a tampered slot asking for more is refused rather than spent on
+pub const MAX_MEMORY_KB: u32 = 4 * 1024 * 1024;
+pub const MAX_ITERATIONS: u32 = 64;
+pub const MAX_LANES: u32 = 16;
+
+/// what a wrapped content key is authenticated against
+const KEY_SLOT_CONTEXT: &[u8] = b"ark key slot";
+/// what the key chunks are identified with is derived for
+const CHUNK_HASH_CONTEXT: &[u8] = b"ark chunk hash";
+/// what sealed headers are authenticated against
+const HEADER_CONTEXT: &[u8] = b"ark header";
+/// what a sealed central directory is authenticated against
+const DIRECTORY_CONTEXT: &[u8] = b"ark directory";
+/// the extra bytes sealing adds to a header, including its length
+pub const SEALED_HEADER_OVERHEAD: u64 = 4 + SEAL_OVERHEAD as u64;
+
+/// a copy of an archive's content key, wrapped so it can only be
+/// opened with a password or by the holder of a recipient's secret key.
+///
+/// every slot takes up the same space, and the table stored straight after
+/// the archive header is padded out to `MAX_KEY_SLOTS` with empty slots
+///
+/// # Layout
+/// ```ignore
+/// kind            u8       0 -> empty, 1 -> password, 2 -> recipient
+///
+/// // password slot
+/// salt            [u8; 16]
+/// memory          u32      argon2id memory cost in kilobytes
+/// iterations      u32
+/// lanes           u32
+/// padding         [u8; 12]
+///
+/// // recipient slot
+/// fingerprint     [u8; 8]  start of the sha-256 of the recipient's public key
+/// ephemeral key   [u8; 32] x25519 public key the slot's key was agreed with
+///
+/// wrapped key     [u8; 72] the content key, sealed with the key of the slot
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySlot {
+    Password {
+        salt: [u8; SALT_SIZE],
+        memory: u32,
+        iterations: u32,
+        lanes: u32,
+        wrapped: [u8; WRAPPED_KEY_SIZE],
+    },
+    Recipient {
+        fingerprint: [u8; 8],
+        ephemeral: [u8; KEY_SIZE],
+        wrapped: [u8; WRAPPED_KEY_SIZE],
+    },
+}
+
+impl KeySlot {
+    /// size in bytes of an encoded slot
+    pub const SIZE: usize = 1 + 40 + WRAPPED_KEY_SIZE;
+    /// size in bytes of the whole table of slots
+    pub const TABLE_SIZE: usize = Self::SIZE * MAX_KEY_SLOTS;
+
+    const EMPTY: u8 = 0;
+    const PASSWORD: u8 = 1;
+    const RECIPIENT: u8 = 2;
+
+    /// encode a single slot
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        match self {
+            KeySlot::Password { salt, memory, iterations, lanes, wrapped } => {
+                bytes.push(Self::PASSWORD);
+                bytes.extend_from_slice(salt);
+                bytes.extend_from_slice(&memory.to_le_bytes());
+                bytes.extend_from_slice(&iterations.to_le_bytes());
+                bytes.extend_from_slice(&lanes.to_le_bytes());
+                bytes.extend_from_slice(&[0; 12]);
+                bytes.extend_from_slice(wrapped);
+            }
+            KeySlot::Recipient { fingerprint, ephemeral, wrapped } => {
+                bytes.push(Self::RECIPIENT);
+                bytes.extend_from_slice(fingerprint);
+                bytes.extend_from_slice(ephemeral);
+                bytes.extend_from_slice(wrapped);
+            }
+        }
+        bytes
+    }
+
+    /// encode a table of slots, padded with empty slots
+    pub fn table_to_bytes(slots: &[KeySlot]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::TABLE_SIZE);
+        for slot in slots {
+            bytes.extend(slot.to_bytes());
+        }
+        bytes.resize(Self::TABLE_SIZE, Self::EMPTY);
+        bytes
+    }
+
+    /// an encoded empty slot, which a new slot can be written into
+    pub fn empty_bytes() -> Vec<u8> {
+        vec![Self::EMPTY; Self::SIZE]
+    }
+
+    /// whether an encoded slot is empty
+    pub fn is_empty(bytes: &[u8]) -> bool {
+        bytes.first() == Some(&Self::EMPTY)
+    }
+
+    /// read a whole table of slots, leaving out the empty ones
+    pub fn read_table(reader: &mut impl Read) -> Result<Vec<KeySlot>, HeaderError> {
+        let mut slots = vec![];
+        for _ in 0..MAX_KEY_SLOTS {
+            let slot: [u8; Self::SIZE] = read_array(reader)?;
+            let (kind, body) = slot.split_first().unwrap();
+            let body = &mut &body[..];
+
+            match *kind {
+                Self::EMPTY => {}
+                Self::PASSWORD => {
+                    let salt = read_array(body)?;
+                    let memory = u32::from_le_bytes(read_array(body)?);
+                    let iterations = u32::from_le_bytes(read_array(body)?);
+                    let lanes = u32::from_le_bytes(read_array(body)?);
+                    let _padding: [u8; 12] = read_array(body)?;
+                    slots.push(KeySlot::Password {
+                        salt, memory, iterations, lanes, wrapped: read_array(body)?
+                    });
+                }
+                Self::RECIPIENT => slots.push(KeySlot::Recipient {
+                    fingerprint: read_array(body)?,
+                    ephemeral: read_array(body)?,
+                    wrapped: read_array(body)?,
+                }),
+                kind => return Err(HeaderError::UnknownKeySlot(kind)),
+            }
+        }
+        Ok(slots)
+    }
+}
+
+/// a short identifier of a recipient's public key, to find their slot by
+fn fingerprint(recipient: &PublicKey) -> [u8; 8] {
+    Sha256::digest(recipient.as_bytes())[..8].try_into().unwrap()
+}
+
+/// derive the key wrapping a password slot's copy of the content key
+fn password_key(
+    password: &str, salt: &[u8; SALT_SIZE], memory: u32, iterations: u32, lanes: u32
+) -> Result<XChaCha20Poly1305, ArchivalError>
+{
+    let invalid = |e| ArchivalError(format!("invalid key derivation parameters: {}", e));
+    if memory > MAX_MEMORY_KB || iterations > MAX_ITERATIONS || lanes > MAX_LANES {
+        return Err(ArchivalError(format!(
+            "the key derivation costs of {} over {} passes in {} lanes are more than the \
+            {} over {} passes in {} lanes allowed, the archive is corrupt or was tampered with",
+            format_bytes!(memory as u64 * KILOBYTE), iterations, lanes,
+            format_bytes!(MAX_MEMORY_KB as u64 * KILOBYTE), MAX_ITERATIONS, MAX_LANES
+        )))
+    }
+
+    let costs = Params::new(memory, iterations, lanes, Some(KEY_SIZE)).map_err(invalid)?;
+    let mut key = [0; KEY_SIZE];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, costs)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(invalid)?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// derive the key wrapping a recipient slot's copy of the content key from
+/// the x25519 agreement between an ephemeral key and the recipient's key.
+/// both public keys are bound into the derived key
+fn recipient_key(
+    shared: &[u8; KEY_SIZE], ephemeral: &PublicKey, recipient: &PublicKey
+) -> XChaCha20Poly1305
+{
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key = [0; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(KEY_SLOT_CONTEXT, &mut key)
+        .expect("32 bytes is a valid hkdf output length");
+    XChaCha20Poly1305::new(&key.into())
+}
+
+/// the random key an archive's data is sealed with, along with the slots it can be unlocked through
+#[derive(Clone)]
+pub struct Cipher {
+    cipher: XChaCha20Poly1305,
+    content_key: [u8; KEY_SIZE],
+    /// the key chunks are identified with, derived from the content key
+    chunk_key: [u8; KEY_SIZE],
+    pub slots: Vec<KeySlot>,
+}
+
+impl Cipher {
+    /// generate a content key for a new archive, which still needs slots to be opened through
+    pub fn generate() -> Cipher {
+        let mut content_key = [0; KEY_SIZE];
+        OsRng.fill_bytes(&mut content_key);
+        Cipher::from_content_key(content_key, vec![])
+    }
+
+    fn from_content_key(content_key: [u8; KEY_SIZE], slots: Vec<KeySlot>) -> Cipher {
+        let mut chunk_key = [0; KEY_SIZE];
+        Hkdf::<Sha256>::new(None, &content_key)
+            .expand(CHUNK_HASH_CONTEXT, &mut chunk_key)
+            .expect("32 bytes is a valid hkdf output length");
+        Cipher {
+            cipher: XChaCha20Poly1305::new(&content_key.into()),
+            content_key,
+            chunk_key,
+            slots,
+        }
+    }
+
+    /// the identity of a chunk within an encrypted archive. a plain hash would show
+    /// which chunks hold known contents, so only those with the key can tell
+    pub fn chunk_hash(&self, chunk: &[u8]) -> ChunkHash {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.chunk_key)
+            .expect("hmac takes a key of any length");
+        mac.update(chunk);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// let the archive be opened with `password`
+    pub fn add_password(&mut self, password: &str) -> Result<(), ArchivalError> {
+        self.add_password_slot(password, DEFAULT_MEMORY_KB, DEFAULT_ITERATIONS)
+    }
+
+    /// replace every password slot with one for `password`, its key derived with
+    /// `memory` kilobytes over `iterations` passes. recipient slots are left as they are
+    pub fn replace_password(&mut self, password: &str, memory: u32, iterations: u32) -> Result<(), ArchivalError> {
+        self.slots.retain(|slot| !matches!(slot, KeySlot::Password { .. }));
+        self.add_password_slot(password, memory, iterations)
+    }
+
+    /// the argon2id memory and iterations of the first password slot, if there is one
+    pub fn password_costs(&self) -> Option<(u32, u32)> {
+        self.slots.iter().find_map(|slot| match slot {
+            KeySlot::Password { memory, iterations, .. } => Some((*memory, *iterations)),
+            _ => None
+        })
+    }
+
+    fn add_password_slot(&mut self, password: &str, memory: u32, iterations: u32) -> Result<(), ArchivalError> {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let lanes = DEFAULT_LANES;
+
+        let key = password_key(password, &salt, memory, iterations, lanes)?;
+        let wrapped = self.wrap(&key);
+        self.add_slot(KeySlot::Password { salt, memory, iterations, lanes, wrapped })
+    }
+
+    /// let the archive be opened by the holder of the secret half of `recipient`
+    pub fn add_recipient(&mut self, recipient: &PublicKey) -> Result<(), ArchivalError> {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(ArchivalError(String::from("refusing to encrypt to an invalid public key")))
+        }
+
+        let wrapped = self.wrap(&recipient_key(shared.as_bytes(), &ephemeral, recipient));
+        self.add_slot(KeySlot::Recipient {
+            fingerprint: fingerprint(recipient),
+            ephemeral: ephemeral.to_bytes(),
+            wrapped,
+        })
+    }
+
+    /// whether `recipient` already has a slot
+    pub fn has_recipient(&self, recipient: &PublicKey) -> bool {
+        let fingerprint = fingerprint(recipient);
+        self.slots.iter().any(|slot| matches!(
+            slot, KeySlot::Recipient { fingerprint: f, .. } if *f == fingerprint
+        ))
+    }
+
+    /// remove the slot of `recipient`, returning whether they had one.
+    ///
+    /// this only stops the archive being opened with their key, anyone who has
+    /// already opened it could have kept the content key, which doesn't change
+    pub fn revoke_recipient(&mut self, recipient: &PublicKey) -> bool {
+        let fingerprint = fingerprint(recipient);
+        let count = self.slots.len();
+        self.slots.retain(|slot| !matches!(
+            slot, KeySlot::Recipient { fingerprint: f, .. } if *f == fingerprint
+        ));
+        self.slots.len() != count
+    }
+
+    fn add_slot(&mut self, slot: KeySlot) -> Result<(), ArchivalError> {
+        if self.slots.len() == MAX_KEY_SLOTS {
+            return Err(ArchivalError(
+                format!("an archive can't have more than {} passwords and recipients", MAX_KEY_SLOTS)
+            ))
+        }
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    fn wrap(&self, key: &XChaCha20Poly1305) -> [u8; WRAPPED_KEY_SIZE] {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = key
+            .encrypt(&nonce, Payload { msg: &self.content_key, aad: KEY_SLOT_CONTEXT })
+            .expect("encrypting into memory can't fail");
+
+        let mut wrapped = [0; WRAPPED_KEY_SIZE];
+        wrapped[..NONCE_SIZE].copy_from_slice(&nonce);
+        wrapped[NONCE_SIZE..].copy_from_slice(&sealed);
+        wrapped
+    }
+
+    /// open the content key of an existing archive through the first slot
+    /// that `password` or `identity` unlocks.
+    ///
+    /// a password slot whose key can't be derived is passed over, as one left half
+    /// cleared by an interrupted re-key mustn't stand in the way of the slots after it.
+    /// the reason is only given when no slot unlocks the archive
+    pub fn unlock(
+        slots: Vec<KeySlot>, password: Option<&str>, identity: Option<&StaticSecret>
+    ) -> Result<Cipher, ArchivalError>
+    {
+        let identity = identity.map(|secret| (secret, PublicKey::from(secret)));
+
+        let mut failed = None;
+        for slot in &slots {
+            let content_key = match (slot, password, &identity) {
+                (KeySlot::Password { salt, memory, iterations, lanes, wrapped }, Some(password), _) => {
+                    match password_key(password, salt, *memory, *iterations, *lanes) {
+                        Ok(key) => unwrap_key(&key, wrapped),
+                        Err(e) => {
+                            failed.get_or_insert(e);
+                            None
+                        }
+                    }
+                }
+                (KeySlot::Recipient { fingerprint: f, ephemeral, wrapped }, _, Some((secret, public)))
+                    if *f == fingerprint(public) =>
+                {
+                    let ephemeral = PublicKey::from(*ephemeral);
+                    let shared = secret.diffie_hellman(&ephemeral);
+                    unwrap_key(&recipient_key(shared.as_bytes(), &ephemeral, public), wrapped)
+                }
+                _ => None
+            };
+
+            if let Some(content_key) = content_key {
+                return Ok(Cipher::from_content_key(content_key, slots))
+            }
+        }
+        if let Some(e) = failed {
+            return Err(e)
+        }
+
+        Err(ArchivalError(String::from(match (password, identity) {
+            (None, None) => "the archive is encrypted, give its password with -k or a key with -i",
+            (Some(_), None) => "wrong password for the archive",
+            _ => "the archive can't be opened with the given password or key",
+        })))
+    }
+
+    /// encrypt and authenticate `data`, returning the nonce, ciphertext and tag.
+    ///
+    /// `context` is authenticated along with the data, so data sealed for one
+    /// entry can't be swapped in for another's
+    pub fn seal(&self, data: &[u8], context: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self.cipher
+            .encrypt(&nonce, Payload { msg: data, aad: context })
+            .expect("encrypting into memory can't fail");
+
+        let mut bytes = Vec::with_capacity(NONCE_SIZE + sealed.len());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend(sealed);
+        bytes
+    }
+
+    /// decrypt data sealed with the same key and context,
+    /// returning nothing if it was tampered with
+    pub fn open(&self, sealed: &[u8], context: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return None
+        }
+        let (nonce, data) = sealed.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: data, aad: context })
+            .ok()
+    }
+}
+
+/// open a wrapped content key, returning nothing if `key` isn't the one it was wrapped with
+fn unwrap_key(key: &XChaCha20Poly1305, wrapped: &[u8; WRAPPED_KEY_SIZE]) -> Option<[u8; KEY_SIZE]> {
+    let (nonce, sealed) = wrapped.split_at(NONCE_SIZE);
+    key.decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad: KEY_SLOT_CONTEXT })
+        .ok()
+        .and_then(|key| key.try_into().ok())
+}
+
+/// decrypt data read from an archive which is `encrypted`, with the cipher it was unlocked
+/// with if any. the same as `Archiver::open_data`, for threads which don't own the archiver
+pub(crate) fn open_data(
+    encrypted: bool, cipher: Option<&Cipher>, data: Vec<u8>, context: &[u8], name: &str
+) -> Result<Vec<u8>, ArchivalError>
+{
+    if !encrypted {
+        return Ok(data)
+    }
+    let cipher = match cipher {
+        Some(cipher) => cipher,
+        None => return Err(ArchivalError(
+            format!("\"{}\" is encrypted, give the archive's password with -k or a key with -i", name)
+        ))
+    };
+    match cipher.open(&data, context) {
+        Some(data) => Ok(data),
+        None => Err(ArchivalError(
+            format!("\"{}\" could not be decrypted, the archive is corrupt or was tampered with", name)
+        ))
+    }
+}
+
+impl Archiver {
+    /// read the key slots following the header of an encrypted archive,
+    /// unlocking it straight away when a password or key was given, or can be asked for
+    pub(crate) fn read_key_slots(&mut self) -> Result<(), ArchivalError> {
+        let slots = KeySlot::read_table(self.archive_reader.as_mut().unwrap())?;
+        self.bytes_processed += KeySlot::TABLE_SIZE;
+
+        let has_password = slots.iter().any(|slot| matches!(slot, KeySlot::Password { .. }));
+        if self.password.is_none() && self.identity.is_none() && self.prompt && has_password {
+            self.password = Some(prompt_password(&self.input)?);
+        }
+
+        if self.password.is_some() || self.identity.is_some() {
+            let identity = match &self.identity {
+                Some(path) => Some(keys::read_secret_key(path)?),
+                None => None,
+            };
+            self.cipher = Some(Cipher::unlock(slots.clone(), self.password.as_deref(), identity.as_ref())?);
+        } else if self.encrypt_headers {
+            return Err(ArchivalError(String::from(
+                "the archive's headers are encrypted, give its password with -k or a key with -i"
+            )))
+        }
+        self.encryption = Some(slots);
+        Ok(())
+    }
+
+    /// settle the key a new archive is written with. an archive being rewritten keeps
+    /// its content key, so the entries copied over from it stay readable
+    pub(crate) fn prepare_cipher(&mut self, existing: bool) -> Result<(), ArchivalError> {
+        let encrypting = self.password.is_some() || !self.recipients.is_empty();
+        match (&self.encryption, &mut self.cipher) {
+            (Some(_), None) => Err(ArchivalError(String::from(
+                "the archive is encrypted, give its password with -k or a key with -i to change it"
+            ))),
+            // recipients can be added whenever the archive is rewritten
+            (Some(_), Some(cipher)) => {
+                for path in &self.recipients {
+                    let recipient = keys::read_public_key(path)?;
+                    if !cipher.has_recipient(&recipient) {
+                        cipher.add_recipient(&recipient)?;
+                    }
+                }
+                Ok(())
+            }
+            (None, _) if self.encrypt_headers && !encrypting => Err(ArchivalError(String::from(
+                "encrypting headers needs a password or recipients, give them with -k or -r"
+            ))),
+            (None, _) if existing && encrypting => Err(ArchivalError(String::from(
+                "the archive isn't encrypted, so it can't be given a password or recipients"
+            ))),
+            (None, _) if encrypting => {
+                let mut cipher = Cipher::generate();
+                if let Some(password) = &self.password {
+                    cipher.add_password(password)?;
+                }
+                for path in &self.recipients {
+                    cipher.add_recipient(&keys::read_public_key(path)?)?;
+                }
+                self.cipher = Some(cipher);
+                Ok(())
+            }
+            (None, _) => Ok(())
+        }
+    }
+
+    /// write the archive header, followed by the table of key slots when encrypting
+    pub(crate) fn write_archive_header(&mut self, total_files: usize, version: usize) -> Result<(), ArchivalError> {
+        let mut bytes = self.build_archive_header(total_files, version)?.to_bytes();
+        if let Some(cipher) = &self.cipher {
+            bytes.extend(KeySlot::table_to_bytes(&cipher.slots));
+        }
+
+        match self.write_archive_bytes(bytes.as_slice()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ArchivalError(
+                format!("Failed to write archive header: {}", e)
+            ))
+        }
+    }
+
+    /// encrypt data about to be written when the archive has a key, otherwise leave it as it is
+    pub(crate) fn seal_data(&self, data: Vec<u8>, context: &[u8]) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(&data, context),
+            None => data,
+        }
+    }
+
+    /// the identity of a chunk, keyed when the archive is encrypted
+    pub(crate) fn chunk_hash(&self, chunk: &[u8]) -> ChunkHash {
+        match &self.cipher {
+            Some(cipher) => cipher.chunk_hash(chunk),
+            None => chunker::hash(chunk),
+        }
+    }
+
+    /// the length `len` bytes of data take up once passed through `seal_data`
+    pub(crate) fn sealed_len(&self, len: usize) -> usize {
+        match self.cipher {
+            Some(_) => len + SEAL_OVERHEAD,
+            None => len,
+        }
+    }
+
+    /// decrypt data read from an encrypted archive, ensuring it hasn't been tampered with.
+    /// data from an archive which isn't encrypted is returned as it is
+    pub(crate) fn open_data(&self, data: Vec<u8>, context: &[u8], name: &str) -> Result<Vec<u8>, ArchivalError> {
+        open_data(self.encryption.is_some(), self.cipher.as_ref(), data, context, name)
+    }
+
+    /// encode a header as it is written to the archive, sealed when headers are encrypted.
+    ///
+    /// a sealed header is stored as its length followed by the sealed bytes,
+    /// so it can be read back without knowing anything about it
+    pub(crate) fn encode_header(&self, header: &Header) -> Vec<u8> {
+        self.seal_header_bytes(header.to_bytes(), HEADER_CONTEXT)
+    }
+
+    /// seal a header or the central directory when headers are encrypted
+    pub(crate) fn seal_header_bytes(&self, bytes: Vec<u8>, context: &[u8]) -> Vec<u8> {
+        let cipher = match &self.cipher {
+            Some(cipher) if self.encrypt_headers => cipher,
+            _ => return bytes
+        };
+        let sealed = cipher.seal(&bytes, context);
+        let mut encoded = Vec::with_capacity(4 + sealed.len());
+        encoded.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        encoded.extend(sealed);
+        encoded
+    }
+
+    /// open a header or the central directory written by `seal_header_bytes`
+    pub(crate) fn open_header_bytes(
+        &self, bytes: Vec<u8>, context: &[u8], what: &str
+    ) -> Result<Vec<u8>, ArchivalError>
+    {
+        let cipher = match &self.cipher {
+            Some(cipher) if self.encrypt_headers => cipher,
+            _ => return Ok(bytes)
+        };
+        let opened = bytes.split_first_chunk::<4>()
+            .filter(|(len, sealed)| u32::from_le_bytes(**len) as usize == sealed.len())
+            .and_then(|(_, sealed)| cipher.open(sealed, context));
+        match opened {
+            Some(bytes) => Ok(bytes),
+            None => Err(ArchivalError(
+                format!("{} could not be decrypted, the archive is corrupt or was tampered with", what)
+            ))
+        }
+    }
+
+    /// seal the central directory when headers are encrypted. otherwise the directory of an
+    /// encrypted archive is followed by a tag sealing nothing against it, so its entries,
+    /// those without any data to seal included, can be listed without the key but not changed
+    pub(crate) fn seal_directory(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) if !self.encrypt_headers => {
+                let tag = cipher.seal(&[], &directory_tag_context(&bytes));
+                [bytes, tag].concat()
+            }
+            _ => self.seal_header_bytes(bytes, DIRECTORY_CONTEXT)
+        }
+    }
+
+    /// open a central directory written by `seal_directory`.
+    /// the tag can only be checked once the archive is unlocked
+    pub(crate) fn open_directory(&self, bytes: Vec<u8>) -> Result<Vec<u8>, ArchivalError> {
+        if self.encryption.is_none() || self.encrypt_headers {
+            return self.open_header_bytes(bytes, DIRECTORY_CONTEXT, "the central directory")
+        }
+        let tampered = || ArchivalError(String::from(
+            "the central directory could not be authenticated, the archive is corrupt or was tampered with"
+        ));
+        let (directory, tag) = match bytes.len().checked_sub(SEAL_OVERHEAD) {
+            Some(len) => bytes.split_at(len),
+            None => return Err(tampered())
+        };
+        if let Some(cipher) = &self.cipher {
+            cipher.open(tag, &directory_tag_context(directory)).ok_or_else(tampered)?;
+        }
+        Ok(directory.to_vec())
+    }
+
+    /// read and open the sealed header at the current position of the archive
+    pub(crate) fn read_sealed_header(&mut self) -> Result<Header, ArchivalError> {
+        let reader = self.archive_reader.as_mut().unwrap();
+        let offset = reader.stream_position()
+            .map_err(|e| ArchivalError(format!("failed to seek within archive: {}", e)))?;
+
+        let len: [u8; 4] = read_array(reader)?;
+        let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+        if let Err(e) = reader.read_exact(&mut bytes) {
+            return Err(ArchivalError(
+                format!("failed to read the header at offset {}: {}", offset, e)
+            ))
+        }
+        self.bytes_processed += 4 + bytes.len();
+
+        let mut encoded = len.to_vec();
+        encoded.extend(bytes);
+        let what = format!("the header at offset {}", offset);
+        let header = self.open_header_bytes(encoded, HEADER_CONTEXT, &what)?;
+        Ok(Header::read_entry(&mut header.as_slice())?)
+    }
+
+    /// the extra bytes in front of every header's contents
+    pub(crate) fn header_overhead(&self) -> u64 {
+        match self.encrypt_headers {
+            true => SEALED_HEADER_OVERHEAD,
+            false => 0,
+        }
+    }
+
+    /// the position of an entry's data, past its header whether sealed or not
+    pub(crate) fn entry_data_offset(&self, entry: &DirectoryEntry) -> u64 {
+        entry.data_offset() + self.header_overhead()
+    }
+}
+
+/// what the tag following a central directory is authenticated against
+fn directory_tag_context(directory: &[u8]) -> Vec<u8> {
+    [DIRECTORY_CONTEXT, directory].concat()
+}
+
+/// ask for the password of `archive` on the terminal, without echoing it
+fn prompt_password(archive: &Path) -> Result<String, ArchivalError> {
+    match rpassword::prompt_password(format!("Password for \"{}\": ", archive.display())) {
+        Ok(password) => Ok(password),
+        Err(e) => Err(ArchivalError(
+            format!("the archive is encrypted, and its password could not be read: {}", e)
+        ))
+    }
+}
+
+/// ask for a new password on the terminal, twice so a typo can't lock the archive away
+pub(crate) fn prompt_new_password(archive: &Path) -> Result<String, ArchivalError> {
+    let read = |prompt: String| rpassword::prompt_password(prompt).map_err(|e| ArchivalError(
+        format!("the new password could not be read: {}", e)
+    ));
+    let password = read(format!("New password for \"{}\": ", archive.display()))?;
+    if read(String::from("Repeat the new password: "))? != password {
+        return Err(ArchivalError(String::from("the new passwords didn't match")))
+    }
+    Ok(password)
+}
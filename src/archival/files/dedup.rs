@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{copy, BufReader, Read, Write};
+use std::path::PathBuf;
+use crate::archival::compression::chunker;
+use crate::archival::compression::chunker::{ChunkHash, DedupEstimate, StreamChunker};
+use crate::archival::compression::file_compressor::Compressor;
+use crate::archival::cli::output::FmtProgress;
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::directory::{ChunkEntry, DirectoryEntry, EntryKind};
+use crate::archival::files::header::Header;
+use crate::archival::files::integrity::ChecksumWriter;
+
+/// method bit marking a file whose data is a list of chunks rather than its contents
+pub const CHUNKED: u8 = 1 << 0;
+
+impl Archiver {
+    /// archive a file as a list of content defined chunks,
+    /// storing only the chunks which aren't already in the archive
+    pub(crate) fn archive_chunked_file(
+        &mut self, method: u8, path: &PathBuf
+    ) -> Result<u64, ArchivalError>
+    {
+        let read_err = |e: io::Error| ArchivalError(
+            format!("Could not read input file: \"{}\"\nreason: {}", path.display(), e)
+        );
+        let file = File::open(path).map_err(read_err)?;
+
+        // the chunks come before the header, so the file only needs to be read once
+        let mut chunker = StreamChunker::new(BufReader::new(file));
+        let mut sums = ChecksumWriter::new(io::sink());
+        let mut list = vec![0; 4];
+        while let Some(chunk) = chunker.next_chunk().map_err(read_err)? {
+            let _ = sums.write_all(chunk);
+            let hash = self.chunk_hash(chunk);
+            if !self.written_chunks.contains(&hash) {
+                self.store_chunk(hash, chunk, method)?;
+            }
+            list.extend_from_slice(&hash);
+        }
+        let count = (list.len() / 32) as u32;
+        list[..4].copy_from_slice(&count.to_le_bytes());
+
+        // the list is sealed against the header holding its length
+        let header = self.build_file_header(path, self.sealed_len(list.len()), method | CHUNKED, sums.checksum())?;
+        let list = self.seal_data(list, &header.to_bytes());
+        self.write_entry_header(&header)?;
+        if let Err(e) = self.write_archive_bytes(list.as_slice()) {
+            return Err(ArchivalError(
+                format!("Failed to write file data: {}", e)
+            ))
+        }
+
+        // logging
+        self.files_processed += 1;
+        self.bytes_processed += sums.written() as usize;
+        self.speed = (self.bytes_processed as f64 /
+            self.start_time.unwrap().elapsed().as_secs_f64()
+        ) as usize;
+
+        self.format_progress(format!("{}", path.display()));
+        Ok(0)
+    }
+
+    /// write a chunk to the output archive. chunks already held by the
+    /// archive being updated are copied over instead of compressed again
+    fn store_chunk(&mut self, hash: ChunkHash, data: &[u8], method: u8) -> Result<(), ArchivalError> {
+        if self.chunks.contains_key(&hash) {
+            return self.copy_chunk(&hash, "a new file")
+        }
+
+        let compressed = match method {
+            0 => data.to_vec(),
+            m => Compressor::new(data.to_vec(), m).compress(),
+        };
+        let header = Header::Chunk {
+            hash,
+            method,
+            compressed_size: self.sealed_len(compressed.len()) as u32,
+            decompressed_size: data.len() as u32,
+        };
+        let compressed = self.seal_data(compressed, &header.to_bytes());
+
+        self.directory.chunks.push(ChunkEntry { hash, offset: self.archive_offset });
+        self.written_chunks.insert(hash);
+        let write_res = self.write_archive_bytes(self.encode_header(&header).as_slice())
+            .and_then(|_| self.write_archive_bytes(compressed.as_slice()));
+        if let Err(e) = write_res {
+            return Err(ArchivalError(
+                format!("Failed to write chunk: {}", e)
+            ))
+        }
+        Ok(())
+    }
+
+    /// copy a chunk, header and data, byte for byte from the archive being read
+    fn copy_chunk(&mut self, hash: &ChunkHash, used_by: &str) -> Result<(), ArchivalError> {
+        let offset = self.chunk_offset(hash, used_by)?;
+        self.seek_archive(offset)?;
+        let compressed_size = match self.read_entry_header()? {
+            Header::Chunk { compressed_size, .. } => compressed_size as u64,
+            _ => return Err(ArchivalError(
+                format!("expected a chunk used by \"{}\" at offset {}", used_by, offset)
+            ))
+        };
+        self.seek_archive(offset)?;
+
+        let len = Header::CHUNK_HEADER_SIZE as u64 + self.header_overhead() + compressed_size;
+        let mut source = self.archive_reader.as_mut().unwrap().take(len);
+        let written = match copy(&mut source, self.archive_writer.as_mut().unwrap()) {
+            Ok(written) => written,
+            Err(e) => return Err(ArchivalError(
+                format!("Failed to copy a chunk of \"{}\" into the new archive: {}", used_by, e)
+            ))
+        };
+        if written != len {
+            return Err(ArchivalError(
+                format!("a chunk of \"{}\" is truncated in the existing archive", used_by)
+            ))
+        }
+
+        self.directory.chunks.push(ChunkEntry { hash: *hash, offset: self.archive_offset });
+        self.written_chunks.insert(*hash);
+        self.archive_offset += written;
+        Ok(())
+    }
+
+    /// copy every chunk used by a deduplicated entry which isn't in the output archive yet
+    pub(crate) fn copy_entry_chunks(&mut self, entry: &DirectoryEntry) -> Result<(), ArchivalError> {
+        if entry.kind != EntryKind::File || entry.method & CHUNKED == 0 {
+            return Ok(())
+        }
+
+        self.seek_archive(self.entry_data_offset(entry))?;
+        let mut list = vec![0; entry.compressed_size as usize];
+        if let Err(e) = self.archive_reader.as_mut().unwrap().read_exact(&mut list) {
+            return Err(ArchivalError(
+                format!("failed to read the chunk list of \"{}\": {}", entry.name, e)
+            ))
+        }
+        let list = self.open_data(list, &entry.header().to_bytes(), &entry.name)?;
+
+        for hash in parse_chunk_list(&entry.name, &list)? {
+            if !self.written_chunks.contains(&hash) {
+                self.copy_chunk(&hash, &entry.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// reassemble the contents of a deduplicated file from its list of chunks,
+    /// writing each chunk to `out` as soon as it is read
+    pub(crate) fn write_chunked_data(
+        &mut self, name: &str, list: &[u8], out: &mut impl Write
+    ) -> Result<(), ArchivalError>
+    {
+        for hash in parse_chunk_list(name, list)? {
+            if let Err(e) = out.write_all(&self.read_chunk(name, &hash)?) {
+                return Err(ArchivalError(
+                    format!("Could not write the data of \"{}\": {}", name, e)
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// read and decompress a single chunk, ensuring it still matches its hash
+    fn read_chunk(&mut self, name: &str, hash: &ChunkHash) -> Result<Vec<u8>, ArchivalError> {
+        let offset = self.chunk_offset(hash, name)?;
+        let (header, buffer) = self.read_shared(offset, name)?;
+        let method = match header {
+            Header::Chunk { hash: found, method, .. } if &found == hash => method,
+            _ => return Err(ArchivalError(
+                format!("expected a chunk of \"{}\" at offset {}", name, offset)
+            ))
+        };
+        let buffer = self.open_data(buffer, &header.to_bytes(), name)?;
+
+        let data = Compressor::new(buffer, method).decompress();
+        if &self.chunk_hash(&data) != hash {
+            return Err(ArchivalError(
+                format!("\"{}\" is corrupt: chunk at offset {} does not match its hash", name, offset)
+            ))
+        }
+        Ok(data)
+    }
+
+    fn chunk_offset(&self, hash: &ChunkHash, used_by: &str) -> Result<u64, ArchivalError> {
+        match self.chunks.get(hash) {
+            Some(offset) => Ok(*offset),
+            None => Err(ArchivalError(
+                format!("\"{}\" uses a chunk which is missing from the archive", used_by)
+            ))
+        }
+    }
+
+    /// chunk every indexed file, counting how much data would be stored once deduplicated
+    pub fn dedup_estimate(&self) -> Result<DedupEstimate, ArchivalError> {
+        let mut estimate = DedupEstimate::default();
+        let mut seen = HashSet::new();
+
+        for path in &self.files {
+            if !fs::symlink_metadata(path).map(|m| m.is_file()).unwrap_or(false) {
+                continue
+            }
+            let read_err = |e: io::Error| ArchivalError(
+                format!("Could not read input file: \"{}\"\nreason: {}", path.display(), e)
+            );
+            let file = File::open(path).map_err(read_err)?;
+
+            estimate.files += 1;
+            let mut chunker = StreamChunker::new(BufReader::new(file));
+            while let Some(chunk) = chunker.next_chunk().map_err(read_err)? {
+                estimate.total_bytes += chunk.len() as u64;
+                estimate.chunks += 1;
+                if seen.insert(chunker::hash(chunk)) {
+                    estimate.unique_chunks += 1;
+                    estimate.unique_bytes += chunk.len() as u64;
+                }
+            }
+        }
+        Ok(estimate)
+    }
+}
+
+/// split the data of a deduplicated file into the hashes of its chunks
+fn parse_chunk_list(name: &str, list: &[u8]) -> Result<Vec<ChunkHash>, ArchivalError> {
+    let invalid = || ArchivalError(format!("\"{}\" has an invalid chunk list", name));
+
+    let (count, hashes) = match list.split_first_chunk::<4>() {
+        Some((count, hashes)) => (u32::from_le_bytes(*count) as usize, hashes),
+        None => return Err(invalid())
+    };
+    if hashes.len() != count * 32 {
+        return Err(invalid())
+    }
+
+    Ok(hashes.chunks_exact(32)
+        .map(|hash| hash.try_into().unwrap())
+        .collect())
+}
@@ -0,0 +1,345 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use crate::archival::compression::chunker::ChunkHash;
+use crate::archival::files::header::{expect_magic, read_array, read_name, Header, HeaderError};
+use crate::archival::files::metadata::EntryMetadata;
+use crate::archival::files::stream::{unmeasured, DESCRIBED};
+
+/// signature found at the start of the central directory
+pub const DIRECTORY_MAGIC: [u8; 4] = *b"ARKI";
+/// signature found at the start of the footer
+pub const FOOTER_MAGIC: [u8; 4] = *b"ARKE";
+
+/// what an entry in the archive represents
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// a symbolic link and the path it points to
+    Symlink(String),
+    /// a hard link and the name of the file entry it shares data with
+    HardLink(String),
+}
+
+impl EntryKind {
+    const FILE: u8 = 0;
+    const DIRECTORY: u8 = 1;
+    const SYMLINK: u8 = 2;
+    const HARD_LINK: u8 = 3;
+
+    /// determine the kind of entry `path` should be archived as, without following symbolic links
+    pub fn of(path: &Path) -> io::Result<EntryKind> {
+        let file_type = fs::symlink_metadata(path)?.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            return match target.to_str() {
+                Some(target) => Ok(EntryKind::Symlink(target.to_owned())),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("link target {} is not valid utf-8", target.display())
+                ))
+            }
+        }
+        if file_type.is_dir() {
+            return Ok(EntryKind::Directory)
+        }
+        Ok(EntryKind::File)
+    }
+}
+
+/// the location and details of a single entry stored in the archive
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    /// position of the entry's file header, from the start of the archive
+    pub offset: u64,
+    /// the compression method used for this file
+    pub method: u8,
+    pub compressed_size: u64,
+    pub decompressed_size: u64,
+    /// crc32 of the decompressed file data
+    pub checksum: u32,
+    /// permissions, ownership and times of the original file
+    pub metadata: EntryMetadata,
+}
+
+impl DirectoryEntry {
+    /// describe the entry whose header is found at `offset`
+    pub fn from_header(header: &Header, offset: u64) -> DirectoryEntry {
+        match header {
+            Header::File
+            { name, method, compressed_size, decompressed_size, checksum, metadata } => {
+                DirectoryEntry {
+                    name: name.clone(),
+                    kind: EntryKind::File,
+                    offset,
+                    method: *method,
+                    compressed_size: *compressed_size,
+                    decompressed_size: *decompressed_size,
+                    checksum: *checksum,
+                    metadata: *metadata,
+                }
+            }
+            Header::Directory { name, metadata } => {
+                Self::without_data(name, EntryKind::Directory, offset, metadata)
+            }
+            Header::Symlink { name, target, metadata } => {
+                Self::without_data(name, EntryKind::Symlink(target.clone()), offset, metadata)
+            }
+            Header::HardLink { name, target, metadata } => {
+                Self::without_data(name, EntryKind::HardLink(target.clone()), offset, metadata)
+            }
+            Header::Archive { .. } | Header::Chunk { .. } | Header::Block { .. } => {
+                unreachable!("archive, chunk and block headers do not describe an entry")
+            }
+        }
+    }
+
+    /// the header the entry was written with, the reverse of `from_header`.
+    /// a described file's header was written without its compressed size and checksum
+    pub fn header(&self) -> Header {
+        let (name, metadata) = (self.name.clone(), self.metadata);
+        let header = match &self.kind {
+            EntryKind::File => Header::File {
+                name,
+                method: self.method,
+                compressed_size: self.compressed_size,
+                decompressed_size: self.decompressed_size,
+                checksum: self.checksum,
+                metadata,
+            },
+            EntryKind::Directory => Header::Directory { name, metadata },
+            EntryKind::Symlink(target) => Header::Symlink { name, target: target.clone(), metadata },
+            EntryKind::HardLink(target) => Header::HardLink { name, target: target.clone(), metadata },
+        };
+        match self.method & DESCRIBED {
+            0 => header,
+            _ => unmeasured(&header),
+        }
+    }
+
+    fn without_data(
+        name: &str, kind: EntryKind, offset: u64, metadata: &EntryMetadata
+    ) -> DirectoryEntry
+    {
+        DirectoryEntry {
+            name: name.to_owned(),
+            kind,
+            offset,
+            method: 0,
+            compressed_size: 0,
+            decompressed_size: 0,
+            checksum: 0,
+            metadata: *metadata,
+        }
+    }
+
+    /// position of the entry's (compressed) data, from the start of the archive
+    pub fn data_offset(&self) -> u64 {
+        let header_size = match &self.kind {
+            EntryKind::File => Header::FILE_HEADER_SIZE,
+            EntryKind::Directory => Header::DIR_HEADER_SIZE,
+            EntryKind::Symlink(target) | EntryKind::HardLink(target) => {
+                Header::LINK_HEADER_SIZE + target.len()
+            }
+        };
+        self.offset + (header_size + self.name.len()) as u64
+    }
+}
+
+/// the table of contents written after the last file in an archive,
+/// allowing entries to be found without walking every file header
+///
+/// # Layout
+/// ```ignore
+/// magic           [u8; 4]  "ARKI"
+/// entry count     u64
+/// // for each entry
+/// name length     u32
+/// name            [u8; name length]
+/// kind            u8       0 -> file, 1 -> directory, 2 -> symbolic link, 3 -> hard link
+/// target length   u32      links only
+/// target          [u8; target length]
+/// offset          u64
+/// method          u8
+/// compressed      u64
+/// decompressed    u64
+/// checksum        u32
+/// metadata        [u8; 36] see `EntryMetadata`
+///
+/// chunk count     u64
+/// // for each chunk
+/// hash            [u8; 32]
+/// offset          u64
+///
+/// block count     u64
+/// // for each solid block
+/// id              u64
+/// offset          u64
+///
+/// // when the archive is encrypted but its headers aren't, see `Archiver::seal_directory`
+/// tag             [u8; 40]
+/// ```
+#[derive(Default)]
+pub struct CentralDirectory {
+    pub entries: Vec<DirectoryEntry>,
+    /// every deduplicated chunk stored in the archive
+    pub chunks: Vec<ChunkEntry>,
+    /// every solid block stored in the archive
+    pub blocks: Vec<BlockEntry>,
+}
+
+/// the location of a single deduplicated chunk
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkEntry {
+    pub hash: ChunkHash,
+    /// position of the chunk's header, from the start of the archive
+    pub offset: u64,
+}
+
+/// the location of a single solid block
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockEntry {
+    pub id: u64,
+    /// position of the block's header, from the start of the archive
+    pub offset: u64,
+}
+
+impl CentralDirectory {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&DIRECTORY_MAGIC);
+        bytes.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+
+        for entry in &self.entries {
+            bytes.extend_from_slice(&(entry.name.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(entry.name.as_bytes());
+            match &entry.kind {
+                EntryKind::File => bytes.push(EntryKind::FILE),
+                EntryKind::Directory => bytes.push(EntryKind::DIRECTORY),
+                EntryKind::Symlink(target) | EntryKind::HardLink(target) => {
+                    bytes.push(match entry.kind {
+                        EntryKind::Symlink(_) => EntryKind::SYMLINK,
+                        _ => EntryKind::HARD_LINK,
+                    });
+                    bytes.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(target.as_bytes());
+                }
+            }
+            bytes.extend_from_slice(&entry.offset.to_le_bytes());
+            bytes.push(entry.method);
+            bytes.extend_from_slice(&entry.compressed_size.to_le_bytes());
+            bytes.extend_from_slice(&entry.decompressed_size.to_le_bytes());
+            bytes.extend_from_slice(&entry.checksum.to_le_bytes());
+            bytes.extend_from_slice(&entry.metadata.to_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.chunks.len() as u64).to_le_bytes());
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&chunk.hash);
+            bytes.extend_from_slice(&chunk.offset.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.blocks.len() as u64).to_le_bytes());
+        for block in &self.blocks {
+            bytes.extend_from_slice(&block.id.to_le_bytes());
+            bytes.extend_from_slice(&block.offset.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    /// read and validate a central directory from the current position of `reader`
+    pub fn read(reader: &mut impl Read) -> Result<CentralDirectory, HeaderError> {
+        expect_magic(reader, DIRECTORY_MAGIC)?;
+
+        let count = u64::from_le_bytes(read_array(reader)?);
+        let mut entries = vec![];
+        for _ in 0..count {
+            let name = read_name(reader)?;
+            let kind = match read_array(reader)? {
+                [EntryKind::FILE] => EntryKind::File,
+                [EntryKind::DIRECTORY] => EntryKind::Directory,
+                [EntryKind::SYMLINK] => EntryKind::Symlink(read_name(reader)?),
+                [EntryKind::HARD_LINK] => EntryKind::HardLink(read_name(reader)?),
+                [kind] => return Err(HeaderError::UnknownEntryKind(kind)),
+            };
+            let offset = u64::from_le_bytes(read_array(reader)?);
+            let [method] = read_array(reader)?;
+            let compressed_size = u64::from_le_bytes(read_array(reader)?);
+            let decompressed_size = u64::from_le_bytes(read_array(reader)?);
+            let checksum = u32::from_le_bytes(read_array(reader)?);
+            let metadata = EntryMetadata::read(reader)?;
+
+            entries.push(DirectoryEntry {
+                name,
+                kind,
+                offset,
+                method,
+                compressed_size,
+                decompressed_size,
+                checksum,
+                metadata,
+            });
+        }
+
+        let count = u64::from_le_bytes(read_array(reader)?);
+        let mut chunks = vec![];
+        for _ in 0..count {
+            chunks.push(ChunkEntry {
+                hash: read_array(reader)?,
+                offset: u64::from_le_bytes(read_array(reader)?),
+            });
+        }
+
+        let count = u64::from_le_bytes(read_array(reader)?);
+        let mut blocks = vec![];
+        for _ in 0..count {
+            blocks.push(BlockEntry {
+                id: u64::from_le_bytes(read_array(reader)?),
+                offset: u64::from_le_bytes(read_array(reader)?),
+            });
+        }
+
+        Ok(CentralDirectory { entries, chunks, blocks })
+    }
+}
+
+/// fixed size record at the very end of an archive which points at the central directory
+///
+/// # Layout
+/// ```ignore
+/// magic             [u8; 4]  "ARKE"
+/// directory offset  u64
+/// directory size    u64
+/// ```
+pub struct Footer {
+    pub directory_offset: u64,
+    pub directory_size: u64,
+}
+
+impl Footer {
+    /// size in bytes of an encoded footer
+    pub const SIZE: usize = 4 + 8 + 8;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&FOOTER_MAGIC);
+        bytes.extend_from_slice(&self.directory_offset.to_le_bytes());
+        bytes.extend_from_slice(&self.directory_size.to_le_bytes());
+        bytes
+    }
+
+    /// read and validate a footer from the current position of `reader`
+    pub fn read(reader: &mut impl Read) -> Result<Footer, HeaderError> {
+        expect_magic(reader, FOOTER_MAGIC)?;
+
+        Ok(Footer {
+            directory_offset: u64::from_le_bytes(read_array(reader)?),
+            directory_size: u64::from_le_bytes(read_array(reader)?),
+        })
+    }
+}
@@ -0,0 +1,73 @@
+use glob::{MatchOptions, Pattern};
+use crate::archival::files::archiver::ArchivalError;
+
+/// selects archive entries by path or glob pattern
+///
+/// a pattern matches an entry when it matches the whole entry path,
+/// or when it names a directory the entry is contained in.
+/// `*` does not cross directory boundaries, use `**` for that
+///
+/// # Example
+/// ```ignore
+/// let filter = EntryFilter::new(&["videos/**/*.mp4".to_owned(), "notes".to_owned()])?;
+/// assert!(filter.matches("videos/2024/trip.mp4"));
+/// assert!(filter.matches("notes/todo.txt"));
+/// assert!(!filter.matches("videos/trip.mov"));
+/// ```
+pub struct EntryFilter {
+    pub patterns: Vec<Pattern>,
+}
+
+impl EntryFilter {
+    const OPTIONS: MatchOptions = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+
+    /// compile `patterns` into a filter, an empty list matches every entry
+    pub fn new(patterns: &[String]) -> Result<EntryFilter, ArchivalError> {
+        let mut compiled = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            // entries are always stored with forward slashes
+            let pattern = pattern.replace('\\', "/");
+            let pattern = pattern.trim_end_matches('/');
+            match Pattern::new(pattern) {
+                Ok(p) => compiled.push(p),
+                Err(e) => return Err(ArchivalError(
+                    format!("invalid pattern \"{}\": {}", pattern, e)
+                ))
+            }
+        }
+        Ok(EntryFilter { patterns: compiled })
+    }
+
+    /// returns true if the filter has no patterns and selects everything
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// check whether the entry with the given name is selected by this filter
+    pub fn matches(&self, name: &str) -> bool {
+        if self.is_empty() {
+            return true
+        }
+
+        let name = name.replace('\\', "/");
+        self.patterns.iter().any(|pattern| {
+            if pattern.matches_with(&name, Self::OPTIONS) {
+                return true
+            }
+
+            // also select everything inside a matching directory
+            let mut parent = name.as_str();
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                if pattern.matches_with(dir, Self::OPTIONS) {
+                    return true
+                }
+                parent = dir;
+            }
+            false
+        })
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+use std::thread;
+use crate::archival::files::archiver::{ArchivalError, Archiver, Deferred};
+use crate::archival::files::crypto::SEAL_OVERHEAD;
+use crate::archival::files::directory::{CentralDirectory, DirectoryEntry, EntryKind};
+use crate::archival::files::filter::EntryFilter;
+use crate::archival::files::header::{read_array, Header};
+use crate::archival::files::paths;
+use crate::archival::files::pipeline::{ExtractPool, MemoryBudget, EXTRACT_MEMORY_BUDGET};
+use crate::archival::files::solid::SOLID;
+use crate::archival::files::stream::DESCRIBED;
+
+/// a chunk or solid block read from a stream, held in memory
+/// as the stream can't go back to it for the files which use it
+pub struct HeldData {
+    pub header: Header,
+    pub data: Vec<u8>,
+    /// how many bytes of a solid block haven't been read out of it by a file yet
+    pub unused: u64,
+}
+
+/// passes reads through, keeping a copy of every byte read
+struct Recording<'a, R> {
+    reader: &'a mut R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> Read for Recording<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl Archiver {
+    /// whether the archive is read from a stream, which can only be read forwards
+    pub(crate) fn is_streamed(&self) -> bool {
+        self.archive_reader.as_ref().is_some_and(|reader| reader.get_ref().is_stream())
+    }
+
+    /// read every entry of an archive from a stream in a single pass, handing each to
+    /// `visit` with the stream at the start of its data, which `visit` may read or leave.
+    ///
+    /// chunks and solid blocks are written before the files using them, so when
+    /// `shared` they are held in memory as they go by. a solid block is let go of
+    /// once every file in it has been passed, but a chunk may be used by any file
+    /// after it, so every chunk is held until the end. reading a deduplicated archive
+    /// from a stream takes as much memory as its compressed chunks.
+    ///
+    /// the entries of an encrypted archive are only returned once its
+    /// central directory, which follows them, has vouched for them
+    pub(crate) fn walk_stream(
+        &mut self, files: usize, shared: bool,
+        mut visit: impl FnMut(&mut Archiver, &DirectoryEntry) -> Result<(), ArchivalError>,
+    ) -> Result<Vec<DirectoryEntry>, ArchivalError>
+    {
+        let mut entries = Vec::with_capacity(files);
+        while entries.len() < files {
+            let offset = self.archive_position()?;
+            let header = self.read_entry_header()?;
+            let (len, unused) = match &header {
+                Header::Chunk { hash, compressed_size, .. } => {
+                    self.chunks.insert(*hash, offset);
+                    (*compressed_size as u64, 0)
+                }
+                Header::Block { id, compressed_size, decompressed_size, .. } => {
+                    self.blocks.insert(*id, offset);
+                    (*compressed_size, *decompressed_size)
+                }
+                header => {
+                    let mut entry = DirectoryEntry::from_header(header, offset);
+                    let data_start = self.archive_position()?;
+                    self.stream_entry = Some(entry.clone());
+                    self.described = None;
+                    let res = visit(self, &entry);
+                    self.stream_entry = None;
+                    res?;
+
+                    // a solid file passed over still counts towards letting go of its block
+                    let passed = self.archive_position()? == data_start;
+                    if shared && passed && entry.kind == EntryKind::File && entry.method & SOLID != 0 {
+                        self.skip_solid_file(&entry);
+                    }
+                    // a described file only gives its size after its blocks, once they are read or passed
+                    if entry.kind == EntryKind::File && entry.method & DESCRIBED != 0 {
+                        match (passed, self.described.take()) {
+                            (true, _) => self.measure_described(&mut entry)?,
+                            (false, Some((compressed, checksum))) => {
+                                entry.compressed_size = compressed;
+                                entry.checksum = checksum;
+                            }
+                            (false, None) => return Err(ArchivalError(format!(
+                                "the end of \"{}\" can't be found, as it was only partly read", entry.name
+                            ))),
+                        }
+                        entries.push(entry);
+                        continue
+                    }
+                    self.seek_archive(data_start + entry.compressed_size)?;
+                    entries.push(entry);
+                    continue
+                }
+            };
+
+            if !shared {
+                let data_start = self.archive_position()?;
+                self.seek_archive(data_start + len)?;
+                continue
+            }
+            let mut data = vec![0; len as usize];
+            if let Err(e) = self.archive_reader.as_mut().unwrap().read_exact(&mut data) {
+                return Err(ArchivalError(
+                    format!("failed to read the data at offset {}: {}", offset, e)
+                ))
+            }
+            self.held.insert(offset, HeldData { header, data, unused });
+        }
+
+        // only the directory vouches for the entries without any data
+        if self.encryption.is_some() && self.read_streamed_directory()?.entries != entries {
+            return Err(ArchivalError(String::from(
+                "the entries read from the stream don't match its central directory"
+            )))
+        }
+        Ok(entries)
+    }
+
+    /// read the header and data of the chunk or solid block at `offset`,
+    /// from memory when it was held on to while reading a stream
+    pub(crate) fn read_shared(&mut self, offset: u64, used_by: &str) -> Result<(Header, Vec<u8>), ArchivalError> {
+        if let Some(held) = self.held.get(&offset) {
+            return Ok((held.header.clone(), held.data.clone()))
+        }
+
+        self.seek_archive(offset)?;
+        let header = self.read_entry_header()?;
+        let len = match &header {
+            Header::Chunk { compressed_size, .. } => *compressed_size as u64,
+            Header::Block { compressed_size, .. } => *compressed_size,
+            _ => 0,
+        };
+        let mut data = vec![0; len as usize];
+        if let Err(e) = self.archive_reader.as_mut().unwrap().read_exact(&mut data) {
+            return Err(ArchivalError(
+                format!("failed to read the data at offset {} used by \"{}\": {}", offset, used_by, e)
+            ))
+        }
+        Ok((header, data))
+    }
+
+    /// read the central directory straight after the last entry of a stream,
+    /// without knowing its size up front
+    fn read_streamed_directory(&mut self) -> Result<CentralDirectory, ArchivalError> {
+        let reader = self.archive_reader.as_mut().unwrap();
+        let read_err = |e: io::Error| ArchivalError(
+            format!("failed to read the central directory: {}", e)
+        );
+
+        let bytes = match self.encrypt_headers {
+            true => {
+                let len: [u8; 4] = read_array(reader)?;
+                let mut sealed = vec![0; u32::from_le_bytes(len) as usize];
+                reader.read_exact(&mut sealed).map_err(read_err)?;
+                [len.to_vec(), sealed].concat()
+            }
+            false => {
+                let mut recording = Recording { reader: &mut *reader, bytes: vec![] };
+                CentralDirectory::read(&mut recording)?;
+                let directory = recording.bytes;
+                let mut tag = [0; SEAL_OVERHEAD];
+                reader.read_exact(&mut tag).map_err(read_err)?;
+                [directory, tag.to_vec()].concat()
+            }
+        };
+
+        let directory = self.open_directory(bytes)?;
+        Ok(CentralDirectory::read(&mut directory.as_slice())?)
+    }
+
+    /// extract the entries of an archive read from a stream as each is reached.
+    ///
+    /// a hard link can only be made to a file extracted before it, as the
+    /// stream can't go back for the data of a file which wasn't selected
+    pub(crate) fn extract_stream(&mut self, files: usize, filter: &EntryFilter) -> Result<(), ArchivalError> {
+        let mut deferred = Deferred::default();
+        let mut selected = 0;
+
+        let (encrypted, cipher, restore_owner) = (self.encryption.is_some(), self.cipher.clone(), self.restore_owner);
+        let budget = MemoryBudget::new(EXTRACT_MEMORY_BUDGET);
+        let jobs = self.jobs;
+        thread::scope(|scope| {
+            let mut pool = match jobs {
+                1 => None,
+                jobs => Some(ExtractPool::start(scope, jobs, &budget, encrypted, cipher.as_ref(), restore_owner)),
+            };
+            self.walk_stream(files, true, |this, entry| {
+                if !filter.matches(&entry.name) {
+                    return Ok(())
+                }
+                selected += 1;
+                match &entry.kind {
+                    EntryKind::HardLink(target) if !deferred.files.contains(target) => {
+                        return Err(ArchivalError(format!(
+                            "\"{}\" is a hard link to \"{}\", which has to be extracted before it \
+                            when the archive is read from a stream",
+                            entry.name, target
+                        )))
+                    }
+                    EntryKind::File => {
+                        deferred.files.insert(entry.name.clone());
+                    }
+                    _ => {}
+                }
+                this.extract_entry(pool.as_mut(), entry.clone(), &mut deferred)
+            })?;
+            match pool {
+                Some(pool) => self.finish_extraction(pool),
+                None => Ok(()),
+            }
+        })?;
+
+        if !filter.is_empty() {
+            if selected == 0 {
+                return Err(ArchivalError(
+                    format!("no entries matched {}", self.filters.join(", "))
+                ))
+            }
+            eprintln!("{} of {} entries selected", selected, files);
+        }
+
+        // links are only checked once they have all been read
+        let links = deferred.symlinks.iter()
+            .map(|(_, entry)| PathBuf::from(&entry.name))
+            .collect::<HashSet<PathBuf>>();
+        for (_, entry) in &deferred.symlinks {
+            if let EntryKind::Symlink(target) = &entry.kind {
+                paths::check_link_target(&entry.name, target, &links)?;
+            }
+        }
+        self.create_deferred(deferred, &[])
+    }
+
+    /// the current position of the archive reader
+    fn archive_position(&mut self) -> Result<u64, ArchivalError> {
+        self.archive_reader.as_mut().unwrap()
+            .stream_position()
+            .map_err(|e| ArchivalError(format!("failed to seek within archive: {}", e)))
+    }
+}
@@ -1,3 +1,30 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::io::Read;
+use std::string::FromUtf8Error;
+use crate::archival::compression::chunker::ChunkHash;
+use crate::archival::files::archiver::ArchivalError;
+use crate::archival::files::metadata::EntryMetadata;
+
+/// signature found at the very start of every archive
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"ARKA";
+/// signature found at the start of every file header
+pub const FILE_MAGIC: [u8; 4] = *b"ARKF";
+/// signature found at the start of every directory header
+pub const DIR_MAGIC: [u8; 4] = *b"ARKD";
+/// signature found at the start of every symbolic link header
+pub const SYMLINK_MAGIC: [u8; 4] = *b"ARKL";
+/// signature found at the start of every hard link header
+pub const HARD_LINK_MAGIC: [u8; 4] = *b"ARKH";
+/// signature found at the start of every deduplicated chunk
+pub const CHUNK_MAGIC: [u8; 4] = *b"ARKC";
+/// signature found at the start of every solid block
+pub const BLOCK_MAGIC: [u8; 4] = *b"ARKS";
+/// the version of the binary layout written by this build of Ark,
+/// archives with any other layout version are rejected when read
+pub const FORMAT_VERSION: u16 = 16;
+
 pub struct ArchiveHeader(
     pub usize,
     pub usize,
@@ -8,10 +35,81 @@ pub struct FileHeader(
     pub String,
     pub u8,
     pub u64,
-    pub u64
+    pub u64,
+    pub u32,
+    pub EntryMetadata
 );
 
 /// Headers which can be found in the archives generated by Ark
+///
+/// all integers are stored as fixed width little endian values,
+/// and all names are stored as a `u32` byte length followed by utf-8 bytes
+///
+/// # Layout
+/// ```ignore
+/// // archive header
+/// magic           [u8; 4]  "ARKA"
+/// format version  u16
+/// total files     u64
+/// version         u64
+/// flags           u8       bit 0 -> encrypted, bit 1 -> headers encrypted
+/// // followed by the table of key slots when encrypted, see `KeySlot`
+///
+/// // when headers are encrypted, every header below and the central directory
+/// // are instead stored sealed, see `Archiver::encode_header`
+/// sealed length   u32
+/// sealed header   [u8; sealed length]
+///
+/// // file header
+/// magic           [u8; 4]  "ARKF"
+/// name length     u32
+/// name            [u8; name length]
+/// method          u8
+/// compressed      u64      zero when the method is `DESCRIBED`, given after the data instead
+/// decompressed    u64
+/// checksum        u32      crc32 of the decompressed data, likewise zero when `DESCRIBED`
+/// metadata        [u8; 36] see `EntryMetadata`
+///
+/// // directory header
+/// magic           [u8; 4]  "ARKD"
+/// name length     u32
+/// name            [u8; name length]
+/// metadata        [u8; 36]
+///
+/// // symbolic link header
+/// magic           [u8; 4]  "ARKL"
+/// name length     u32
+/// name            [u8; name length]
+/// target length   u32
+/// target          [u8; target length]
+/// metadata        [u8; 36]
+///
+/// // hard link header, laid out exactly like a symbolic link
+/// magic           [u8; 4]  "ARKH"
+/// ...
+///
+/// // chunk header, followed by the chunk's compressed data
+/// magic           [u8; 4]  "ARKC"
+/// hash            [u8; 32] sha-256 of the decompressed chunk, or its hmac-sha-256 when encrypted
+/// method          u8
+/// compressed      u32
+/// decompressed    u32
+///
+/// // solid block header, followed by the block's compressed data
+/// magic           [u8; 4]  "ARKS"
+/// id              u64
+/// method          u8
+/// compressed      u64
+/// decompressed    u64
+/// checksum        u32      crc32 of the decompressed block
+/// ```
+///
+/// directories and links are never followed by any data.
+/// files written with deduplication have the `CHUNKED` method bit set, and their data
+/// is a list of chunk hashes (`u32` count followed by each hash) instead of file contents.
+/// files written in solid mode have the `SOLID` method bit set, and their data is
+/// the id of the block holding them (`u64`) and their offset within it once decompressed (`u64`)
+#[derive(Clone)]
 pub enum Header {
     /// the archive header which contains information
     /// about the archive and the files it is made up of
@@ -22,6 +120,8 @@ pub enum Header {
         version: usize,
         /// if the archive is encrypted or not
         encrypted: bool,
+        /// if the names and sizes in every header are encrypted as well as the data
+        encrypted_headers: bool,
     },
     /// the file header which contains information about a specific file
     File {
@@ -30,29 +130,302 @@ pub enum Header {
         method: u8,
         compressed_size: u64,
         decompressed_size: u64,
+        /// crc32 of the decompressed file data
+        checksum: u32,
+        /// permissions, ownership and times of the original file
+        metadata: EntryMetadata,
+    },
+    /// a directory, stored so that empty directories and
+    /// directory permissions survive a round trip
+    Directory {
+        name: String,
+        metadata: EntryMetadata,
+    },
+    /// a symbolic link, stored as the path it points to rather than the contents of its target
+    Symlink {
+        name: String,
+        target: String,
+        metadata: EntryMetadata,
+    },
+    /// another name for a file already in the archive, whose data is only stored once
+    HardLink {
+        name: String,
+        /// the name of the file entry holding the data
+        target: String,
+        metadata: EntryMetadata,
+    },
+    /// a piece of file data shared by any number of deduplicated files.
+    /// chunks are not entries, they are only found through the files which use them
+    Chunk {
+        hash: ChunkHash,
+        method: u8,
+        compressed_size: u32,
+        decompressed_size: u32,
+    },
+    /// many small files concatenated and compressed together.
+    /// like chunks, blocks are only found through the files inside them
+    Block {
+        id: u64,
+        method: u8,
+        compressed_size: u64,
+        decompressed_size: u64,
+        /// crc32 of the decompressed block
+        checksum: u32,
+    },
+}
+
+/// errors which can occur while parsing a header
+#[derive(Debug)]
+pub enum HeaderError {
+    /// the underlying reader failed, or ended in the middle of a header
+    Io(io::Error),
+    /// the header did not start with the expected signature
+    BadMagic { expected: [u8; 4], found: [u8; 4] },
+    /// the archive was written with a layout this build can't read
+    UnsupportedFormat(u16),
+    /// a stored name was not valid utf-8
+    InvalidName(FromUtf8Error),
+    /// the central directory recorded an entry kind this build doesn't know about
+    UnknownEntryKind(u8),
+    /// an encrypted archive held a kind of key slot this build doesn't know about
+    UnknownKeySlot(u8),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Io(e) => write!(f, "failed to read header: {}", e),
+            HeaderError::BadMagic { expected, found } => write!(
+                f, "invalid header signature, expected {:?} but found {:?}",
+                String::from_utf8_lossy(expected), String::from_utf8_lossy(found)
+            ),
+            HeaderError::UnsupportedFormat(v) => write!(
+                f, "unsupported archive format version {} (expected {})",
+                v, FORMAT_VERSION
+            ),
+            HeaderError::InvalidName(e) => write!(f, "invalid file name in header: {}", e),
+            HeaderError::UnknownEntryKind(k) => write!(f, "unknown entry kind {}", k),
+            HeaderError::UnknownKeySlot(k) => write!(f, "unknown key slot kind {}", k),
+        }
+    }
+}
+
+impl From<io::Error> for HeaderError {
+    fn from(e: io::Error) -> Self {
+        HeaderError::Io(e)
+    }
+}
+
+impl From<HeaderError> for ArchivalError {
+    fn from(e: HeaderError) -> Self {
+        ArchivalError(e.to_string())
     }
 }
 
 impl Header {
-    pub const ARCHIVE_HEADER_SIZE: usize = 3;
-    pub const FILE_HEADER_SIZE: usize = 4;
+    /// size in bytes of an encoded archive header
+    pub const ARCHIVE_HEADER_SIZE: usize = 4 + 2 + 8 + 8 + 1;
+    /// size in bytes of an encoded file header, excluding its name
+    pub const FILE_HEADER_SIZE: usize = 4 + 4 + 1 + 8 + 8 + 4 + EntryMetadata::SIZE;
+    /// size in bytes of an encoded directory header, excluding its name
+    pub const DIR_HEADER_SIZE: usize = 4 + 4 + EntryMetadata::SIZE;
+    /// size in bytes of an encoded symbolic or hard link header, excluding its name and target
+    pub const LINK_HEADER_SIZE: usize = 4 + 4 + 4 + EntryMetadata::SIZE;
+    /// size in bytes of an encoded chunk header
+    pub const CHUNK_HEADER_SIZE: usize = 4 + 32 + 1 + 4 + 4;
+    /// size in bytes of an encoded solid block header
+    pub const BLOCK_HEADER_SIZE: usize = 4 + 8 + 1 + 8 + 8 + 4;
+
+    const ENCRYPTED_FLAG: u8 = 1 << 0;
+    const ENCRYPTED_HEADERS_FLAG: u8 = 1 << 1;
 
-    // pub const HEADER_LEN: u32 =
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
-            Header::Archive { total_files, version, encrypted } => {
-                format!(
-                    "files:{}\nver:{}\nencrypted:{}",
-                    total_files, version, encrypted
-                )
+            Header::Archive { total_files, version, encrypted, encrypted_headers } => {
+                let mut bytes = Vec::with_capacity(Self::ARCHIVE_HEADER_SIZE);
+                bytes.extend_from_slice(&ARCHIVE_MAGIC);
+                bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+                bytes.extend_from_slice(&(*total_files as u64).to_le_bytes());
+                bytes.extend_from_slice(&(*version as u64).to_le_bytes());
+                let mut flags = 0;
+                if *encrypted { flags |= Self::ENCRYPTED_FLAG }
+                if *encrypted_headers { flags |= Self::ENCRYPTED_HEADERS_FLAG }
+                bytes.push(flags);
+                bytes
             },
             Header::File
-            { name, method, compressed_size, decompressed_size } => {
-                format!(
-                    "\nname:{}\nmethod:{}\ncompressed:{}\ndecompressed:{}\n",
-                    name, method, compressed_size, decompressed_size
-                )
+            { name, method, compressed_size, decompressed_size, checksum, metadata } => {
+                let mut bytes = Vec::with_capacity(Self::FILE_HEADER_SIZE + name.len());
+                bytes.extend_from_slice(&FILE_MAGIC);
+                bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(*method);
+                bytes.extend_from_slice(&compressed_size.to_le_bytes());
+                bytes.extend_from_slice(&decompressed_size.to_le_bytes());
+                bytes.extend_from_slice(&checksum.to_le_bytes());
+                bytes.extend_from_slice(&metadata.to_bytes());
+                bytes
+            },
+            Header::Directory { name, metadata } => {
+                let mut bytes = Vec::with_capacity(Self::DIR_HEADER_SIZE + name.len());
+                bytes.extend_from_slice(&DIR_MAGIC);
+                bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.extend_from_slice(&metadata.to_bytes());
+                bytes
+            },
+            Header::Symlink { name, target, metadata }
+            | Header::HardLink { name, target, metadata } => {
+                let mut bytes = Vec::with_capacity(
+                    Self::LINK_HEADER_SIZE + name.len() + target.len()
+                );
+                bytes.extend_from_slice(match self {
+                    Header::Symlink { .. } => &SYMLINK_MAGIC,
+                    _ => &HARD_LINK_MAGIC,
+                });
+                bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(target.as_bytes());
+                bytes.extend_from_slice(&metadata.to_bytes());
+                bytes
+            },
+            Header::Chunk { hash, method, compressed_size, decompressed_size } => {
+                let mut bytes = Vec::with_capacity(Self::CHUNK_HEADER_SIZE);
+                bytes.extend_from_slice(&CHUNK_MAGIC);
+                bytes.extend_from_slice(hash);
+                bytes.push(*method);
+                bytes.extend_from_slice(&compressed_size.to_le_bytes());
+                bytes.extend_from_slice(&decompressed_size.to_le_bytes());
+                bytes
+            },
+            Header::Block { id, method, compressed_size, decompressed_size, checksum } => {
+                let mut bytes = Vec::with_capacity(Self::BLOCK_HEADER_SIZE);
+                bytes.extend_from_slice(&BLOCK_MAGIC);
+                bytes.extend_from_slice(&id.to_le_bytes());
+                bytes.push(*method);
+                bytes.extend_from_slice(&compressed_size.to_le_bytes());
+                bytes.extend_from_slice(&decompressed_size.to_le_bytes());
+                bytes.extend_from_slice(&checksum.to_le_bytes());
+                bytes
+            },
+        }
+    }
+
+    /// the number of bytes this header occupies once encoded
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Header::Archive { .. } => Self::ARCHIVE_HEADER_SIZE,
+            Header::File { name, .. } => Self::FILE_HEADER_SIZE + name.len(),
+            Header::Directory { name, .. } => Self::DIR_HEADER_SIZE + name.len(),
+            Header::Symlink { name, target, .. } | Header::HardLink { name, target, .. } => {
+                Self::LINK_HEADER_SIZE + name.len() + target.len()
             }
-        }.into_bytes()
+            Header::Chunk { .. } => Self::CHUNK_HEADER_SIZE,
+            Header::Block { .. } => Self::BLOCK_HEADER_SIZE,
+        }
+    }
+
+    /// read and validate an archive header from the start of `reader`
+    pub fn read_archive(reader: &mut impl Read) -> Result<Header, HeaderError> {
+        expect_magic(reader, ARCHIVE_MAGIC)?;
+
+        let format = u16::from_le_bytes(read_array(reader)?);
+        if format != FORMAT_VERSION {
+            return Err(HeaderError::UnsupportedFormat(format))
+        }
+
+        let total_files = u64::from_le_bytes(read_array(reader)?) as usize;
+        let version = u64::from_le_bytes(read_array(reader)?) as usize;
+        let [flags] = read_array(reader)?;
+
+        Ok(Header::Archive {
+            total_files,
+            version,
+            encrypted: flags & Self::ENCRYPTED_FLAG != 0,
+            encrypted_headers: flags & Self::ENCRYPTED_HEADERS_FLAG != 0,
+        })
+    }
+
+    /// read and validate a file header from the current position of `reader`
+    pub fn read_file(reader: &mut impl Read) -> Result<Header, HeaderError> {
+        expect_magic(reader, FILE_MAGIC)?;
+        Self::read_file_body(reader)
     }
-}
\ No newline at end of file
+
+    /// read and validate the header of any kind of entry, a chunk or a solid block,
+    /// from the current position of `reader`
+    pub fn read_entry(reader: &mut impl Read) -> Result<Header, HeaderError> {
+        match read_array(reader)? {
+            FILE_MAGIC => Self::read_file_body(reader),
+            DIR_MAGIC => Ok(Header::Directory {
+                name: read_name(reader)?,
+                metadata: EntryMetadata::read(reader)?,
+            }),
+            SYMLINK_MAGIC => Ok(Header::Symlink {
+                name: read_name(reader)?,
+                target: read_name(reader)?,
+                metadata: EntryMetadata::read(reader)?,
+            }),
+            HARD_LINK_MAGIC => Ok(Header::HardLink {
+                name: read_name(reader)?,
+                target: read_name(reader)?,
+                metadata: EntryMetadata::read(reader)?,
+            }),
+            CHUNK_MAGIC => Ok(Header::Chunk {
+                hash: read_array(reader)?,
+                method: u8::from_le_bytes(read_array(reader)?),
+                compressed_size: u32::from_le_bytes(read_array(reader)?),
+                decompressed_size: u32::from_le_bytes(read_array(reader)?),
+            }),
+            BLOCK_MAGIC => Ok(Header::Block {
+                id: u64::from_le_bytes(read_array(reader)?),
+                method: u8::from_le_bytes(read_array(reader)?),
+                compressed_size: u64::from_le_bytes(read_array(reader)?),
+                decompressed_size: u64::from_le_bytes(read_array(reader)?),
+                checksum: u32::from_le_bytes(read_array(reader)?),
+            }),
+            found => Err(HeaderError::BadMagic { expected: FILE_MAGIC, found }),
+        }
+    }
+
+    /// read the remainder of a file header, after its magic
+    fn read_file_body(reader: &mut impl Read) -> Result<Header, HeaderError> {
+        let name = read_name(reader)?;
+        let [method] = read_array(reader)?;
+        let compressed_size = u64::from_le_bytes(read_array(reader)?);
+        let decompressed_size = u64::from_le_bytes(read_array(reader)?);
+        let checksum = u32::from_le_bytes(read_array(reader)?);
+        let metadata = EntryMetadata::read(reader)?;
+
+        Ok(Header::File { name, method, compressed_size, decompressed_size, checksum, metadata })
+    }
+}
+
+/// read exactly `N` bytes from `reader`
+pub(crate) fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], HeaderError> {
+    let mut buffer = [0u8; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// read a `u32` length prefixed utf-8 string from `reader`
+pub(crate) fn read_name(reader: &mut impl Read) -> Result<String, HeaderError> {
+    let len = u32::from_le_bytes(read_array(reader)?) as usize;
+    // a damaged length shouldn't allocate more than the archive holds
+    let mut name = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut name)?;
+    if name.len() != len {
+        return Err(HeaderError::from(io::Error::from(io::ErrorKind::UnexpectedEof)))
+    }
+    String::from_utf8(name).map_err(HeaderError::InvalidName)
+}
+
+/// read four bytes from `reader` and ensure they match `expected`
+pub(crate) fn expect_magic(reader: &mut impl Read, expected: [u8; 4]) -> Result<(), HeaderError> {
+    let found = read_array(reader)?;
+    if found != expected {
+        return Err(HeaderError::BadMagic { expected, found })
+    }
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::Instant;
 use walkdir::WalkDir;
@@ -15,6 +16,9 @@ pub struct ArchiveIndexer {
     pub file_count: usize,
     pub bytes_count: usize,
     pub ranges: Vec<FileRange>,
+    /// every indexed path which is a hard link to a file indexed before it,
+    /// mapped to that first path
+    pub hard_links: HashMap<PathBuf, PathBuf>,
 }
 
 impl ArchiveIndexer {
@@ -43,21 +47,33 @@ impl ArchiveIndexer {
             file_count: 0,
             bytes_count: 0,
             ranges: vec![],
+            hard_links: HashMap::new(),
         }
     }
 
-    /// recursively read all file paths contained within the root directory.
-    /// Pushes all file paths to `self.contents`
-    /// and tracks the total number of files with `self.file_count`
+    /// recursively read all file, directory and symbolic link paths contained within the
+    /// root directory. links are recorded as they are, never followed.
+    /// Pushes all paths to `self.contents`
+    /// and tracks the total number of entries with `self.file_count`
     pub fn index_files(&mut self) {
         self.read_start_time = Some(Instant::now());
 
         let contents = WalkDir::new(&self.root)
             .into_iter()
             .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.file_type().is_file())
+            .filter(|entry| {
+                let file_type = entry.file_type();
+                match file_type.is_file() || file_type.is_symlink() || file_type.is_dir() {
+                    // the root itself is not an entry, its name is never stored
+                    true => entry.depth() > 0 || !file_type.is_dir(),
+                    false => {
+                        eprintln!("skipped :: {} (unsupported file type)", entry.path().display());
+                        false
+                    }
+                }
+            })
             .map(|entry| {
-                println!("indexed :: {}", entry.path().to_str().unwrap());
+                eprintln!("indexed :: {}", entry.path().to_str().unwrap());
                 entry.path().to_path_buf()
             })
             .collect::<Vec<PathBuf>>();
@@ -111,13 +127,41 @@ impl ArchiveIndexer {
             .map(|item| item.unwrap().metadata().unwrap().len() as usize)
             .sum();
 
+        self.find_hard_links(&contents);
         self.file_count = contents.len();
         self.contents = contents;
 
-        println!(
+        eprintln!(
             "Successfully indexed {} files in {:.2}s",
             self.file_count,
             self.read_start_time.unwrap().elapsed().as_secs_f64()
         );
     }
+
+    /// group files which share a device and inode, so each one
+    /// after the first can be stored as a link instead of a copy
+    #[cfg(unix)]
+    fn find_hard_links(&mut self, contents: &[PathBuf]) {
+        use std::collections::hash_map::Entry;
+        use std::os::unix::fs::MetadataExt;
+
+        let mut first_paths = HashMap::new();
+        for path in contents {
+            let metadata = match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_file() && metadata.nlink() > 1 => metadata,
+                _ => continue
+            };
+            match first_paths.entry((metadata.dev(), metadata.ino())) {
+                Entry::Vacant(first) => {
+                    first.insert(path.clone());
+                }
+                Entry::Occupied(first) => {
+                    self.hard_links.insert(path.clone(), first.get().clone());
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn find_hard_links(&mut self, _contents: &[PathBuf]) {}
 }
\ No newline at end of file
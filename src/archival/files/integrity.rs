@@ -0,0 +1,133 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use crc32fast::Hasher;
+use crate::archival::files::archiver::ArchivalError;
+
+/// problems found with the contents of a single archive entry
+#[derive(Debug, PartialEq)]
+pub enum EntryError {
+    /// the entry decompressed to a different number of bytes than its header records
+    SizeMismatch { name: String, expected: u64, actual: u64 },
+    /// the decompressed data does not match the checksum stored in its header
+    ChecksumMismatch { name: String, expected: u32, actual: u32 },
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryError::SizeMismatch { name, expected, actual } => write!(
+                f, "\"{}\" is corrupt: expected {} bytes but found {}",
+                name, expected, actual
+            ),
+            EntryError::ChecksumMismatch { name, expected, actual } => write!(
+                f, "\"{}\" is corrupt: expected checksum {:08x} but found {:08x}",
+                name, expected, actual
+            ),
+        }
+    }
+}
+
+impl From<EntryError> for ArchivalError {
+    fn from(e: EntryError) -> Self {
+        ArchivalError(e.to_string())
+    }
+}
+
+/// calculate the crc32 of `data`
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// calculate the crc32 of a file without loading it all into memory
+pub fn checksum_file(path: &Path) -> io::Result<u32> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Hasher::new();
+    let mut buffer = [0u8; 64 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// passes everything written through to another writer,
+/// keeping the size and crc32 of it for when the entry is complete
+pub struct ChecksumWriter<W: Write> {
+    pub inner: W,
+    hasher: Hasher,
+    written: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, hasher: Hasher::new(), written: 0 }
+    }
+
+    /// the number of bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// the crc32 of everything written so far
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+
+    /// ensure everything written matches the size and checksum recorded for the entry
+    pub fn verify(&self, name: &str, expected_size: u64, expected_checksum: u32) -> Result<(), EntryError> {
+        verify_sums(name, self.written, self.checksum(), expected_size, expected_checksum)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// ensure decompressed entry data matches the size and checksum recorded for it
+pub fn verify_entry(
+    name: &str, data: &[u8], expected_size: u64, expected_checksum: u32
+) -> Result<(), EntryError>
+{
+    verify_sums(name, data.len() as u64, checksum(data), expected_size, expected_checksum)
+}
+
+fn verify_sums(
+    name: &str, size: u64, actual: u32, expected_size: u64, expected_checksum: u32
+) -> Result<(), EntryError>
+{
+    if size != expected_size {
+        return Err(EntryError::SizeMismatch {
+            name: name.to_owned(),
+            expected: expected_size,
+            actual: size,
+        })
+    }
+
+    if actual != expected_checksum {
+        return Err(EntryError::ChecksumMismatch {
+            name: name.to_owned(),
+            expected: expected_checksum,
+            actual,
+        })
+    }
+
+    Ok(())
+}
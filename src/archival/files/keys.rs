@@ -0,0 +1,336 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::crypto::{
+    prompt_new_password, KeySlot, DEFAULT_ITERATIONS, DEFAULT_MEMORY_KB, MAX_ITERATIONS, MAX_MEMORY_KB
+};
+use crate::archival::files::header::{ArchiveHeader, Header};
+use crate::constants::{GIGABYTE, KILOBYTE, MEGABYTE, TERABYTE};
+use crate::format_bytes;
+
+/// first word of a file holding a secret key
+const SECRET_KEY_LABEL: &str = "ark-secret-key";
+/// first word of a file holding a public key
+const PUBLIC_KEY_LABEL: &str = "ark-public-key";
+/// first word of a file holding a key archives are signed with
+const SIGNING_KEY_LABEL: &str = "ark-signing-key";
+/// first word of a file holding the public half of a signing key
+const VERIFYING_KEY_LABEL: &str = "ark-verifying-key";
+
+/// the path the public half of the key pair at `path` is written to, e.g. `alice.key.pub`
+pub fn public_key_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".pub");
+    PathBuf::from(name)
+}
+
+/// read the public key of a recipient, written by `Ark keygen`
+pub fn read_public_key(path: &Path) -> Result<PublicKey, ArchivalError> {
+    read_key(path, PUBLIC_KEY_LABEL).map(PublicKey::from)
+}
+
+/// read a secret key, written by `Ark keygen`
+pub fn read_secret_key(path: &Path) -> Result<StaticSecret, ArchivalError> {
+    read_key(path, SECRET_KEY_LABEL).map(StaticSecret::from)
+}
+
+/// read a key to sign archives with, written by `Ark keygen --signing`
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, ArchivalError> {
+    read_key(path, SIGNING_KEY_LABEL).map(|key| SigningKey::from_bytes(&key))
+}
+
+/// read the public key of someone trusted to sign archives, written by `Ark keygen --signing`
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey, ArchivalError> {
+    let key = read_key(path, VERIFYING_KEY_LABEL)?;
+    match VerifyingKey::from_bytes(&key) {
+        Ok(key) => Ok(key),
+        Err(_) => Err(ArchivalError(
+            format!("\"{}\" does not hold a valid {}", path.display(), VERIFYING_KEY_LABEL)
+        ))
+    }
+}
+
+/// key files hold a single line, the kind of key followed by its 32 bytes in hex
+fn read_key(path: &Path, label: &str) -> Result<[u8; 32], ArchivalError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => return Err(ArchivalError(
+            format!("Could not read key file \"{}\"\nreason: {}", path.display(), e)
+        ))
+    };
+
+    let key = contents.trim()
+        .strip_prefix(label)
+        .map(str::trim)
+        .and_then(decode_hex);
+    match key {
+        Some(key) => Ok(key),
+        None => Err(ArchivalError(
+            format!("\"{}\" is not an {} file", path.display(), label)
+        ))
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+/// create a file which must not already exist, readable only by its owner when `secret`
+fn create_key_file(path: &Path, secret: bool) -> std::io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if secret { 0o600 } else { 0o644 });
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    options.open(path)
+}
+
+impl Archiver {
+    /// generate a new key pair, writing the secret key to the input path
+    /// and the public key given to others next to it.
+    ///
+    /// the pair encrypts archives, or signs them when `--signing` was given
+    pub fn keygen(&mut self) -> Result<u64, ArchivalError> {
+        let public_path = public_key_path(&self.input);
+        let (secret, public) = match self.signing {
+            true => {
+                let secret = SigningKey::generate(&mut OsRng);
+                (
+                    (SIGNING_KEY_LABEL, secret.to_bytes()),
+                    (VERIFYING_KEY_LABEL, secret.verifying_key().to_bytes()),
+                )
+            }
+            false => {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                (
+                    (SECRET_KEY_LABEL, secret.to_bytes()),
+                    (PUBLIC_KEY_LABEL, PublicKey::from(&secret).to_bytes()),
+                )
+            }
+        };
+
+        let files = [
+            (&self.input, secret.0, secret.1, true),
+            (&public_path, public.0, public.1, false),
+        ];
+        for (path, label, key, secret) in files {
+            let res = create_key_file(path, secret)
+                .and_then(|mut file| writeln!(file, "{} {}", label, encode_hex(&key)));
+            if let Err(e) = res {
+                return Err(ArchivalError(
+                    format!("Could not write key file \"{}\"\nreason: {}", path.display(), e)
+                ))
+            }
+        }
+
+        eprintln!(
+            "Generated a key pair, share \"{}\" and keep \"{}\" to yourself",
+            public_path.display(), self.input.display()
+        );
+        Ok(0)
+    }
+
+    /// add the recipients given with `-r` to an encrypted archive and remove those given
+    /// with `--revoke`, after unlocking it with its password or a key that can already open it.
+    ///
+    /// only the table of key slots is rewritten, in place, as the content key stays the same
+    pub fn share(&mut self) -> Result<u64, ArchivalError> {
+        if self.recipients.is_empty() && self.revoked.is_empty() {
+            return Err(ArchivalError(String::from(
+                "give recipients to add with -r or remove with --revoke"
+            )))
+        }
+
+        let ArchiveHeader(_, _, encrypted) = self.read_archive_header()?;
+        let cipher = match (encrypted, self.cipher.as_mut()) {
+            (false, _) => return Err(ArchivalError(String::from(
+                "the archive isn't encrypted, so it can't be shared with recipients"
+            ))),
+            (true, None) => return Err(ArchivalError(String::from(
+                "the archive is encrypted, give its password with -k or a key with -i to share it"
+            ))),
+            (true, Some(cipher)) => cipher,
+        };
+
+        for path in &self.revoked {
+            if !cipher.revoke_recipient(&read_public_key(path)?) {
+                return Err(ArchivalError(
+                    format!("\"{}\" is not a recipient of the archive", path.display())
+                ))
+            }
+            eprintln!("Revoked {}", path.display());
+        }
+        for path in &self.recipients {
+            let recipient = read_public_key(path)?;
+            if cipher.has_recipient(&recipient) {
+                eprintln!("{} can already open the archive", path.display());
+                continue
+            }
+            cipher.add_recipient(&recipient)?;
+            eprintln!("Added {}", path.display());
+        }
+        if cipher.slots.is_empty() {
+            return Err(ArchivalError(String::from(
+                "that would leave no password or key able to open the archive"
+            )))
+        }
+
+        self.rewrite_key_slots()
+    }
+
+    /// change the password of an encrypted archive to the one given with `--new-key`,
+    /// or derive its key with the costs given with `--kdf-memory` and `--kdf-passes`.
+    ///
+    /// only the content key is re-wrapped, the entries stay sealed with it as they are,
+    /// so this takes as long as deriving the key rather than rewriting the archive
+    pub fn rekey(&mut self) -> Result<u64, ArchivalError> {
+        let new_costs = self.kdf_memory.is_some() || self.kdf_passes.is_some();
+        if self.new_password.is_none() && !new_costs && !self.prompt {
+            return Err(ArchivalError(String::from(
+                "give a new password with --new-key, or new costs with --kdf-memory or --kdf-passes"
+            )))
+        }
+
+        let ArchiveHeader(_, _, encrypted) = self.read_archive_header()?;
+        if !encrypted {
+            return Err(ArchivalError(String::from(
+                "the archive isn't encrypted, so it has no password to change"
+            )))
+        }
+        if self.cipher.is_none() {
+            return Err(ArchivalError(String::from(
+                "the archive is encrypted, give its password with -k or a key with -i to re-key it"
+            )))
+        }
+        if self.new_password.is_none() && !new_costs {
+            self.new_password = Some(prompt_new_password(&self.input)?);
+        }
+
+        let password = match self.new_password.as_ref().or(self.password.as_ref()) {
+            Some(password) => password,
+            None => return Err(ArchivalError(String::from(
+                "the current password is needed to change its costs, give it with -k"
+            )))
+        };
+        let memory = match self.kdf_memory {
+            Some(bytes) => match u32::try_from(bytes / KILOBYTE) {
+                Ok(kilobytes) if kilobytes <= MAX_MEMORY_KB => Some(kilobytes),
+                _ => return Err(ArchivalError(format!(
+                    "{} is too much memory to derive a key with, the most is {}",
+                    format_bytes!(bytes), format_bytes!(MAX_MEMORY_KB as u64 * KILOBYTE)
+                )))
+            },
+            None => None,
+        };
+        if self.kdf_passes.is_some_and(|passes| passes > MAX_ITERATIONS) {
+            return Err(ArchivalError(
+                format!("a key can be derived over at most {} passes", MAX_ITERATIONS)
+            ))
+        }
+
+        // costs which aren't given are kept from the current password
+        let cipher = self.cipher.as_mut().unwrap();
+        let (current_memory, current_passes) = cipher.password_costs()
+            .unwrap_or((DEFAULT_MEMORY_KB, DEFAULT_ITERATIONS));
+        cipher.replace_password(
+            password,
+            memory.unwrap_or(current_memory),
+            self.kdf_passes.unwrap_or(current_passes),
+        )?;
+
+        self.rewrite_key_slots()?;
+        eprintln!("Re-keyed \"{}\"", self.input.display());
+        Ok(0)
+    }
+
+    /// write the key slots of the unlocked archive over those it was read with, in place.
+    ///
+    /// the table is the only copy of the slots, so it is never left without a way in:
+    /// new slots are first written into empty ones, and only once they are on disk are
+    /// the slots no longer wanted cleared. an interrupted re-key leaves both the old and
+    /// the new slots behind, either of which opens the archive. the table is always the
+    /// same size, so nothing after it moves, though the recovery record covering it has to follow
+    fn rewrite_key_slots(&mut self) -> Result<u64, ArchivalError> {
+        let table = self.add_key_slots()?;
+        self.clear_key_slots(table)?;
+        Ok(0)
+    }
+
+    /// write every slot of the unlocked archive which isn't in its table yet into an
+    /// empty slot, leaving the others as they are, and return the table as written
+    pub(crate) fn add_key_slots(&mut self) -> Result<Vec<Vec<u8>>, ArchivalError> {
+        self.seek_archive(Header::ARCHIVE_HEADER_SIZE as u64)?;
+        let mut table = vec![0; KeySlot::TABLE_SIZE];
+        if let Err(e) = self.archive_reader.as_mut().unwrap().read_exact(&mut table) {
+            return Err(ArchivalError(
+                format!("Could not read the key slots of \"{}\"\nreason: {}", self.input.display(), e)
+            ))
+        }
+        let mut table = table.chunks(KeySlot::SIZE)
+            .map(|slot| slot.to_vec())
+            .collect::<Vec<Vec<u8>>>();
+
+        let slots = self.cipher.as_ref().unwrap().slots.iter()
+            .map(|slot| slot.to_bytes())
+            .collect::<Vec<Vec<u8>>>();
+        for slot in slots {
+            if table.contains(&slot) {
+                continue
+            }
+            match table.iter_mut().find(|existing| KeySlot::is_empty(existing)) {
+                Some(empty) => *empty = slot,
+                None => return Err(ArchivalError(String::from(
+                    "there are no empty key slots to write the new ones into before the old \
+                    ones are cleared, revoke a recipient first"
+                )))
+            }
+        }
+        self.write_key_slots(&table)?;
+        Ok(table)
+    }
+
+    /// clear every slot of `table` the unlocked archive no longer has
+    fn clear_key_slots(&mut self, mut table: Vec<Vec<u8>>) -> Result<(), ArchivalError> {
+        let slots = self.cipher.as_ref().unwrap().slots.iter()
+            .map(|slot| slot.to_bytes())
+            .collect::<Vec<Vec<u8>>>();
+        for existing in table.iter_mut() {
+            if !KeySlot::is_empty(existing) && !slots.contains(existing) {
+                *existing = KeySlot::empty_bytes();
+            }
+        }
+        self.write_key_slots(&table)
+    }
+
+    /// write a whole table of slots to disk, along with the recovery record covering it
+    fn write_key_slots(&mut self, table: &[Vec<u8>]) -> Result<(), ArchivalError> {
+        let table = table.concat();
+        let reader = self.archive_reader.as_mut().unwrap().get_mut();
+        if let Err(e) = reader.overwrite(Header::ARCHIVE_HEADER_SIZE as u64, &table) {
+            return Err(ArchivalError(
+                format!("Could not update the key slots of \"{}\"\nreason: {}", self.input.display(), e)
+            ))
+        }
+        self.refresh_recovery_record(Header::ARCHIVE_HEADER_SIZE as u64, table.len() as u64)
+    }
+}
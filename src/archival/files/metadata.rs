@@ -0,0 +1,190 @@
+use std::fs;
+use std::fs::{File, FileTimes};
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::archival::files::header::{read_array, HeaderError};
+
+/// whether extracted entries can be given back to the owners they were archived with,
+/// which like tar is only tried when running as root
+#[cfg(unix)]
+pub fn can_restore_owner() -> bool {
+    // SAFETY: geteuid has no preconditions and can't fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// whether extracted entries can be given back to the owners they were archived with
+#[cfg(not(unix))]
+pub fn can_restore_owner() -> bool {
+    false
+}
+
+/// file system details stored alongside each entry so they can be restored on extraction
+///
+/// # Layout
+/// ```ignore
+/// mode            u32      permission and file type bits
+/// uid             u32
+/// gid             u32
+/// mtime           i64      seconds since the unix epoch
+/// mtime nanos     u32
+/// atime           i64      seconds since the unix epoch
+/// atime nanos     u32
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EntryMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nanos: u32,
+    pub atime: i64,
+    pub atime_nanos: u32,
+}
+
+impl EntryMetadata {
+    /// size in bytes of encoded metadata
+    pub const SIZE: usize = 4 + 4 + 4 + 8 + 4 + 8 + 4;
+
+    /// capture the details of a file from its metadata
+    #[cfg(unix)]
+    pub fn from_metadata(metadata: &fs::Metadata) -> EntryMetadata {
+        use std::os::unix::fs::MetadataExt;
+
+        EntryMetadata {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime(),
+            mtime_nanos: metadata.mtime_nsec() as u32,
+            atime: metadata.atime(),
+            atime_nanos: metadata.atime_nsec() as u32,
+        }
+    }
+
+    /// capture the details of a file from its metadata
+    #[cfg(not(unix))]
+    pub fn from_metadata(metadata: &fs::Metadata) -> EntryMetadata {
+        let since_epoch = |time: io::Result<SystemTime>| {
+            time.ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default()
+        };
+        let modified = since_epoch(metadata.modified());
+        let accessed = since_epoch(metadata.accessed());
+
+        EntryMetadata {
+            mode: if metadata.permissions().readonly() { 0o444 } else { 0o644 },
+            uid: 0,
+            gid: 0,
+            mtime: modified.as_secs() as i64,
+            mtime_nanos: modified.subsec_nanos(),
+            atime: accessed.as_secs() as i64,
+            atime_nanos: accessed.subsec_nanos(),
+        }
+    }
+
+    /// check whether two sets of metadata describe the same file contents and attributes.
+    /// access times are ignored, since reading a file is enough to change them
+    pub fn same_file_as(&self, other: &EntryMetadata) -> bool {
+        self.mode == other.mode
+            && self.uid == other.uid
+            && self.gid == other.gid
+            && self.mtime == other.mtime
+            && self.mtime_nanos == other.mtime_nanos
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.mode.to_le_bytes());
+        bytes.extend_from_slice(&self.uid.to_le_bytes());
+        bytes.extend_from_slice(&self.gid.to_le_bytes());
+        bytes.extend_from_slice(&self.mtime.to_le_bytes());
+        bytes.extend_from_slice(&self.mtime_nanos.to_le_bytes());
+        bytes.extend_from_slice(&self.atime.to_le_bytes());
+        bytes.extend_from_slice(&self.atime_nanos.to_le_bytes());
+        bytes
+    }
+
+    pub fn read(reader: &mut impl Read) -> Result<EntryMetadata, HeaderError> {
+        Ok(EntryMetadata {
+            mode: u32::from_le_bytes(read_array(reader)?),
+            uid: u32::from_le_bytes(read_array(reader)?),
+            gid: u32::from_le_bytes(read_array(reader)?),
+            mtime: i64::from_le_bytes(read_array(reader)?),
+            mtime_nanos: u32::from_le_bytes(read_array(reader)?),
+            atime: i64::from_le_bytes(read_array(reader)?),
+            atime_nanos: u32::from_le_bytes(read_array(reader)?),
+        })
+    }
+
+    /// apply the stored times, ownership and permissions to an extracted file or directory.
+    ///
+    /// ownership is only restored when `restore_owner` is set,
+    /// since changing it usually requires running as root.
+    /// setuid, setgid and sticky bits are dropped when it isn't
+    pub fn apply(&self, path: &Path, restore_owner: bool) -> io::Result<()> {
+        // times first, the file may not be readable once its permissions are set
+        let times = FileTimes::new()
+            .set_modified(Self::to_system_time(self.mtime, self.mtime_nanos))
+            .set_accessed(Self::to_system_time(self.atime, self.atime_nanos));
+        Self::open_for_times(path)?.set_times(times)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            // ownership before permissions, as changing owner can clear setuid bits.
+            // those bits are only kept for the owner they were archived with,
+            // never handed to whoever happens to be extracting
+            let mode = match restore_owner {
+                true => {
+                    std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid))?;
+                    self.mode & 0o7777
+                }
+                false => self.mode & 0o777,
+            };
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = restore_owner;
+            let mut permissions = fs::metadata(path)?.permissions();
+            permissions.set_readonly(self.mode & 0o222 == 0);
+            fs::set_permissions(path, permissions)?;
+        }
+
+        Ok(())
+    }
+
+    /// open a file or directory with enough access to set its times
+    #[cfg(unix)]
+    fn open_for_times(path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+
+    /// open a file or directory with enough access to set its times,
+    /// which takes write access outside of unix
+    #[cfg(not(unix))]
+    fn open_for_times(path: &Path) -> io::Result<File> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true);
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            // FILE_FLAG_BACKUP_SEMANTICS, without which directories can't be opened
+            options.custom_flags(0x0200_0000);
+        }
+        options.open(path)
+    }
+
+    fn to_system_time(secs: i64, nanos: u32) -> SystemTime {
+        let nanos = Duration::from_nanos(nanos as u64);
+        match secs {
+            0.. => UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos,
+            _ => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos,
+        }
+    }
+}
@@ -1,3 +1,22 @@
 pub(crate) mod indexer;
 pub(crate) mod archiver;
-pub(crate) mod header;
\ No newline at end of file
+pub(crate) mod crypto;
+pub(crate) mod dedup;
+pub(crate) mod directory;
+pub(crate) mod filter;
+pub(crate) mod forward;
+pub(crate) mod header;
+pub(crate) mod integrity;
+pub(crate) mod keys;
+pub(crate) mod metadata;
+pub(crate) mod paths;
+pub(crate) mod pipeline;
+pub(crate) mod recovery;
+pub(crate) mod remove;
+pub(crate) mod salvage;
+pub(crate) mod signature;
+pub(crate) mod solid;
+pub(crate) mod stream;
+pub(crate) mod update;
+pub(crate) mod verify;
+pub(crate) mod volume;
\ No newline at end of file
@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use crate::archival::files::archiver::ArchivalError;
+
+/// the path an entry is extracted to within `root`.
+///
+/// names which are absolute or climb out of the root with `..` are refused,
+/// so a crafted archive can't write anywhere outside of the output
+pub fn entry_path(root: &Path, name: &str) -> Result<PathBuf, ArchivalError> {
+    let mut path = root.to_path_buf();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err(ArchivalError(
+                format!("refusing to extract \"{}\", it points outside of the output", name)
+            ))
+        }
+    }
+    Ok(path)
+}
+
+/// ensure the symbolic link `name` can only point at something inside the output root.
+///
+/// the target is resolved relative to the link without touching the file system,
+/// so it may never climb with `..` once it has passed through any of the archive's
+/// `links`, as the path underneath a link could lead anywhere
+pub fn check_link_target(
+    name: &str, target: &str, links: &HashSet<PathBuf>
+) -> Result<(), ArchivalError>
+{
+    let escapes = || ArchivalError(format!(
+        "refusing to create link \"{}\" -> \"{}\", it points outside of the output",
+        name, target
+    ));
+
+    let mut resolved = match Path::new(name).parent() {
+        Some(parent) => parent.to_path_buf(),
+        None => PathBuf::new(),
+    };
+    let mut through_link = resolved.ancestors().any(|prefix| links.contains(prefix));
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(part) => {
+                resolved.push(part);
+                through_link |= links.contains(&resolved);
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if through_link || !resolved.pop() {
+                    return Err(escapes())
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+        }
+    }
+    Ok(())
+}
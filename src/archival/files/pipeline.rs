@@ -0,0 +1,455 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::Scope;
+use crate::archival::compression::file_compressor::{Compressor, BLOCK_SIZE};
+use crate::archival::compression::profiler::Profiler;
+use crate::archival::cli::output::FmtProgress;
+use crate::archival::files::archiver::{restore_entry_metadata, write_file, ArchivalError, Archiver};
+use crate::archival::files::crypto::Cipher;
+use crate::archival::files::dedup::CHUNKED;
+use crate::archival::files::directory::{DirectoryEntry, EntryKind};
+use crate::archival::files::integrity::ChecksumWriter;
+use crate::archival::files::solid::SOLID;
+use crate::archival::files::stream::{decompress_blocks, BlockSums, DESCRIBED};
+use crate::constants::MEGABYTE;
+
+/// what `add` will do with each of its files, decided before anything is written
+#[derive(Debug, PartialEq)]
+pub(crate) enum Plan {
+    /// the file still matches its entry in the existing archive, which is copied over
+    Unchanged,
+    /// a directory or link, made up of only a header
+    Special(EntryKind),
+    /// a small file compressed with the given method inside a solid block of the given size
+    Solid(u8, u64),
+    /// a file compressed with the given method and split into deduplicated chunks
+    Chunked(u8),
+    /// a file compressed with the given method block by block
+    Compressed(u8),
+}
+
+/// run `f` over every item on `jobs` threads, returning the results in the order of the items
+pub(crate) fn parallel_map<T: Sync, R: Send>(
+    jobs: usize, items: &[T], f: impl Fn(&T) -> R + Sync
+) -> Vec<R>
+{
+    if jobs <= 1 {
+        return items.iter().map(f).collect()
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let workers = (0..jobs.min(items.len()))
+            .map(|_| scope.spawn(|| {
+                let mut done = vec![];
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    match items.get(i) {
+                        Some(item) => done.push((i, f(item))),
+                        None => return done
+                    }
+                }
+            }))
+            .collect::<Vec<_>>();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect::<Vec<(usize, R)>>()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// a block of a file for a worker to compress
+struct Work {
+    seq: u64,
+    method: u8,
+    data: Vec<u8>,
+}
+
+/// what the writer is handed, in the order the feeder read it
+enum Done {
+    /// a compressed block of the file
+    Block(Vec<u8>),
+    /// every block of the file has been sent
+    End { size: u64, checksum: u32 },
+    Failed(ArchivalError),
+}
+
+/// the blocks of files compressed by a pool of workers, handed back in the order they were read.
+///
+/// a feeder thread reads the files one after another and hands their blocks out to
+/// the workers, while only the writer ever touches the archive. each file is read once,
+/// like `archive_compressed_file` reads it, so at most a window of blocks is ever held in memory
+pub(crate) struct Pipeline {
+    results: Receiver<(u64, Done)>,
+    /// every item handed to the writer gives a ticket back for the feeder to read another
+    tickets: SyncSender<()>,
+    pending: BTreeMap<u64, Done>,
+    next: u64,
+}
+
+impl Pipeline {
+    /// start compressing `files` with their methods on `jobs` workers
+    pub(crate) fn start<'scope>(
+        scope: &'scope Scope<'scope, '_>, jobs: usize, files: Vec<(PathBuf, u8)>
+    ) -> Pipeline
+    {
+        let window = jobs * 2;
+        let (ticket_tx, ticket_rx) = sync_channel(window);
+        for _ in 0..window {
+            let _ = ticket_tx.send(());
+        }
+        let (work_tx, work_rx) = channel::<Work>();
+        let (result_tx, result_rx) = channel();
+
+        let work_rx = Arc::new(Mutex::new(work_rx));
+        for _ in 0..jobs {
+            let work_rx = Arc::clone(&work_rx);
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                let work = match work_rx.lock().unwrap().recv() {
+                    Ok(work) => work,
+                    Err(_) => return
+                };
+                let block = Compressor::new(work.data, work.method).compress();
+                if result_tx.send((work.seq, Done::Block(block))).is_err() {
+                    return
+                }
+            });
+        }
+        scope.spawn(move || {
+            let mut feeder = Feeder { seq: 0, tickets: ticket_rx, work: work_tx, results: result_tx };
+            for (path, method) in files {
+                match feeder.feed_file(&path, method) {
+                    Ok(true) => continue,
+                    Ok(false) => return,
+                    Err(e) => {
+                        feeder.send(Err(Done::Failed(ArchivalError(
+                            format!("Could not read input file: \"{}\"\nreason: {}", path.display(), e)
+                        ))));
+                        return
+                    }
+                }
+            }
+        });
+
+        Pipeline { results: result_rx, tickets: ticket_tx, pending: BTreeMap::new(), next: 0 }
+    }
+
+    /// the next item in the order the feeder read them
+    fn next(&mut self) -> Result<Done, ArchivalError> {
+        let lost = || ArchivalError(String::from("a compression worker stopped unexpectedly"));
+        while !self.pending.contains_key(&self.next) {
+            let (seq, done) = self.results.recv().map_err(|_| lost())?;
+            self.pending.insert(seq, done);
+        }
+        let done = self.pending.remove(&self.next).unwrap();
+        self.next += 1;
+        let _ = self.tickets.send(());
+        Ok(done)
+    }
+}
+
+/// reads the files given to the pipeline, numbering everything it sends in the order it was read
+struct Feeder {
+    seq: u64,
+    tickets: Receiver<()>,
+    work: Sender<Work>,
+    results: Sender<(u64, Done)>,
+}
+
+impl Feeder {
+    /// send a block to the workers or anything else straight to the writer,
+    /// once the writer has room for it. false once the writer has gone
+    fn send(&mut self, item: Result<(u8, Vec<u8>), Done>) -> bool {
+        if self.tickets.recv().is_err() {
+            return false
+        }
+        let seq = self.seq;
+        self.seq += 1;
+        match item {
+            Ok((method, data)) => self.work.send(Work { seq, method, data }).is_ok(),
+            Err(done) => self.results.send((seq, done)).is_ok(),
+        }
+    }
+
+    /// send every block of a file, followed by its end. false once the writer has gone
+    fn feed_file(&mut self, path: &PathBuf, method: u8) -> io::Result<bool> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut sums = ChecksumWriter::new(io::sink());
+        loop {
+            let block = Compressor::read_block(&mut reader)?;
+            if block.is_empty() {
+                break
+            }
+            let _ = sums.write_all(&block);
+            if !self.send(Ok((method, block))) {
+                return Ok(false)
+            }
+        }
+        Ok(self.send(Err(Done::End { size: sums.written(), checksum: sums.checksum() })))
+    }
+}
+
+impl Archiver {
+    /// decide what to do with each file given to `add`, profiling them and checking
+    /// them against the existing archive on `jobs` threads
+    pub(crate) fn plan_files(
+        &self, names: &[String], existing: &HashMap<String, DirectoryEntry>
+    ) -> Vec<Result<Plan, ArchivalError>>
+    {
+        let items = self.files.iter()
+            .zip(names)
+            .map(|(path, name)| (path, self.entry_kind(path), existing.get(name)))
+            .collect::<Vec<_>>();
+        let (solid_block_size, dedup) = (self.solid_block_size, self.dedup);
+
+        parallel_map(self.jobs, &items, |(path, kind, entry)| {
+            // files which haven't changed don't need to be compressed again
+            if let Some(entry) = entry {
+                if Archiver::is_unchanged(path, kind.as_ref().ok(), entry) {
+                    return Ok(Plan::Unchanged)
+                }
+            }
+
+            let kind = match kind {
+                Ok(kind) => kind.clone(),
+                Err(e) => return Err(ArchivalError(
+                    format!("Could not read \"{}\"\nreason: {}", path.display(), e)
+                ))
+            };
+            if kind != EntryKind::File {
+                return Ok(Plan::Special(kind))
+            }
+
+            // profile the file to determine the best method to compress it
+            let method = Profiler::new(path.to_path_buf()).profile();
+            let solid_block_size = solid_block_size
+                .filter(|size| fs::metadata(path).map(|m| m.len() < *size).unwrap_or(false));
+            Ok(match solid_block_size {
+                Some(size) => Plan::Solid(method, size),
+                None if dedup => Plan::Chunked(method),
+                None => Plan::Compressed(method),
+            })
+        })
+    }
+
+    /// write a file compressed by the pipeline, which must be the next file it was given
+    pub(crate) fn write_compressed_file(
+        &mut self, method: u8, path: &Path, pipeline: &mut Pipeline
+    ) -> Result<u64, ArchivalError>
+    {
+        let (header, offset) = self.start_compressed_file(method, path)?;
+        let context = header.to_bytes();
+
+        let mut compressed = 0;
+        for index in 0.. {
+            match pipeline.next()? {
+                Done::Block(block) => compressed += self.write_block(&context, index, block)?,
+                Done::End { size, checksum } => {
+                    let written = self.measure_file(path, header, offset, BlockSums { size, compressed, checksum })?;
+                    self.file_written(path, written);
+                    return Ok(0)
+                }
+                Done::Failed(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+}
+
+/// how many bytes of entries read from the archive may wait on extraction workers at once
+pub const EXTRACT_MEMORY_BUDGET: u64 = 64 * MEGABYTE;
+
+/// the bytes held by work handed to other threads, which has to wait for room before taking more
+pub(crate) struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    freed: Condvar,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: u64) -> MemoryBudget {
+        MemoryBudget { limit, used: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    /// wait until `bytes` fit in what is left of the budget
+    fn acquire(&self, bytes: u64) {
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + bytes > self.limit {
+            used = self.freed.wait(used).unwrap();
+        }
+        *used += bytes;
+    }
+
+    fn release(&self, bytes: u64) {
+        *self.used.lock().unwrap() -= bytes;
+        self.freed.notify_all();
+    }
+}
+
+/// a file entry read from the archive for a worker to write out
+struct Extraction {
+    /// the entry as the directory lists it, whose metadata is restored
+    entry: DirectoryEntry,
+    /// the entry as its header describes its data
+    header: DirectoryEntry,
+    path: PathBuf,
+    /// the blocks of the file as they are stored, or its contents when `decoded`
+    data: Vec<u8>,
+    decoded: bool,
+    /// how much of the memory budget the extraction holds
+    cost: u64,
+}
+
+/// workers writing out the files read by `extract`, so the time spent decompressing
+/// and waiting on the file system is spread across threads
+pub(crate) struct ExtractPool<'scope> {
+    budget: &'scope MemoryBudget,
+    work: Option<Sender<Extraction>>,
+    /// each file written, with its compressed size to report the progress made
+    results: Receiver<(PathBuf, u64, Result<(), ArchivalError>)>,
+}
+
+impl<'scope> ExtractPool<'scope> {
+    /// start `jobs` workers, opening data with `cipher` when the archive is `encrypted`
+    pub(crate) fn start(
+        scope: &'scope Scope<'scope, '_>, jobs: usize, budget: &'scope MemoryBudget,
+        encrypted: bool, cipher: Option<&'scope Cipher>, restore_owner: bool,
+    ) -> ExtractPool<'scope>
+    {
+        let (work_tx, work_rx) = channel::<Extraction>();
+        let (result_tx, result_rx) = channel();
+
+        let work_rx = Arc::new(Mutex::new(work_rx));
+        for _ in 0..jobs {
+            let work_rx = Arc::clone(&work_rx);
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                let job = match work_rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return
+                };
+                let res = Self::write(&job, encrypted, cipher)
+                    .and_then(|_| restore_entry_metadata(&job.path, &job.entry, restore_owner));
+                budget.release(job.cost);
+                if result_tx.send((job.path, job.entry.compressed_size, res)).is_err() {
+                    return
+                }
+            });
+        }
+
+        ExtractPool { budget, work: Some(work_tx), results: result_rx }
+    }
+
+    /// the part of the budget extracting `entry` would hold, as long as it fits at all.
+    /// a file stored as blocks is decompressed a block at a time, anything else all at once
+    fn cost(&self, entry: &DirectoryEntry) -> Option<u64> {
+        // a described file read from its header alone has no size to read it by
+        if entry.method & DESCRIBED != 0 && entry.compressed_size == 0 {
+            return None
+        }
+        let cost = match entry.method & (CHUNKED | SOLID) {
+            0 => entry.compressed_size + entry.decompressed_size.min(BLOCK_SIZE),
+            _ => entry.decompressed_size,
+        };
+        Some(cost).filter(|cost| *cost <= self.budget.limit)
+    }
+
+    /// whether `entry` is small enough to be handed to the workers,
+    /// rather than streamed straight to its file
+    pub(crate) fn fits(&self, entry: &DirectoryEntry) -> bool {
+        self.cost(entry).is_some()
+    }
+
+    fn write(job: &Extraction, encrypted: bool, cipher: Option<&Cipher>) -> Result<(), ArchivalError> {
+        write_file(&job.path, |writer| {
+            if job.decoded {
+                return writer.write_all(&job.data).map_err(|e| ArchivalError(
+                    format!("Could not write the data of \"{}\": {}", job.header.name, e)
+                ))
+            }
+            let DirectoryEntry { name, decompressed_size, .. } = &job.header;
+            let mut out = ChecksumWriter::new(writer);
+            let (_, checksum) = decompress_blocks(&mut job.data.as_slice(), encrypted, cipher, &job.header, &mut out)?;
+            out.verify(name, *decompressed_size, checksum)?;
+            Ok(())
+        })
+    }
+}
+
+impl Archiver {
+    /// read a file entry and hand it to the workers once the budget has room for it,
+    /// reporting the files they have finished with in the meantime
+    pub(crate) fn queue_extraction(
+        &mut self, pool: &mut ExtractPool, entry: DirectoryEntry, path: PathBuf
+    ) -> Result<(), ArchivalError>
+    {
+        let cost = pool.cost(&entry).unwrap();
+        pool.budget.acquire(cost);
+        let read = match entry.method & (CHUNKED | SOLID) {
+            // blocks are read as they are and left for the worker to open and decompress
+            0 => self.read_entry_start(&entry).and_then(|header| {
+                let mut data = vec![0; header.compressed_size as usize];
+                match self.archive_reader.as_mut().unwrap().read_exact(&mut data) {
+                    Ok(_) => Ok((header, data, false)),
+                    Err(e) => Err(ArchivalError(
+                        format!("failed to read file data for \"{}\": {}", header.name, e)
+                    ))
+                }
+            }),
+            // chunks and solid blocks are shared between entries, so are decoded here
+            _ => {
+                let mut data = Vec::with_capacity(entry.decompressed_size as usize);
+                self.read_entry_to(&entry, &mut data).map(|_| (entry.clone(), data, true))
+            }
+        };
+        let (header, data, decoded) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                pool.budget.release(cost);
+                return Err(e)
+            }
+        };
+
+        let job = Extraction { entry, header, path, data, decoded, cost };
+        if pool.work.as_ref().unwrap().send(job).is_err() {
+            return Err(ArchivalError(String::from("an extraction worker stopped unexpectedly")))
+        }
+        for (path, compressed, res) in pool.results.try_iter() {
+            res?;
+            self.file_extracted(&path, compressed);
+        }
+        Ok(())
+    }
+
+    /// wait for the workers to write every file handed to them
+    pub(crate) fn finish_extraction(&mut self, mut pool: ExtractPool) -> Result<(), ArchivalError> {
+        pool.work = None;
+        for (path, compressed, res) in pool.results.iter() {
+            res?;
+            self.file_extracted(&path, compressed);
+        }
+        Ok(())
+    }
+
+    /// count a file which was extracted and report the progress made
+    pub(crate) fn file_extracted(&mut self, path: &Path, compressed: u64) {
+        self.format_progress(format!("{}", path.display()));
+
+        // logging
+        self.bytes_processed += compressed as usize;
+        self.files_processed += 1;
+        self.speed = (self.bytes_processed as f64 /
+            self.start_time.unwrap().elapsed().as_secs_f64()
+        ) as usize;
+    }
+}
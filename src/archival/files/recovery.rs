@@ -0,0 +1,515 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::directory::Footer;
+use crate::archival::files::header::{expect_magic, read_array};
+use crate::archival::files::integrity;
+use crate::archival::files::volume::split_base;
+use crate::constants::MEGABYTE;
+
+/// signature found at the start and the very end of a recovery record
+pub const RECOVERY_MAGIC: [u8; 4] = *b"ARKR";
+/// size in bytes of the blocks parity is computed over
+pub const RECOVERY_BLOCK_SIZE: u32 = 4096;
+/// reed-solomon over gf(2^8) can't have more data and parity blocks in a group than this
+const MAX_GROUP_BLOCKS: u64 = 256;
+/// size in bytes of the record's offset and magic, stored straight before the footer
+const LOCATOR_SIZE: u64 = 8 + 4;
+/// the most parity held in memory at once while writing a recovery record
+const PARITY_MEMORY_BUDGET: u64 = 64 * MEGABYTE;
+
+/// parity stored at the end of an archive, which damaged blocks of it can be rebuilt from.
+///
+/// everything before the record is split into blocks, and block `i` falls into group
+/// `i % groups`, each group having its own parity blocks. neighbouring blocks are spread
+/// over different groups, so a run of damaged blocks only costs each group a little.
+///
+/// the checksums come after the parity, so the parity can be written out
+/// a few groups at a time, before the checksums of every block are known
+///
+/// # Layout
+/// ```ignore
+/// magic             [u8; 4]  "ARKR"
+/// block size        u32
+/// covered           u64      bytes of the archive covered from its start, up to the record
+/// groups            u64
+/// parity per group  u32
+/// directory offset  u64      a copy of the footer, so a damaged one can be rebuilt
+/// directory size    u64
+///
+/// parity            [u8; block size] for each parity block, a group at a time
+///
+/// checksums         [u32; blocks] crc32 of each block covered, the last padded with zeros
+/// parity checksums  [u32; groups * parity per group]
+/// header checksum   u32      crc32 of the record, leaving out the parity
+/// record offset     u64      where the record starts, so it can be found from the end
+/// magic             [u8; 4]  "ARKR"
+/// ```
+pub struct RecoveryRecord {
+    pub block_size: u32,
+    pub covered: u64,
+    pub groups: u64,
+    pub parity_per_group: u32,
+    pub directory_offset: u64,
+    pub directory_size: u64,
+    pub checksums: Vec<u32>,
+    pub parity_checksums: Vec<u32>,
+    pub parity: Vec<Vec<u8>>,
+}
+
+impl RecoveryRecord {
+    /// the number of blocks the archive is split into
+    pub fn block_count(&self) -> u64 {
+        self.covered.div_ceil(self.block_size as u64)
+    }
+
+    /// the parity blocks of `group`
+    fn parity_range(&self, group: u64) -> std::ops::Range<usize> {
+        let per_group = self.parity_per_group as usize;
+        group as usize * per_group..(group as usize + 1) * per_group
+    }
+
+    /// encode the whole record, followed by its locator
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head_bytes();
+        for block in &self.parity {
+            bytes.extend_from_slice(block);
+        }
+        bytes.extend(self.tail_bytes());
+        bytes
+    }
+
+    /// encode what follows the parity: the checksums, the header checksum and the locator
+    fn tail_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.checksum_bytes();
+        let header = [self.head_bytes(), bytes.clone()].concat();
+        bytes.extend_from_slice(&integrity::checksum(&header).to_le_bytes());
+        bytes.extend(self.locator());
+        bytes
+    }
+
+    /// where the record starts, which is also where its coverage ends, then its magic again
+    fn locator(&self) -> Vec<u8> {
+        let mut bytes = self.covered.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&RECOVERY_MAGIC);
+        bytes
+    }
+
+    /// the footer the archive had when the record was written
+    pub fn footer(&self) -> Footer {
+        Footer { directory_offset: self.directory_offset, directory_size: self.directory_size }
+    }
+
+    /// read a record from the current position of `reader`, checking its header
+    /// against its checksum before trusting any of it. the sizes it claims are
+    /// bounded by `archive_size`, as damage could have made them anything
+    pub fn read(reader: &mut impl Read, archive_size: u64) -> Result<RecoveryRecord, ArchivalError> {
+        let damaged = || ArchivalError(String::from("the recovery record is damaged"));
+        expect_magic(reader, RECOVERY_MAGIC)?;
+
+        let mut record = RecoveryRecord {
+            block_size: u32::from_le_bytes(read_array(reader)?),
+            covered: u64::from_le_bytes(read_array(reader)?),
+            groups: u64::from_le_bytes(read_array(reader)?),
+            parity_per_group: u32::from_le_bytes(read_array(reader)?),
+            directory_offset: u64::from_le_bytes(read_array(reader)?),
+            directory_size: u64::from_le_bytes(read_array(reader)?),
+            checksums: vec![],
+            parity_checksums: vec![],
+            parity: vec![],
+        };
+        let parity_blocks = record.groups.checked_mul(record.parity_per_group as u64);
+        let sane = (1..=16 * MEGABYTE).contains(&(record.block_size as u64))
+            && record.covered <= archive_size
+            && (1..=record.block_count().max(1)).contains(&record.groups)
+            && (1..MAX_GROUP_BLOCKS).contains(&(record.parity_per_group as u64))
+            && parity_blocks.and_then(|n| n.checked_mul(record.block_size as u64))
+                .is_some_and(|size| size <= archive_size);
+        if !sane {
+            return Err(damaged())
+        }
+
+        // parity blocks are only checked against their checksums when repairing,
+        // so a damaged one can be rebuilt along with the archive
+        for _ in 0..parity_blocks.unwrap() {
+            let mut block = Vec::with_capacity(record.block_size as usize);
+            let _ = reader.by_ref().take(record.block_size as u64).read_to_end(&mut block);
+            block.resize(record.block_size as usize, 0);
+            record.parity.push(block);
+        }
+
+        for _ in 0..record.block_count() {
+            record.checksums.push(u32::from_le_bytes(read_array(reader)?));
+        }
+        for _ in 0..parity_blocks.unwrap() {
+            record.parity_checksums.push(u32::from_le_bytes(read_array(reader)?));
+        }
+        let header = [record.head_bytes(), record.checksum_bytes()].concat();
+        if u32::from_le_bytes(read_array(reader)?) != integrity::checksum(&header) {
+            return Err(damaged())
+        }
+        Ok(record)
+    }
+
+    /// encode the fields before the parity
+    fn head_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(44);
+        bytes.extend_from_slice(&RECOVERY_MAGIC);
+        bytes.extend_from_slice(&self.block_size.to_le_bytes());
+        bytes.extend_from_slice(&self.covered.to_le_bytes());
+        bytes.extend_from_slice(&self.groups.to_le_bytes());
+        bytes.extend_from_slice(&self.parity_per_group.to_le_bytes());
+        bytes.extend_from_slice(&self.directory_offset.to_le_bytes());
+        bytes.extend_from_slice(&self.directory_size.to_le_bytes());
+        bytes
+    }
+
+    /// encode the checksums of the blocks covered, then those of the parity blocks
+    fn checksum_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((self.checksums.len() + self.parity_checksums.len()) * 4);
+        for checksum in self.checksums.iter().chain(self.parity_checksums.iter()) {
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// the parity blocks a group of `blocks` data blocks is given, at least one
+fn parity_for(blocks: u64, percent: u8) -> u64 {
+    (blocks * percent as u64).div_ceil(100).max(1)
+}
+
+/// split `blocks` into groups small enough for reed-solomon along with their parity,
+/// returning the number of groups and the parity blocks of each
+fn layout(blocks: u64, percent: u8) -> (u64, u64) {
+    let mut largest = MAX_GROUP_BLOCKS * 100 / (100 + percent as u64);
+    while largest + parity_for(largest, percent) > MAX_GROUP_BLOCKS {
+        largest -= 1;
+    }
+    let groups = blocks.div_ceil(largest).max(1);
+    (groups, parity_for(blocks.div_ceil(groups), percent))
+}
+
+/// the number of data blocks in `group` when `blocks` are dealt out over `groups`
+fn group_blocks(blocks: u64, groups: u64, group: u64) -> usize {
+    (blocks - group).div_ceil(groups) as usize
+}
+
+/// read block `index` of the archive, padded with zeros to the full block size,
+/// along with whether all of it could be read
+fn read_block(
+    reader: &mut (impl Read + Seek), index: u64, block_size: u32, covered: u64
+) -> io::Result<(Vec<u8>, bool)>
+{
+    let start = index * block_size as u64;
+    let length = (covered - start).min(block_size as u64);
+    reader.seek(SeekFrom::Start(start))?;
+
+    let mut block = Vec::with_capacity(block_size as usize);
+    reader.take(length).read_to_end(&mut block)?;
+    let complete = block.len() as u64 == length;
+    block.resize(block_size as usize, 0);
+    Ok((block, complete))
+}
+
+impl Archiver {
+    /// compute parity over the archive written so far by reading it back,
+    /// then write it out as the recovery record.
+    ///
+    /// the parity of a group is only complete once its last block is read, and the blocks
+    /// of every group are spread over the whole archive, so the parity is computed a batch
+    /// of groups at a time, each batch reading only its own blocks and being written out
+    /// before the next. the archive is still read once over, just not in order
+    pub(crate) fn write_recovery_record(&mut self, footer: &Footer) -> Result<(), ArchivalError> {
+        let percent = match self.recovery {
+            Some(percent) => percent,
+            None => return Ok(())
+        };
+        let write_err = |e: io::Error| ArchivalError(format!("Failed to write the recovery record: {}", e));
+        let mut reader = self.read_back_output().map_err(write_err)?;
+
+        let covered = self.archive_offset;
+        let block_size = RECOVERY_BLOCK_SIZE;
+        let blocks = covered.div_ceil(block_size as u64);
+        let (groups, parity_per_group) = layout(blocks, percent);
+
+        let mut record = RecoveryRecord {
+            block_size,
+            covered,
+            groups,
+            parity_per_group: parity_per_group as u32,
+            directory_offset: footer.directory_offset,
+            directory_size: footer.directory_size,
+            checksums: vec![0; blocks as usize],
+            parity_checksums: Vec::with_capacity((groups * parity_per_group) as usize),
+            parity: vec![],
+        };
+        self.write_archive_bytes(&record.head_bytes()).map_err(write_err)?;
+
+        let batch = (PARITY_MEMORY_BUDGET / (parity_per_group * block_size as u64)).max(1);
+        let mut codecs = HashMap::new();
+        for first in (0..groups).step_by(batch as usize) {
+            let batch_groups = first..(first + batch).min(groups);
+            let mut parity = vec![vec![0; block_size as usize]; ((batch_groups.end - first) * parity_per_group) as usize];
+
+            // each group's parity is built up a block at a time, its blocks read in order
+            for group in batch_groups {
+                let data_blocks = group_blocks(blocks, groups, group);
+                let codec = codecs.entry(data_blocks).or_insert_with(|| {
+                    ReedSolomon::new(data_blocks, parity_per_group as usize)
+                        .expect("groups are laid out within reed-solomon's limits")
+                });
+                let range = record.parity_range(group - first);
+                for k in 0..data_blocks {
+                    let index = group + k as u64 * groups;
+                    let (block, _) = read_block(&mut reader, index, block_size, covered).map_err(write_err)?;
+                    record.checksums[index as usize] = integrity::checksum(&block);
+                    if let Err(e) = codec.encode_single_sep(k, &block, &mut parity[range.clone()]) {
+                        return Err(ArchivalError(format!("Failed to compute the recovery record: {:?}", e)))
+                    }
+                }
+            }
+
+            for block in &parity {
+                record.parity_checksums.push(integrity::checksum(block));
+                self.write_archive_bytes(block).map_err(write_err)?;
+            }
+        }
+
+        self.write_archive_bytes(&record.tail_bytes()).map_err(write_err)
+    }
+
+    /// rebuild the damaged blocks of an archive from its recovery record, then write the
+    /// healthy archive to the output, replacing the input when no output was given.
+    ///
+    /// nothing is written unless every damaged block could be rebuilt
+    pub fn repair(&mut self) -> Result<u64, ArchivalError> {
+        let mut record = self.find_recovery_record()?;
+        let read_err = |e: io::Error| ArchivalError(format!("Could not read the archive\nreason: {}", e));
+        let (blocks, groups) = (record.block_count(), record.groups);
+        let per_group = record.parity_per_group as usize;
+
+        let reader = self.archive_reader.as_mut().unwrap();
+        let mut damaged = BTreeSet::new();
+        for index in 0..blocks {
+            let (block, complete) = read_block(reader, index, record.block_size, record.covered).map_err(read_err)?;
+            if !complete || integrity::checksum(&block) != record.checksums[index as usize] {
+                damaged.insert(index);
+            }
+        }
+        let damaged_parity = (0..record.parity.len())
+            .filter(|i| integrity::checksum(&record.parity[*i]) != record.parity_checksums[*i])
+            .collect::<BTreeSet<usize>>();
+
+        // rebuild each group which lost anything, as long as it lost no more than its parity
+        let mut repaired = HashMap::new();
+        let mut lost = 0;
+        let damaged_groups = damaged.iter()
+            .map(|index| index % groups)
+            .chain(damaged_parity.iter().map(|i| (i / per_group) as u64))
+            .collect::<BTreeSet<u64>>();
+        for group in damaged_groups {
+            let data_blocks = group_blocks(blocks, groups, group);
+            let indices = (0..data_blocks as u64).map(|k| group + k * groups).collect::<Vec<u64>>();
+            let parity_range = record.parity_range(group);
+
+            let mut shards = Vec::with_capacity(data_blocks + per_group);
+            for index in &indices {
+                shards.push(match damaged.contains(index) {
+                    true => None,
+                    false => Some(read_block(reader, *index, record.block_size, record.covered).map_err(read_err)?.0),
+                });
+            }
+            for i in parity_range.clone() {
+                shards.push((!damaged_parity.contains(&i)).then(|| record.parity[i].clone()));
+            }
+
+            let missing = shards.iter().filter(|shard| shard.is_none()).count();
+            let codec = ReedSolomon::new(data_blocks, per_group)
+                .expect("groups are laid out within reed-solomon's limits");
+            if missing > per_group || codec.reconstruct(&mut shards).is_err() {
+                lost += missing;
+                continue
+            }
+
+            for (k, index) in indices.iter().enumerate() {
+                if damaged.contains(index) {
+                    let block = shards[k].take().unwrap();
+                    // a block rebuilt wrong is as good as lost
+                    match integrity::checksum(&block) == record.checksums[*index as usize] {
+                        true => { repaired.insert(*index, block); }
+                        false => lost += 1,
+                    }
+                }
+            }
+            for (k, i) in parity_range.enumerate() {
+                if damaged_parity.contains(&i) {
+                    record.parity[i] = shards[data_blocks + k].take().unwrap();
+                }
+            }
+        }
+
+        let found = damaged.len() + damaged_parity.len();
+        eprintln!(
+            "{} damaged blocks found in {}: {} recovered, {} could not be recovered",
+            found, self.input.display(), found - lost, lost
+        );
+        if lost > 0 {
+            return Err(ArchivalError(
+                format!("{} can't be repaired, {} blocks could not be recovered", self.input.display(), lost)
+            ))
+        }
+
+        // the locator and footer after the record are rebuilt too, so check them as well
+        let mut tail = record.locator();
+        tail.extend(record.footer().to_bytes());
+        let tail_start = self.archive_size.checked_sub(tail.len() as u64);
+        let mut found_tail = vec![0; tail.len()];
+        let tail_intact = tail_start.is_some_and(|start| {
+            reader.seek(SeekFrom::Start(start)).and_then(|_| reader.read_exact(&mut found_tail)).is_ok()
+        }) && found_tail == tail;
+
+        let in_place = self.output == split_base(&self.input).unwrap_or(self.input.clone());
+        if found == 0 && tail_intact && in_place {
+            eprintln!("{} is healthy, nothing to repair", self.input.display());
+            return Ok(self.archive_size)
+        }
+
+        let res = self.write_repaired(&record, repaired);
+        if res.is_err() {
+            self.remove_temporary_output();
+        }
+        res
+    }
+
+    /// write the archive covered by `record` with its damaged blocks replaced by those rebuilt
+    fn write_repaired(&mut self, record: &RecoveryRecord, mut repaired: HashMap<u64, Vec<u8>>) -> Result<u64, ArchivalError> {
+        let write_err = |e: io::Error| ArchivalError(format!("Failed to write the repaired archive: {}", e));
+        self.open_archive_writer()?;
+
+        for index in 0..record.block_count() {
+            let block = match repaired.remove(&index) {
+                Some(block) => block,
+                None => read_block(self.archive_reader.as_mut().unwrap(), index, record.block_size, record.covered)
+                    .map_err(write_err)?.0,
+            };
+            let length = (record.covered - index * record.block_size as u64).min(record.block_size as u64);
+            self.write_archive_bytes(&block[..length as usize]).map_err(write_err)?;
+        }
+        self.write_archive_bytes(record.to_bytes().as_slice()).map_err(write_err)?;
+        self.write_archive_bytes(record.footer().to_bytes().as_slice()).map_err(write_err)?;
+
+        self.archive_writer.as_mut().unwrap().flush().map_err(write_err)?;
+        self.replace_output()?;
+        eprintln!("Repaired archive written to {}", self.output.display());
+        Ok(self.archive_offset)
+    }
+
+    /// bring the recovery record up to date after `len` bytes at `start` were overwritten
+    /// in place, as changing the key slots does, so a repair doesn't put the old bytes back.
+    ///
+    /// only the groups holding those bytes have their parity computed again,
+    /// and an archive without a record is left as it is
+    pub(crate) fn refresh_recovery_record(&mut self, start: u64, len: u64) -> Result<(), ArchivalError> {
+        let mut record = match self.locate_recovery_record() {
+            Some(record) => record,
+            None => return Ok(())
+        };
+        let input = self.input.clone();
+        let update_err = |e: io::Error| ArchivalError(
+            format!("Could not update the recovery record of \"{}\"\nreason: {}", input.display(), e)
+        );
+        let (blocks, groups) = (record.block_count(), record.groups);
+        let (block_size, per_group) = (record.block_size as u64, record.parity_per_group as usize);
+        let reader = self.archive_reader.as_mut().unwrap();
+
+        let mut changed_groups = BTreeSet::new();
+        for index in start / block_size..(start + len).div_ceil(block_size).min(blocks) {
+            let (block, _) = read_block(reader, index, record.block_size, record.covered).map_err(update_err)?;
+            record.checksums[index as usize] = integrity::checksum(&block);
+            changed_groups.insert(index % groups);
+        }
+
+        // the parity of a group is built up from its first block, which overwrites what was there
+        for group in changed_groups {
+            let data_blocks = group_blocks(blocks, groups, group);
+            let codec = ReedSolomon::new(data_blocks, per_group)
+                .expect("groups are laid out within reed-solomon's limits");
+            let range = record.parity_range(group);
+            for k in 0..data_blocks {
+                let index = group + k as u64 * groups;
+                let (block, _) = read_block(reader, index, record.block_size, record.covered).map_err(update_err)?;
+                if let Err(e) = codec.encode_single_sep(k, &block, &mut record.parity[range.clone()]) {
+                    return Err(ArchivalError(format!("Failed to compute the recovery record: {:?}", e)))
+                }
+            }
+            for i in range {
+                record.parity_checksums[i] = integrity::checksum(&record.parity[i]);
+            }
+        }
+
+        // the record is the same size as before, so it is written over itself
+        reader.get_mut().overwrite(record.covered, &record.to_bytes()).map_err(update_err)
+    }
+
+    /// find the recovery record through the locator before the footer
+    fn locate_recovery_record(&mut self) -> Option<RecoveryRecord> {
+        let size = self.archive_size;
+        let reader = self.archive_reader.as_mut().unwrap();
+
+        let at = size.checked_sub(Footer::SIZE as u64 + LOCATOR_SIZE)?;
+        reader.seek(SeekFrom::Start(at)).ok()?;
+        let offset = u64::from_le_bytes(read_array(reader).ok()?);
+        expect_magic(reader, RECOVERY_MAGIC).ok()?;
+        read_record_at(reader, offset, size)
+    }
+
+    /// find the recovery record through the locator before the footer, or failing
+    /// that, as damage can take the end of the archive with it, by scanning for it
+    fn find_recovery_record(&mut self) -> Result<RecoveryRecord, ArchivalError> {
+        if let Some(record) = self.locate_recovery_record() {
+            return Ok(record)
+        }
+        let size = self.archive_size;
+        let reader = self.archive_reader.as_mut().unwrap();
+
+        let mut candidates = vec![];
+        let (mut window, mut position) = (vec![], 0u64);
+        let mut buffer = vec![0; MEGABYTE as usize];
+        reader.seek(SeekFrom::Start(0)).map_err(|e| ArchivalError(format!("failed to seek within archive: {}", e)))?;
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            window.extend_from_slice(&buffer[..read]);
+            candidates.extend(window.windows(4)
+                .enumerate()
+                .filter(|(_, w)| *w == RECOVERY_MAGIC)
+                .map(|(i, _)| position + i as u64));
+
+            // keep the end of the window, in case the magic straddles two reads
+            let keep = window.len().min(3);
+            position += (window.len() - keep) as u64;
+            window.drain(..window.len() - keep);
+        }
+
+        for offset in candidates.into_iter().rev() {
+            if let Some(record) = read_record_at(reader, offset, size) {
+                return Ok(record)
+            }
+        }
+        Err(ArchivalError(
+            format!("{} has no recovery record, or it is too damaged to use", self.input.display())
+        ))
+    }
+}
+
+/// read the recovery record said to start at `offset`, which is also where its coverage ends
+fn read_record_at(reader: &mut (impl Read + Seek), offset: u64, archive_size: u64) -> Option<RecoveryRecord> {
+    reader.seek(SeekFrom::Start(offset)).ok()?;
+    RecoveryRecord::read(reader, archive_size).ok()
+        .filter(|record| record.covered == offset)
+}
@@ -0,0 +1,81 @@
+use std::io::Write;
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::directory::{DirectoryEntry, EntryKind};
+use crate::archival::files::filter::EntryFilter;
+use crate::archival::files::header::ArchiveHeader;
+
+impl Archiver {
+    /// remove every entry selected by the filters from the archive.
+    ///
+    /// the remaining entries are copied byte for byte into a new archive,
+    /// which then replaces the original
+    pub fn remove(&mut self) -> Result<u64, ArchivalError> {
+        if self.filters.is_empty() {
+            return Err(ArchivalError(
+                "no entries to remove were given, select them with -f".to_owned()
+            ))
+        }
+        let filter = EntryFilter::new(&self.filters)?;
+
+        let ArchiveHeader(files, version, _encrypted) = self.read_archive_header()?;
+        let (removed, kept): (Vec<DirectoryEntry>, Vec<DirectoryEntry>) = self.read_entries(files)?
+            .into_iter()
+            .partition(|entry| filter.matches(&entry.name));
+        if removed.is_empty() {
+            return Err(ArchivalError(
+                format!("no entries matched {}", self.filters.join(", "))
+            ))
+        }
+
+        // hard links only refer to their target's data, so it can't go while they stay
+        for entry in &kept {
+            if let EntryKind::HardLink(target) = &entry.kind {
+                if removed.iter().any(|e| &e.name == target) {
+                    return Err(ArchivalError(format!(
+                        "\"{}\" holds the data of the hard link \"{}\", remove both or neither",
+                        target, entry.name
+                    )))
+                }
+            }
+        }
+
+        let res = self.write_compacted(&kept, version + 1);
+        if res.is_err() {
+            self.remove_temporary_output();
+            return res
+        }
+
+        for entry in &removed {
+            eprintln!("removed :: {}", entry.name);
+        }
+        eprintln!(
+            "Removed {} of {} entries in {:.2}s, now at version {}",
+            removed.len(), files,
+            self.start_time.unwrap().elapsed().as_secs_f64(),
+            version + 1
+        );
+        res
+    }
+
+    /// write a new archive made up of only `kept`, then swap it into place
+    fn write_compacted(&mut self, kept: &[DirectoryEntry], version: usize) -> Result<u64, ArchivalError> {
+        self.open_archive_writer()?;
+
+        self.prepare_cipher(true)?;
+        self.write_archive_header(kept.len(), version)?;
+
+        for entry in kept {
+            self.copy_entry(entry)?;
+        }
+        self.write_directory()?;
+
+        if let Err(e) = self.archive_writer.as_mut().unwrap().flush() {
+            return Err(ArchivalError(
+                format!("Failed to flush output file: {}", e)
+            ))
+        }
+        self.replace_output()?;
+
+        Ok(self.archive_offset)
+    }
+}
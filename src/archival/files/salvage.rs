@@ -0,0 +1,386 @@
+use std::collections::{BTreeSet, HashSet};
+use std::{cmp, fs};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use crate::archival::cli::output::FmtProgress;
+use crate::archival::files::archiver::{ArchivalError, Archiver, Deferred};
+use crate::archival::files::crypto::KeySlot;
+use crate::archival::files::directory::{CentralDirectory, DirectoryEntry, EntryKind, Footer, DIRECTORY_MAGIC};
+use crate::archival::files::filter::EntryFilter;
+use crate::archival::files::header::{
+    read_array, ArchiveHeader, Header, BLOCK_MAGIC, CHUNK_MAGIC, DIR_MAGIC, FILE_MAGIC, HARD_LINK_MAGIC, SYMLINK_MAGIC
+};
+use crate::archival::files::paths;
+use crate::archival::files::stream::DESCRIBED;
+
+/// every header that can follow another within the archive, and so can be resynchronised on
+const ENTRY_MAGICS: [[u8; 4]; 6] = [
+    FILE_MAGIC, DIR_MAGIC, SYMLINK_MAGIC, HARD_LINK_MAGIC, CHUNK_MAGIC, BLOCK_MAGIC
+];
+/// how much of the archive is read at a time while scanning for the next header
+const SCAN_SIZE: usize = 64 * 1024;
+
+/// what a salvaged extraction couldn't recover
+#[derive(Default)]
+struct SalvageReport {
+    /// ranges of the archive which were skipped, as their start and end
+    skipped: Vec<(u64, u64)>,
+    /// entries which could not be extracted, with the reason why
+    lost: Vec<(String, String)>,
+}
+
+impl Archiver {
+    /// extract every entry which can still be read from a damaged archive.
+    ///
+    /// the headers are walked one after another rather than trusting the central
+    /// directory alone. when a header can't be read the archive is searched for the
+    /// next one, either at the next offset the directory knows of or, without a
+    /// readable directory, at the next header signature. entries that fail to
+    /// extract are skipped, and a report of everything lost is printed at the end
+    pub fn salvage(&mut self) -> Result<u64, ArchivalError> {
+        let mut report = SalvageReport::default();
+        let (files, encrypted) = match self.salvage_archive_header()? {
+            Some(ArchiveHeader(files, _ver, encrypted)) => (Some(files), encrypted),
+            None => {
+                report.skipped.push((0, Header::ARCHIVE_HEADER_SIZE as u64));
+                (None, self.guess_encryption()?)
+            }
+        };
+        let filter = EntryFilter::new(&self.filters)?;
+        let start = Header::ARCHIVE_HEADER_SIZE as u64 + match encrypted {
+            true => KeySlot::TABLE_SIZE as u64,
+            false => 0,
+        };
+
+        let (directory, mut end) = self.salvage_directory();
+        let mut known = BTreeSet::new();
+        if let Some(directory) = &directory {
+            known.extend(directory.entries.iter().map(|entry| entry.offset));
+            known.extend(directory.chunks.iter().map(|chunk| chunk.offset));
+            known.extend(directory.blocks.iter().map(|block| block.offset));
+            self.chunks = directory.chunks.iter().map(|chunk| (chunk.hash, chunk.offset)).collect();
+            self.blocks = directory.blocks.iter().map(|block| (block.id, block.offset)).collect();
+        } else if self.encrypt_headers {
+            eprintln!("the central directory is unreadable and the headers are encrypted, \
+                so nothing after the first damaged header can be found");
+        }
+
+        let mut deferred = Deferred::default();
+        let mut seen = HashSet::new();
+        let mut damaged_from = None;
+        let mut position = start;
+
+        while position < end {
+            let (header, next) = match self.salvage_header(position, end) {
+                Ok(found) => found,
+                // without a footer, the entries end where the directory starts
+                Err(_) if directory.is_none() && self.is_directory_at(position) => {
+                    end = position;
+                    break
+                }
+                Err(_) => {
+                    damaged_from.get_or_insert(position);
+                    position = self.next_header(position, end, &known);
+                    continue
+                }
+            };
+            if let Some(from) = damaged_from.take() {
+                report.skipped.push((from, position));
+            }
+
+            match header {
+                Header::Chunk { hash, .. } => {
+                    self.chunks.entry(hash).or_insert(position);
+                }
+                Header::Block { id, .. } => {
+                    self.blocks.entry(id).or_insert(position);
+                }
+                header => {
+                    let entry = DirectoryEntry::from_header(&header, position);
+                    seen.insert(position);
+                    if filter.matches(&entry.name) {
+                        // the header held together, so the next one is where it says
+                        if let Err(ArchivalError(e)) = self.salvage_entry(&entry, &mut deferred) {
+                            report.lost.push((entry.name, e));
+                        }
+                    }
+                }
+            }
+            position = next;
+        }
+        if let Some(from) = damaged_from {
+            report.skipped.push((from, end));
+        }
+
+        self.finish_salvage(deferred, &mut report);
+
+        // the directory names the entries whose headers were never reached
+        let unreached = directory.iter()
+            .flat_map(|directory| directory.entries.iter())
+            .filter(|entry| !seen.contains(&entry.offset) && filter.matches(&entry.name))
+            .map(|entry| (entry.name.clone(), String::from("its header is in a damaged range")));
+        report.lost.extend(unreached.collect::<Vec<(String, String)>>());
+
+        // without the archive header, the directory is the only count of the entries left
+        let files = files
+            .or(directory.as_ref().map(|directory| directory.entries.len()))
+            .unwrap_or(seen.len());
+        self.print_salvage_report(files, &report)
+    }
+
+    /// read the archive header, or `None` when it is too damaged to read.
+    /// a password that doesn't open an intact header still fails the salvage
+    fn salvage_archive_header(&mut self) -> Result<Option<ArchiveHeader>, ArchivalError> {
+        if let Err(e) = Header::read_archive(self.archive_reader.as_mut().unwrap()) {
+            eprintln!("the archive header is unreadable, searching for the first entry instead\nreason: {}", e);
+            return Ok(None)
+        }
+        self.seek_archive(0)?;
+        self.read_archive_header().map(Some)
+    }
+
+    /// tell whether an archive whose header was lost is encrypted, reading its key slots if so.
+    ///
+    /// the entries of a plain archive start straight after the archive header, while
+    /// those of an encrypted one follow its key slots. an encrypted archive with no
+    /// header signature anywhere after its slots is taken to have its headers encrypted
+    fn guess_encryption(&mut self) -> Result<bool, ArchivalError> {
+        let start = Header::ARCHIVE_HEADER_SIZE as u64;
+        let first = match self.archive_size > start {
+            true => self.scan_for_header(start, self.archive_size),
+            false => None,
+        };
+        if first == Some(start) {
+            return Ok(false)
+        }
+
+        self.seek_archive(start)?;
+        let slots = KeySlot::read_table(self.archive_reader.as_mut().unwrap());
+        if !slots.is_ok_and(|slots| !slots.is_empty()) {
+            return Ok(false)
+        }
+        self.encrypt_headers = first.is_none();
+        self.seek_archive(start)?;
+        self.read_key_slots()?;
+        Ok(true)
+    }
+
+    /// read the central directory if it survived, along with where the entries end.
+    /// without a directory every byte up to the end of the archive is searched
+    fn salvage_directory(&mut self) -> (Option<CentralDirectory>, u64) {
+        let directory = match self.read_directory() {
+            Ok(directory) => directory,
+            Err(ArchivalError(e)) => {
+                eprintln!("the central directory is unreadable, searching for every header instead\nreason: {}", e);
+                None
+            }
+        };
+
+        let reader = self.archive_reader.as_mut().unwrap();
+        let footer = reader.seek(SeekFrom::End(-(Footer::SIZE as i64)))
+            .ok()
+            .and_then(|_| Footer::read(reader).ok());
+        match footer {
+            Some(footer) if footer.directory_offset < self.archive_size => (directory, footer.directory_offset),
+            _ => (None, self.archive_size),
+        }
+    }
+
+    /// read the header at `position`, returning it along with where the next one should start
+    fn salvage_header(&mut self, position: u64, end: u64) -> Result<(Header, u64), ArchivalError> {
+        self.seek_archive(position)?;
+        let header = self.read_entry_header()?;
+        let data_start = self.archive_reader.as_mut().unwrap()
+            .stream_position()
+            .map_err(|e| ArchivalError(format!("failed to seek within archive: {}", e)))?;
+
+        let data_size = match &header {
+            // a described file is walked to its descriptor to find where it ends
+            Header::File { method, .. } if method & DESCRIBED != 0 => {
+                let mut entry = DirectoryEntry::from_header(&header, position);
+                self.measure_described(&mut entry)?;
+                entry.compressed_size
+            }
+            Header::File { compressed_size, .. } | Header::Block { compressed_size, .. } => *compressed_size,
+            Header::Chunk { compressed_size, .. } => *compressed_size as u64,
+            _ => 0,
+        };
+        match data_start.checked_add(data_size) {
+            Some(next) if next <= end => Ok((header, next)),
+            _ => Err(ArchivalError(
+                format!("the header at offset {} runs past the end of the entries", position)
+            ))
+        }
+    }
+
+    /// whether the central directory starts at `position`
+    fn is_directory_at(&mut self, position: u64) -> bool {
+        self.seek_archive(position).is_ok() &&
+            read_array(self.archive_reader.as_mut().unwrap()).ok() == Some(DIRECTORY_MAGIC)
+    }
+
+    /// find where the next header after the damaged one at `position` starts,
+    /// or `end` when there are no more
+    fn next_header(&mut self, position: u64, end: u64, known: &BTreeSet<u64>) -> u64 {
+        if !known.is_empty() {
+            return known.range(position + 1..end).next().copied().unwrap_or(end)
+        }
+        if self.encrypt_headers {
+            return end
+        }
+        self.scan_for_header(position + 1, end).unwrap_or(end)
+    }
+
+    /// search the archive from `from` for the signature of any header
+    fn scan_for_header(&mut self, from: u64, end: u64) -> Option<u64> {
+        self.seek_archive(from).ok()?;
+        let reader = self.archive_reader.as_mut().unwrap();
+
+        // the last few bytes of each read are kept, so a signature split between two is found
+        let mut window_start = from;
+        let mut window = Vec::with_capacity(SCAN_SIZE + 3);
+        loop {
+            let remaining = end - window_start - window.len() as u64;
+            if remaining == 0 {
+                return None
+            }
+            let mut buffer = vec![0; cmp::min(SCAN_SIZE as u64, remaining) as usize];
+            reader.read_exact(&mut buffer).ok()?;
+            window.extend_from_slice(&buffer);
+
+            let found = window.windows(4)
+                .position(|bytes| ENTRY_MAGICS.iter().any(|magic| magic.as_slice() == bytes));
+            if let Some(i) = found {
+                return Some(window_start + i as u64)
+            }
+            let keep = window.len().saturating_sub(3);
+            window.drain(..keep);
+            window_start += keep as u64;
+        }
+    }
+
+    /// extract a single entry, leaving links and the metadata of directories until the end
+    fn salvage_entry(&mut self, entry: &DirectoryEntry, deferred: &mut Deferred) -> Result<(), ArchivalError> {
+        let path = paths::entry_path(&self.output, &entry.name)?;
+        if entry.kind != EntryKind::File {
+            self.read_entry_data(entry)?;
+        }
+
+        match &entry.kind {
+            EntryKind::Directory => {
+                if let Err(e) = fs::create_dir_all(&path) {
+                    return Err(ArchivalError(
+                        format!("Could not create directory \"{}\": {}", path.display(), e)
+                    ))
+                }
+                deferred.directories.push((path, entry.clone()));
+                return Ok(())
+            }
+            EntryKind::Symlink(_) => {
+                deferred.symlinks.push((path, entry.clone()));
+                return Ok(())
+            }
+            EntryKind::HardLink(_) => {
+                deferred.hard_links.push((path, entry.clone()));
+                return Ok(())
+            }
+            EntryKind::File => {}
+        }
+
+        self.extract_file(entry, &path)?;
+        self.restore_metadata(&path, entry)?;
+        deferred.files.insert(entry.name.clone());
+        self.format_progress(format!("{}", path.display()));
+
+        // logging
+        self.bytes_processed += entry.compressed_size as usize;
+        self.files_processed += 1;
+        self.speed = (self.bytes_processed as f64 /
+            self.start_time.unwrap().elapsed().as_secs_f64()
+        ) as usize;
+        Ok(())
+    }
+
+    /// create the links and finish the directories of every entry that was salvaged
+    fn finish_salvage(&mut self, deferred: Deferred, report: &mut SalvageReport) {
+        let Deferred { files, mut directories, symlinks, hard_links } = deferred;
+
+        for (path, entry) in hard_links {
+            let res = match &entry.kind {
+                EntryKind::HardLink(target) if files.contains(target) => {
+                    self.extract_hard_link(&path, target, &files, &[])
+                }
+                EntryKind::HardLink(target) => Err(ArchivalError(
+                    format!("it links to \"{}\", which was lost", target)
+                )),
+                _ => unreachable!("only hard links are deferred as hard links"),
+            };
+            match res {
+                Ok(_) => self.files_processed += 1,
+                Err(ArchivalError(e)) => report.lost.push((entry.name, e)),
+            }
+        }
+
+        let links = symlinks.iter()
+            .map(|(_, entry)| PathBuf::from(&entry.name))
+            .collect::<HashSet<PathBuf>>();
+        for (path, entry) in symlinks {
+            let target = match &entry.kind {
+                EntryKind::Symlink(target) => target,
+                _ => unreachable!("only symbolic links are deferred as symbolic links"),
+            };
+            if let Err(ArchivalError(e)) = paths::check_link_target(&entry.name, target, &links) {
+                report.lost.push((entry.name, e));
+                continue
+            }
+            if let Some(parent) = path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            match Self::create_symlink(target, &path) {
+                Ok(_) => self.files_processed += 1,
+                Err(e) => report.lost.push((
+                    entry.name, format!("Could not create link \"{}\": {}", path.display(), e)
+                )),
+            }
+        }
+
+        // deepest directories first, so parents are only finished once their children are
+        directories.sort_by(|(a, _), (b, _)| b.cmp(a));
+        for (path, entry) in directories {
+            match self.restore_metadata(&path, &entry) {
+                Ok(_) => self.files_processed += 1,
+                Err(ArchivalError(e)) => report.lost.push((entry.name, e)),
+            }
+        }
+    }
+
+    /// print what was skipped and lost, failing the extraction if anything was
+    fn print_salvage_report(&self, files: usize, report: &SalvageReport) -> Result<u64, ArchivalError> {
+        eprintln!(
+            "Salvaged {} of {} entries from {} in {:.2}s",
+            self.files_processed, files, self.input.display(),
+            self.start_time.unwrap().elapsed().as_secs_f64(),
+        );
+        if report.skipped.is_empty() && report.lost.is_empty() {
+            return Ok(self.archive_size)
+        }
+
+        if !report.skipped.is_empty() {
+            eprintln!("Skipped {} damaged ranges:", report.skipped.len());
+            for (start, end) in &report.skipped {
+                eprintln!("  {}..{} ({} bytes)", start, end, end - start);
+            }
+        }
+        if !report.lost.is_empty() {
+            eprintln!("Lost {} entries:", report.lost.len());
+            for (name, reason) in &report.lost {
+                eprintln!("  {}: {}", name, reason);
+            }
+        }
+
+        Err(ArchivalError(format!(
+            "{} is damaged, {} entries were lost and {} ranges skipped",
+            self.input.display(), report.lost.len(), report.skipped.len()
+        )))
+    }
+}
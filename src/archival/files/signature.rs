@@ -0,0 +1,270 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::crypto::KeySlot;
+use crate::archival::files::directory::Footer;
+use crate::archival::files::header::{expect_magic, read_array, ArchiveHeader, Header, HeaderError};
+use crate::archival::files::keys;
+
+/// signature found at the start of a signature trailer
+pub const SIGNATURE_MAGIC: [u8; 4] = *b"ARKG";
+
+/// a range of the archive and the sha-256 of its bytes when it was signed
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedRange {
+    pub offset: u64,
+    pub length: u64,
+    pub digest: [u8; 32],
+}
+
+/// proof of who wrote an archive, stored between the central directory and the footer.
+///
+/// every byte before it is covered, split into a range per header so a modified
+/// entry can be told apart from the rest of the archive. the key slots are left out,
+/// so sharing or re-keying a signed archive keeps its signature
+///
+/// # Layout
+/// ```ignore
+/// magic           [u8; 4]  "ARKG"
+/// signer          [u8; 32] ed25519 public key of whoever signed the archive
+/// range count     u64
+/// // for each range, the last being the central directory
+/// offset          u64
+/// length          u64
+/// digest          [u8; 32] sha-256 of the bytes in the range
+///
+/// signature       [u8; 64] ed25519 signature of every byte above
+/// ```
+pub struct SignatureTrailer {
+    pub signer: [u8; 32],
+    pub ranges: Vec<SignedRange>,
+    pub signature: [u8; 64],
+}
+
+impl SignatureTrailer {
+    /// sign the digests of an archive's ranges with `key`
+    pub fn sign(key: &SigningKey, ranges: Vec<SignedRange>) -> SignatureTrailer {
+        let signer = key.verifying_key().to_bytes();
+        let signature = key.sign(&Self::signed_bytes(&signer, &ranges)).to_bytes();
+        SignatureTrailer { signer, ranges, signature }
+    }
+
+    /// whether the trailer was signed by `trusted` and hasn't been changed since
+    pub fn is_signed_by(&self, trusted: &VerifyingKey) -> bool {
+        let signature = Signature::from_bytes(&self.signature);
+        self.signer == trusted.to_bytes() &&
+            trusted.verify_strict(&Self::signed_bytes(&self.signer, &self.ranges), &signature).is_ok()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Self::signed_bytes(&self.signer, &self.ranges);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// read a trailer from the current position of `reader`
+    pub fn read(reader: &mut impl Read) -> Result<SignatureTrailer, HeaderError> {
+        expect_magic(reader, SIGNATURE_MAGIC)?;
+        let signer = read_array(reader)?;
+
+        let count = u64::from_le_bytes(read_array(reader)?);
+        let mut ranges = vec![];
+        for _ in 0..count {
+            ranges.push(SignedRange {
+                offset: u64::from_le_bytes(read_array(reader)?),
+                length: u64::from_le_bytes(read_array(reader)?),
+                digest: read_array(reader)?,
+            });
+        }
+
+        Ok(SignatureTrailer { signer, ranges, signature: read_array(reader)? })
+    }
+
+    fn signed_bytes(signer: &[u8; 32], ranges: &[SignedRange]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + 32 + 8 + ranges.len() * 48);
+        bytes.extend_from_slice(&SIGNATURE_MAGIC);
+        bytes.extend_from_slice(signer);
+        bytes.extend_from_slice(&(ranges.len() as u64).to_le_bytes());
+        for range in ranges {
+            bytes.extend_from_slice(&range.offset.to_le_bytes());
+            bytes.extend_from_slice(&range.length.to_le_bytes());
+            bytes.extend_from_slice(&range.digest);
+        }
+        bytes
+    }
+}
+
+/// hash `length` bytes of `reader` starting at `offset`
+fn digest_range(reader: &mut (impl Read + Seek), offset: u64, length: u64) -> io::Result<[u8; 32]> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut hasher = Sha256::new();
+    let copied = io::copy(&mut reader.take(length), &mut hasher)?;
+    if copied != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the range ends past the end of the archive"))
+    }
+    Ok(hasher.finalize().into())
+}
+
+impl Archiver {
+    /// sign the archive written so far, which must end with its central directory,
+    /// by reading it back and writing the signature trailer after it
+    pub(crate) fn write_signature(&mut self, directory_offset: u64) -> Result<(), ArchivalError> {
+        let key = match &self.signing_key {
+            Some(key) => key.clone(),
+            None => return Ok(())
+        };
+        let sign_err = |e| ArchivalError(format!("Failed to sign the archive: {}", e));
+
+        let mut reader = self.read_back_output().map_err(sign_err)?;
+
+        let mut ranges = vec![];
+        for (offset, length) in self.signed_ranges(directory_offset) {
+            let digest = digest_range(&mut reader, offset, length).map_err(sign_err)?;
+            ranges.push(SignedRange { offset, length, digest });
+        }
+
+        let trailer = SignatureTrailer::sign(&key, ranges);
+        self.write_archive_bytes(trailer.to_bytes().as_slice()).map_err(sign_err)
+    }
+
+    /// split the archive written so far into the ranges its signature covers, one for the
+    /// archive header, then one from each entry, chunk or block header up to the next
+    fn signed_ranges(&self, directory_offset: u64) -> Vec<(u64, u64)> {
+        let header_end = Header::ARCHIVE_HEADER_SIZE as u64;
+        let slots = match self.cipher {
+            Some(_) => KeySlot::TABLE_SIZE as u64,
+            None => 0,
+        };
+
+        let mut starts = BTreeSet::from([header_end + slots, directory_offset]);
+        starts.extend(self.directory.entries.iter().map(|entry| entry.offset));
+        starts.extend(self.directory.chunks.iter().map(|chunk| chunk.offset));
+        starts.extend(self.directory.blocks.iter().map(|block| block.offset));
+
+        let starts = starts.into_iter().collect::<Vec<u64>>();
+        let mut ranges = vec![(0, header_end)];
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(self.archive_offset);
+            ranges.push((*start, end - start));
+        }
+        ranges
+    }
+
+    /// check the archive was signed by the key given with `--trust`,
+    /// reporting every entry which was modified after it was signed
+    pub fn verify_signature(&mut self) -> Result<u64, ArchivalError> {
+        let trusted = match &self.trust {
+            Some(path) => keys::read_verifying_key(path)?,
+            None => return Err(ArchivalError(String::from(
+                "give the public key of whoever should have signed the archive with --trust"
+            )))
+        };
+        let (footer, trailer) = self.read_signature()?;
+
+        println!("Checking the signature of {}", self.input.display());
+        if !trailer.is_signed_by(&trusted) {
+            return Err(ArchivalError(match trailer.signer == trusted.to_bytes() {
+                true => format!("the signature of {} is invalid", self.input.display()),
+                false => format!(
+                    "{} was signed by a different key than \"{}\"",
+                    self.input.display(), self.trust.as_ref().unwrap().display()
+                ),
+            }))
+        }
+
+        let reader = self.archive_reader.as_mut().unwrap();
+        let mut modified = trailer.ranges.iter()
+            .filter(|range| digest_range(reader, range.offset, range.length).ok() != Some(range.digest))
+            .map(|range| range.offset)
+            .collect::<Vec<u64>>();
+
+        // readers must be pointed at the directory which was signed
+        let directory = trailer.ranges.last().map(|range| (range.offset, range.length));
+        if directory != Some((footer.directory_offset, footer.directory_size)) {
+            modified.push(footer.directory_offset);
+        }
+
+        if modified.is_empty() {
+            println!(
+                "Good signature from \"{}\", covering {} ranges of the archive",
+                self.trust.as_ref().unwrap().display(), trailer.ranges.len()
+            );
+            return Ok(self.archive_size)
+        }
+
+        let names = self.describe_ranges(footer.directory_offset, !modified.contains(&footer.directory_offset));
+        let modified = modified.iter()
+            .map(|offset| names.get(offset).cloned().unwrap_or(format!("the data at offset {}", offset)))
+            .collect::<Vec<String>>();
+        for name in &modified {
+            println!("  MODIFIED  {}", name);
+        }
+        Err(ArchivalError(
+            format!("{} was modified after it was signed: {}", self.input.display(), modified.join(", "))
+        ))
+    }
+
+    /// read the footer and the signature trailer before it
+    fn read_signature(&mut self) -> Result<(Footer, SignatureTrailer), ArchivalError> {
+        let unsigned = || ArchivalError(format!("{} isn't signed", self.input.display()));
+        let read_err = |e: io::Error| ArchivalError(
+            format!("Could not read the signature of {}\nreason: {}", self.input.display(), e)
+        );
+        let footer_start = match self.archive_size.checked_sub(Footer::SIZE as u64) {
+            Some(start) => start,
+            None => return Err(unsigned())
+        };
+
+        let reader = self.archive_reader.as_mut().unwrap();
+        reader.seek(SeekFrom::Start(footer_start)).map_err(read_err)?;
+        let footer = Footer::read(reader).map_err(|_| unsigned())?;
+
+        let trailer_start = match footer.directory_offset.checked_add(footer.directory_size) {
+            Some(start) if start < footer_start => start,
+            _ => return Err(unsigned())
+        };
+        reader.seek(SeekFrom::Start(trailer_start)).map_err(read_err)?;
+        let mut trailer = vec![0; (footer_start - trailer_start) as usize];
+        reader.read_exact(&mut trailer).map_err(read_err)?;
+        // a recovery record can follow the directory without a signature
+        if !trailer.starts_with(&SIGNATURE_MAGIC) {
+            return Err(unsigned())
+        }
+
+        match SignatureTrailer::read(&mut trailer.as_slice()) {
+            Ok(trailer) => Ok((footer, trailer)),
+            Err(e) => Err(ArchivalError(
+                format!("the signature of {} is unreadable: {}", self.input.display(), e)
+            ))
+        }
+    }
+
+    /// name what starts at each signed range, as far as the archive can be read.
+    ///
+    /// a modified directory can't be trusted to name anything, nor can one whose
+    /// headers are encrypted without the key, so those ranges are left to their offsets
+    fn describe_ranges(&mut self, directory_offset: u64, directory_intact: bool) -> HashMap<u64, String> {
+        let mut names = HashMap::from([
+            (0, String::from("the archive header")),
+            (directory_offset, String::from("the central directory")),
+        ]);
+        if !directory_intact {
+            return names
+        }
+
+        // naming entries is only a courtesy, so never ask for a password to do it
+        self.prompt = false;
+        let entries = self.seek_archive(0)
+            .and_then(|_| self.read_archive_header())
+            .and_then(|ArchiveHeader(files, _, _)| self.read_entries(files));
+        if let Ok(entries) = entries {
+            names.extend(entries.into_iter().map(|entry| (entry.offset, format!("\"{}\"", entry.name))));
+            names.extend(self.chunks.values().map(|offset| (*offset, format!("the deduplicated chunk at offset {}", offset))));
+            names.extend(self.blocks.iter().map(|(id, offset)| (*offset, format!("solid block {}", id))));
+        }
+        names
+    }
+}
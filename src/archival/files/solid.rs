@@ -0,0 +1,268 @@
+use std::fs;
+use std::io::{copy, Read};
+use std::path::PathBuf;
+use crate::archival::compression::file_compressor::Compressor;
+use crate::archival::cli::output::FmtProgress;
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::directory::{BlockEntry, DirectoryEntry, EntryKind};
+use crate::archival::files::header::Header;
+use crate::archival::files::integrity;
+use crate::constants::MEGABYTE;
+
+/// method bit marking a file stored inside a solid block rather than on its own
+pub const SOLID: u8 = 1 << 1;
+/// the solid block size used when `--solid` isn't given one
+pub const DEFAULT_BLOCK_SIZE: u64 = 4 * MEGABYTE;
+/// size in bytes of a solid file's data, the block id and its offset within the block,
+/// before it is encrypted
+const BLOCK_REF_SIZE: usize = 8 + 8;
+
+/// small files waiting to be compressed together as a single block
+#[derive(Default)]
+pub struct SolidBlock {
+    /// the method every file in the block was profiled with
+    pub method: u8,
+    pub data: Vec<u8>,
+    /// each file in the block, with where its data starts and its checksum
+    pub files: Vec<(PathBuf, u64, u32)>,
+}
+
+impl Archiver {
+    /// add a file to the pending solid block, writing the block out first
+    /// when the file would not fit or was profiled with a different method.
+    ///
+    /// files at least as large as a block gain nothing from sharing one,
+    /// so the caller should archive those on their own
+    pub(crate) fn archive_solid_file(
+        &mut self, method: u8, path: &PathBuf, block_size: u64
+    ) -> Result<u64, ArchivalError>
+    {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) => return Err(ArchivalError(
+                format!("Could not read input file: \"{}\"\nreason: {}", path.display(), e)
+            ))
+        };
+
+        let block = &self.solid_block;
+        let full = block.data.len() as u64 + data.len() as u64 > block_size;
+        if !block.files.is_empty() && (block.method != method || full) {
+            self.flush_solid_block()?;
+        }
+
+        let block = &mut self.solid_block;
+        block.method = method;
+        block.files.push((path.clone(), block.data.len() as u64, integrity::checksum(&data)));
+        block.data.extend(data);
+        Ok(0)
+    }
+
+    /// compress and write the pending solid block, followed by the header of each file inside it
+    pub(crate) fn flush_solid_block(&mut self) -> Result<(), ArchivalError> {
+        if self.solid_block.files.is_empty() {
+            return Ok(())
+        }
+        let block = std::mem::take(&mut self.solid_block);
+
+        // ids only need to be unique within the archive
+        let id = self.blocks.keys()
+            .chain(self.written_blocks.iter())
+            .max()
+            .map_or(0, |id| id + 1);
+
+        let decompressed_size = block.data.len() as u64;
+        let checksum = integrity::checksum(&block.data);
+        let compressed = Compressor::new(block.data, block.method).compress();
+        let header = Header::Block {
+            id,
+            method: block.method,
+            compressed_size: self.sealed_len(compressed.len()) as u64,
+            decompressed_size,
+            checksum,
+        };
+        let compressed = self.seal_data(compressed, &header.to_bytes());
+
+        self.directory.blocks.push(BlockEntry { id, offset: self.archive_offset });
+        self.written_blocks.insert(id);
+        let write_res = self.write_archive_bytes(self.encode_header(&header).as_slice())
+            .and_then(|_| self.write_archive_bytes(compressed.as_slice()));
+        if let Err(e) = write_res {
+            return Err(ArchivalError(
+                format!("Failed to write solid block: {}", e)
+            ))
+        }
+
+        for (path, offset, checksum) in block.files {
+            let mut reference = Vec::with_capacity(BLOCK_REF_SIZE);
+            reference.extend_from_slice(&id.to_le_bytes());
+            reference.extend_from_slice(&offset.to_le_bytes());
+            let header = self.build_file_header(
+                &path, self.sealed_len(reference.len()), block.method | SOLID, checksum
+            )?;
+            let reference = self.seal_data(reference, &header.to_bytes());
+            self.write_entry_header(&header)?;
+            if let Err(e) = self.write_archive_bytes(reference.as_slice()) {
+                return Err(ArchivalError(
+                    format!("Failed to write file data: {}", e)
+                ))
+            }
+
+            // logging
+            self.files_processed += 1;
+            if let Header::File { decompressed_size, .. } = header {
+                self.bytes_processed += decompressed_size as usize;
+            }
+            self.speed = (self.bytes_processed as f64 /
+                self.start_time.unwrap().elapsed().as_secs_f64()
+            ) as usize;
+
+            self.format_progress(format!("{}", path.display()));
+        }
+        Ok(())
+    }
+
+    /// copy the block holding a solid entry into the output archive, unless it's already there
+    pub(crate) fn copy_entry_block(&mut self, entry: &DirectoryEntry) -> Result<(), ArchivalError> {
+        if entry.kind != EntryKind::File || entry.method & SOLID == 0 {
+            return Ok(())
+        }
+
+        self.seek_archive(self.entry_data_offset(entry))?;
+        let mut reference = vec![0; entry.compressed_size as usize];
+        if let Err(e) = self.archive_reader.as_mut().unwrap().read_exact(&mut reference) {
+            return Err(ArchivalError(
+                format!("failed to read the block reference of \"{}\": {}", entry.name, e)
+            ))
+        }
+        let reference = self.open_data(reference, &entry.header().to_bytes(), &entry.name)?;
+        let (id, _) = parse_block_ref(&entry.name, &reference)?;
+        if self.written_blocks.contains(&id) {
+            return Ok(())
+        }
+
+        let offset = self.block_offset(id, &entry.name)?;
+        self.seek_archive(offset)?;
+        let compressed_size = match self.read_entry_header()? {
+            Header::Block { id: found, compressed_size, .. } if found == id => compressed_size,
+            _ => return Err(ArchivalError(
+                format!("expected the block holding \"{}\" at offset {}", entry.name, offset)
+            ))
+        };
+        self.seek_archive(offset)?;
+
+        let len = Header::BLOCK_HEADER_SIZE as u64 + self.header_overhead() + compressed_size;
+        let mut source = self.archive_reader.as_mut().unwrap().take(len);
+        let written = match copy(&mut source, self.archive_writer.as_mut().unwrap()) {
+            Ok(written) => written,
+            Err(e) => return Err(ArchivalError(
+                format!("Failed to copy the block of \"{}\" into the new archive: {}", entry.name, e)
+            ))
+        };
+        if written != len {
+            return Err(ArchivalError(
+                format!("the block of \"{}\" is truncated in the existing archive", entry.name)
+            ))
+        }
+
+        self.directory.blocks.push(BlockEntry { id, offset: self.archive_offset });
+        self.written_blocks.insert(id);
+        self.archive_offset += written;
+        Ok(())
+    }
+
+    /// read the contents of a solid file out of its block.
+    ///
+    /// the last block decompressed is kept, so extracting the files of
+    /// a block one after another only decompresses it once
+    pub(crate) fn read_solid_data(
+        &mut self, name: &str, reference: &[u8], size: u64
+    ) -> Result<Vec<u8>, ArchivalError>
+    {
+        let (id, offset) = parse_block_ref(name, reference)?;
+        if self.solid_cache.as_ref().map(|(cached, _)| *cached) != Some(id) {
+            let block = self.read_block(name, id)?;
+            self.solid_cache = Some((id, block));
+        }
+
+        let block = &self.solid_cache.as_ref().unwrap().1;
+        let data = match offset.checked_add(size) {
+            Some(end) if end <= block.len() as u64 => block[offset as usize..end as usize].to_vec(),
+            _ => return Err(ArchivalError(
+                format!("\"{}\" points past the end of its solid block", name)
+            ))
+        };
+        self.release_block(id, size);
+        Ok(data)
+    }
+
+    /// count a solid file passed over in a stream without being read, so its block
+    /// can still be let go of. a reference which can't be read leaves the block held
+    pub(crate) fn skip_solid_file(&mut self, entry: &DirectoryEntry) {
+        let mut reference = vec![0; entry.compressed_size as usize];
+        if self.archive_reader.as_mut().unwrap().read_exact(&mut reference).is_err() {
+            return
+        }
+        let id = self.open_data(reference, &entry.header().to_bytes(), &entry.name)
+            .and_then(|reference| parse_block_ref(&entry.name, &reference));
+        if let Ok((id, _)) = id {
+            self.release_block(id, entry.decompressed_size);
+        }
+    }
+
+    /// count `size` bytes as read out of a solid block held from a stream,
+    /// letting go of the block once every file in it has been read
+    fn release_block(&mut self, id: u64, size: u64) {
+        let offset = match self.blocks.get(&id) {
+            Some(offset) => *offset,
+            None => return
+        };
+        if let Some(held) = self.held.get_mut(&offset) {
+            held.unused = held.unused.saturating_sub(size);
+            if held.unused == 0 {
+                self.held.remove(&offset);
+            }
+        }
+    }
+
+    /// read and decompress a whole solid block, ensuring it matches its checksum
+    fn read_block(&mut self, name: &str, id: u64) -> Result<Vec<u8>, ArchivalError> {
+        let offset = self.block_offset(id, name)?;
+        let (header, buffer) = self.read_shared(offset, name)?;
+        let (method, decompressed_size, checksum) = match header {
+            Header::Block { id: found, method, decompressed_size, checksum, .. }
+                if found == id => (method, decompressed_size, checksum),
+            _ => return Err(ArchivalError(
+                format!("expected the block holding \"{}\" at offset {}", name, offset)
+            ))
+        };
+        let buffer = self.open_data(buffer, &header.to_bytes(), name)?;
+
+        let data = Compressor::new(buffer, method).decompress();
+        if data.len() as u64 != decompressed_size || integrity::checksum(&data) != checksum {
+            return Err(ArchivalError(
+                format!("\"{}\" is corrupt: the solid block at offset {} does not match its checksum", name, offset)
+            ))
+        }
+        Ok(data)
+    }
+
+    fn block_offset(&self, id: u64, used_by: &str) -> Result<u64, ArchivalError> {
+        match self.blocks.get(&id) {
+            Some(offset) => Ok(*offset),
+            None => Err(ArchivalError(
+                format!("\"{}\" is in a solid block which is missing from the archive", used_by)
+            ))
+        }
+    }
+}
+
+/// split the data of a solid file into its block id and offset within the block
+fn parse_block_ref(name: &str, reference: &[u8]) -> Result<(u64, u64), ArchivalError> {
+    match reference.split_first_chunk::<8>() {
+        Some((id, offset)) if offset.len() == 8 => Ok((
+            u64::from_le_bytes(*id),
+            u64::from_le_bytes(offset.try_into().unwrap()),
+        )),
+        _ => Err(ArchivalError(format!("\"{}\" has an invalid solid block reference", name)))
+    }
+}
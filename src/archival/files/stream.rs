@@ -0,0 +1,321 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::archival::cli::output::FmtProgress;
+use crate::archival::compression::file_compressor::{Compressor, BLOCK_SIZE};
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::crypto;
+use crate::archival::files::crypto::Cipher;
+use crate::archival::files::directory::DirectoryEntry;
+use crate::archival::files::header::{read_array, Header};
+use crate::archival::files::integrity::ChecksumWriter;
+use crate::archival::files::volume::is_stdio;
+
+/// method bit of a file whose header leaves its compressed size and checksum as zero,
+/// giving them in a descriptor after its blocks instead, as the archive it was written
+/// to couldn't be gone back over to fill them in
+pub const DESCRIBED: u8 = 1 << 2;
+/// size in bytes of the descriptor after the blocks of a described file
+pub const DESCRIPTOR_SIZE: u64 = 8 + 4;
+/// no block is stored in more than this, however badly its data compressed
+const MAX_BLOCK_LEN: u64 = 4 * BLOCK_SIZE;
+
+/// the size, compressed size and checksum of a file, from one pass over it
+#[derive(Debug)]
+pub(crate) struct BlockSums {
+    pub size: u64,
+    pub compressed: u64,
+    pub checksum: u32,
+}
+
+/// the context a block of a file is sealed with: the file's header as written without
+/// encryption and the block's index, so blocks can't be moved between files or reordered
+/// and nothing in the header can be changed. `header` is the header left unmeasured,
+/// as the compressed size and checksum are only known once every block is written
+fn block_context(header: &[u8], index: u64) -> Vec<u8> {
+    let mut context = header.to_vec();
+    context.extend_from_slice(&index.to_le_bytes());
+    context
+}
+
+/// the header of a file as it is before its blocks are written,
+/// with its compressed size and checksum left as zero
+pub(crate) fn unmeasured(header: &Header) -> Header {
+    match header.clone() {
+        Header::File { name, method, decompressed_size, metadata, .. } => Header::File {
+            name, method, compressed_size: 0, decompressed_size, checksum: 0, metadata
+        },
+        header => header,
+    }
+}
+
+/// read the compressed size and checksum given after the blocks of a described file
+fn read_descriptor(reader: &mut impl Read) -> Result<(u64, u32), ArchivalError> {
+    Ok((u64::from_le_bytes(read_array(reader)?), u32::from_le_bytes(read_array(reader)?)))
+}
+
+impl Archiver {
+    /// archive a file as a run of blocks, each compressed and sealed on its own.
+    ///
+    /// the file is read once, and only a single block of it is ever held in memory.
+    /// its header comes before the data, so is written before its compressed size and
+    /// checksum are known, and then written over once the archive is complete. an archive
+    /// written to stdout can't be gone back over, so its files are `DESCRIBED` instead
+    ///
+    /// # Layout
+    /// ```ignore
+    /// // for each block of BLOCK_SIZE bytes, the last of which may be shorter
+    /// compressed length   u32
+    /// compressed block    [u8; compressed length]
+    ///
+    /// // only for a described file
+    /// compressed size     u64      of the blocks and the descriptor
+    /// checksum            u32
+    /// ```
+    pub(crate) fn archive_compressed_file(
+        &mut self, method: u8, path: &PathBuf
+    ) -> Result<u64, ArchivalError>
+    {
+        let (header, offset) = self.start_compressed_file(method, path)?;
+        let written = self.compress_blocks(method, path, &header.to_bytes())?;
+        let compressed = self.measure_file(path, header, offset, written)?;
+        self.file_written(path, compressed);
+        Ok(0)
+    }
+
+    /// write the header of a file before any of its blocks, with its compressed size and
+    /// checksum left to be measured, returning it and where it was written
+    pub(crate) fn start_compressed_file(
+        &mut self, method: u8, path: &Path
+    ) -> Result<(Header, u64), ArchivalError>
+    {
+        // a stream can't go back to fill in the header, so the file is described after its blocks
+        let method = match is_stdio(&self.output) {
+            true => method | DESCRIBED,
+            false => method,
+        };
+        let offset = self.archive_offset;
+        let header = self.build_file_header(path, 0, method, 0)?;
+        self.write_entry_header(&header)?;
+        Ok((header, offset))
+    }
+
+    /// fill in the compressed size and checksum of a file once every block of it is
+    /// written, in a descriptor straight after them for a described file, otherwise
+    /// in its header once the archive is complete. returns the space its data took up
+    pub(crate) fn measure_file(
+        &mut self, path: &Path, header: Header, offset: u64, written: BlockSums
+    ) -> Result<u64, ArchivalError>
+    {
+        let (name, method, decompressed_size, metadata) = match header {
+            Header::File { name, method, decompressed_size, metadata, .. } => (name, method, decompressed_size, metadata),
+            _ => unreachable!("only files are measured"),
+        };
+        if written.size != decompressed_size {
+            return Err(ArchivalError(
+                format!("\"{}\" changed while it was being archived", path.display())
+            ))
+        }
+
+        let mut compressed_size = written.compressed;
+        if method & DESCRIBED != 0 {
+            compressed_size += DESCRIPTOR_SIZE;
+            let mut descriptor = compressed_size.to_le_bytes().to_vec();
+            descriptor.extend_from_slice(&written.checksum.to_le_bytes());
+            if let Err(e) = self.write_archive_bytes(&descriptor) {
+                return Err(ArchivalError(format!("Failed to write file data: {}", e)))
+            }
+        }
+
+        let header = Header::File {
+            name, method, compressed_size, decompressed_size, checksum: written.checksum, metadata
+        };
+        if method & DESCRIBED == 0 {
+            self.patches.push((offset, self.encode_header(&header)));
+        }
+        *self.directory.entries.last_mut().unwrap() = DirectoryEntry::from_header(&header, offset);
+        Ok(compressed_size)
+    }
+
+    /// write every header left unmeasured over with its measured one, before anything
+    /// reads the archive back. each header is the same size either way, so nothing moves
+    pub(crate) fn patch_headers(&mut self) -> Result<(), ArchivalError> {
+        if self.patches.is_empty() {
+            return Ok(())
+        }
+        let write_err = |e: io::Error| ArchivalError(format!("Failed to write file header: {}", e));
+        let mut output = self.read_back_output().map_err(write_err)?;
+        for (offset, header) in std::mem::take(&mut self.patches) {
+            output.patch(offset, &header).map_err(write_err)?;
+        }
+        Ok(())
+    }
+
+    /// count a file which was written to the archive and report the progress made
+    pub(crate) fn file_written(&mut self, path: &Path, compressed: u64) {
+        self.files_processed += 1;
+        self.bytes_processed += compressed as usize;
+        self.speed = (self.bytes_processed as f64 /
+            self.start_time.unwrap().elapsed().as_secs_f64()
+        ) as usize;
+
+        self.format_progress(format!("{}", path.display()));
+    }
+
+    /// compress a file block by block, writing each block to the output archive
+    /// sealed against the file's unmeasured header
+    fn compress_blocks(
+        &mut self, method: u8, path: &PathBuf, header: &[u8]
+    ) -> Result<BlockSums, ArchivalError>
+    {
+        let read_err = |e: io::Error| ArchivalError(
+            format!("Could not read input file: \"{}\"\nreason: {}", path.display(), e)
+        );
+        let mut reader = BufReader::new(File::open(path).map_err(read_err)?);
+        let mut sums = ChecksumWriter::new(io::sink());
+        let mut compressed = 0;
+        for index in 0.. {
+            let block = Compressor::read_block(&mut reader).map_err(read_err)?;
+            if block.is_empty() {
+                break
+            }
+            let _ = sums.write_all(&block);
+            let block = Compressor::new(block, method).compress();
+            compressed += self.write_block(header, index, block)?;
+        }
+
+        Ok(BlockSums { size: sums.written(), compressed, checksum: sums.checksum() })
+    }
+
+    /// seal a compressed block of the file with the given header and write it after its length,
+    /// returning the space it took up
+    pub(crate) fn write_block(&mut self, header: &[u8], index: u64, block: Vec<u8>) -> Result<u64, ArchivalError> {
+        let block = self.seal_data(block, &block_context(header, index));
+        let write_res = self.write_archive_bytes(&(block.len() as u32).to_le_bytes())
+            .and_then(|_| self.write_archive_bytes(block.as_slice()));
+        match write_res {
+            Ok(_) => Ok(4 + block.len() as u64),
+            Err(e) => Err(ArchivalError(
+                format!("Failed to write file data: {}", e)
+            ))
+        }
+    }
+
+    /// read the blocks of a file entry from the current position of the archive,
+    /// decompressing each into `out` as soon as it is read, and return the checksum
+    /// the data must have
+    pub(crate) fn read_compressed_blocks(
+        &mut self, entry: &DirectoryEntry, out: &mut impl Write
+    ) -> Result<u32, ArchivalError>
+    {
+        let (encrypted, cipher) = (self.encryption.is_some(), self.cipher.as_ref());
+        let reader = self.archive_reader.as_mut().unwrap();
+        let (compressed, checksum) = decompress_blocks(reader, encrypted, cipher, entry, out)?;
+        if entry.method & DESCRIBED != 0 {
+            self.described = Some((compressed, checksum));
+        }
+        Ok(checksum)
+    }
+
+    /// walk the blocks of a described file from the start of its data to its descriptor,
+    /// filling in its compressed size and checksum and leaving the archive after it.
+    /// only the length of each block is read, so this can be done on a stream
+    pub(crate) fn measure_described(&mut self, entry: &mut DirectoryEntry) -> Result<(), ArchivalError> {
+        let corrupt = |reason: String| ArchivalError(format!("\"{}\" is corrupt: {}", entry.name, reason));
+        let reader = self.archive_reader.as_mut().unwrap();
+
+        let mut used = 0;
+        for index in 0..Compressor::block_count(entry.decompressed_size) {
+            let len = u32::from_le_bytes(read_array(reader)?) as u64;
+            if len > MAX_BLOCK_LEN {
+                return Err(corrupt(format!("block {} is larger than any block is stored in", index)))
+            }
+            if let Err(e) = reader.seek_relative(len as i64) {
+                return Err(ArchivalError(
+                    format!("failed to skip file data for \"{}\": {}", entry.name, e)
+                ))
+            }
+            used += 4 + len;
+        }
+
+        let (compressed, checksum) = read_descriptor(reader)?;
+        if compressed != used + DESCRIPTOR_SIZE {
+            return Err(corrupt(String::from("its descriptor doesn't match its blocks")))
+        }
+        entry.compressed_size = compressed;
+        entry.checksum = checksum;
+        Ok(())
+    }
+}
+
+/// read the blocks of a file entry from `reader`, opening them when the archive is
+/// `encrypted` and decompressing each into `out` as soon as it is read.
+///
+/// returns the compressed size and checksum of the file, which a described file read
+/// from its header alone only learns from the descriptor after its blocks
+pub(crate) fn decompress_blocks(
+    reader: &mut impl Read, encrypted: bool, cipher: Option<&Cipher>,
+    entry: &DirectoryEntry, out: &mut impl Write
+) -> Result<(u64, u32), ArchivalError>
+{
+    let DirectoryEntry { name, method, compressed_size: compressed, decompressed_size: decompressed, checksum, .. } = entry;
+    let (method, decompressed) = (*method, *decompressed);
+    let corrupt = |reason: String| ArchivalError(format!("\"{}\" is corrupt: {}", name, reason));
+    let read_err = |e: io::Error| ArchivalError(
+        format!("failed to read file data for \"{}\": {}", name, e)
+    );
+
+    let header = unmeasured(&entry.header()).to_bytes();
+    let described = method & DESCRIBED != 0;
+    let measured = !described || *compressed != 0;
+    let mut remaining = match (measured, described) {
+        (false, _) => u64::MAX,
+        (true, true) => compressed.saturating_sub(DESCRIPTOR_SIZE),
+        (true, false) => *compressed,
+    };
+    let mut used = 0;
+    for index in 0..Compressor::block_count(decompressed) {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).map_err(read_err)?;
+        let len = u32::from_le_bytes(len) as u64;
+        remaining = match remaining.checked_sub(4 + len) {
+            Some(remaining) if len <= MAX_BLOCK_LEN => remaining,
+            _ => return Err(corrupt(format!("block {} runs past the end of its data", index)))
+        };
+        used += 4 + len;
+
+        let mut block = vec![0; len as usize];
+        reader.read_exact(&mut block).map_err(read_err)?;
+        let block = crypto::open_data(encrypted, cipher, block, &block_context(&header, index), name)?;
+        let block = Compressor::new(block, method).decompress();
+
+        let expected = Compressor::block_len(decompressed, index);
+        if block.len() as u64 != expected {
+            return Err(corrupt(format!(
+                "block {} decompressed to {} bytes rather than {}", index, block.len(), expected
+            )))
+        }
+        if let Err(e) = out.write_all(&block) {
+            return Err(ArchivalError(
+                format!("Could not write the data of \"{}\": {}", name, e)
+            ))
+        }
+    }
+
+    if measured && remaining != 0 {
+        return Err(corrupt(format!("{} bytes follow its last block", remaining)))
+    }
+    if !described {
+        return Ok((*compressed, *checksum))
+    }
+
+    let (stored, stored_checksum) = read_descriptor(reader)?;
+    if stored != used + DESCRIPTOR_SIZE {
+        return Err(corrupt(String::from("its descriptor doesn't match its blocks")))
+    }
+    if measured && stored_checksum != *checksum {
+        return Err(corrupt(String::from("its descriptor doesn't match its entry in the central directory")))
+    }
+    Ok((stored, stored_checksum))
+}
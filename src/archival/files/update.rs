@@ -0,0 +1,238 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::io::{copy, BufReader, Read};
+use std::path::{Path, PathBuf};
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::directory::DirectoryEntry;
+use crate::archival::files::header::ArchiveHeader;
+use crate::archival::files::volume::{existing_volumes, is_stdio, volume_path, VolumeReader};
+
+impl Archiver {
+    /// open the output archive for reading if it already exists, returning
+    /// its version and entries so they can be carried over into the new archive
+    pub(crate) fn read_existing_archive(
+        &mut self
+    ) -> Result<Option<(usize, Vec<DirectoryEntry>)>, ArchivalError>
+    {
+        // there is nothing to update when streaming out
+        if is_stdio(&self.output) {
+            return Ok(None)
+        }
+        let path = match self.output.is_file() {
+            true => self.output.clone(),
+            false => volume_path(&self.output, 1),
+        };
+        if !path.is_file() {
+            return Ok(None)
+        }
+
+        let existing = match VolumeReader::open(&path) {
+            Ok(reader) => reader,
+            Err(e) => return Err(ArchivalError(
+                format!("Could not open existing archive \"{}\": {}", path.display(), e)
+            ))
+        };
+        // a split archive stays split unless a new volume size is given
+        if existing.is_split() && self.volume_size.is_none() {
+            self.volume_size = Some(existing.volume_size());
+        }
+        self.archive_size = existing.size();
+        self.archive_reader = Some(BufReader::new(existing));
+
+        // refuse to overwrite anything that isn't an archive
+        let encrypt_headers = self.encrypt_headers;
+        let ArchiveHeader(files, version, _encrypted) = match self.read_archive_header() {
+            Ok(header) => header,
+            Err(ArchivalError(e)) => return Err(ArchivalError(
+                format!(
+                    "\"{}\" already exists and is not a readable archive: {}",
+                    self.output.display(), e
+                )
+            ))
+        };
+        // the headers of an archive can only be encrypted when it is first written
+        if encrypt_headers && !self.encrypt_headers {
+            return Err(ArchivalError(String::from(
+                "the headers of an existing archive can't be encrypted, write a new archive instead"
+            )))
+        }
+        let entries = self.read_entries(files)?;
+        self.bytes_processed = 0;
+
+        Ok(Some((version, entries)))
+    }
+
+    /// copy an entry, header and data, byte for byte from the archive being read
+    /// into the archive being written. nothing is decompressed or recompressed.
+    ///
+    /// only chunks still used by a copied entry are carried over,
+    /// so chunks belonging to removed or replaced files are dropped
+    pub(crate) fn copy_entry(&mut self, entry: &DirectoryEntry) -> Result<(), ArchivalError> {
+        // the chunks of a deduplicated file, or the block of a solid one, must come along with it
+        self.copy_entry_chunks(entry)?;
+        self.copy_entry_block(entry)?;
+        self.seek_archive(entry.offset)?;
+
+        let mut copied = entry.clone();
+        copied.offset = self.archive_offset;
+
+        let len = self.entry_data_offset(entry) - entry.offset + entry.compressed_size;
+        let mut source = self.archive_reader.as_mut().unwrap().take(len);
+        let written = match copy(&mut source, self.archive_writer.as_mut().unwrap()) {
+            Ok(written) => written,
+            Err(e) => return Err(ArchivalError(
+                format!("Failed to copy \"{}\" into the new archive: {}", entry.name, e)
+            ))
+        };
+        if written != len {
+            return Err(ArchivalError(
+                format!("\"{}\" is truncated in the existing archive", entry.name)
+            ))
+        }
+
+        self.archive_offset += written;
+        self.bytes_processed += written as usize;
+        self.directory.entries.push(copied);
+        Ok(())
+    }
+
+    /// the path a new archive is written to before it replaces the output
+    pub(crate) fn temporary_output(&self) -> PathBuf {
+        let mut name = self.output.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        self.output.with_file_name(name)
+    }
+
+    /// replace the output with the finished temporary archive in a single rename,
+    /// so readers only ever see the old or the new archive.
+    ///
+    /// a split archive is renamed a volume at a time, see `Archiver::swap_volumes`,
+    /// and whichever form the old archive took, single file or volumes, is then removed
+    pub(crate) fn replace_output(&mut self) -> Result<(), ArchivalError> {
+        // release both files before swapping them
+        self.archive_reader = None;
+        self.archive_writer = None;
+        if is_stdio(&self.output) {
+            return Ok(())
+        }
+
+        if self.volume_size.is_some() {
+            return self.swap_volumes()
+        }
+        if let Err(e) = fs::rename(self.temporary_output(), &self.output) {
+            self.remove_temporary_output();
+            return Err(ArchivalError(
+                format!("Could not replace \"{}\": {}", self.output.display(), e)
+            ))
+        }
+        for path in existing_volumes(&self.output) {
+            let _ = fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    /// move the volumes of the new archive into place.
+    ///
+    /// a split archive can't be swapped in a single rename, so every volume is checked
+    /// before the first is moved, and the old archive is set aside rather than
+    /// overwritten. when a rename still fails part way, the new volumes are moved
+    /// back and the old archive restored, so it is left as it was
+    fn swap_volumes(&mut self) -> Result<(), ArchivalError> {
+        let renames = existing_volumes(&self.temporary_output())
+            .into_iter()
+            .enumerate()
+            .map(|(i, volume)| (volume, volume_path(&self.output, i + 1)))
+            .collect::<Vec<(PathBuf, PathBuf)>>();
+        let replace_err = |to: &Path, e: &dyn Display| ArchivalError(
+            format!("Could not replace \"{}\": {}", to.display(), e)
+        );
+
+        for (_, to) in &renames {
+            if to.is_dir() {
+                self.remove_temporary_output();
+                return Err(replace_err(to, &"it is a directory"))
+            }
+        }
+
+        let mut old = existing_volumes(&self.output);
+        old.extend(Some(self.output.clone()).filter(|path| path.is_file()));
+        let old = old.into_iter()
+            .map(|path| {
+                let aside = set_aside_path(&path);
+                (path, aside)
+            })
+            .collect::<Vec<(PathBuf, PathBuf)>>();
+
+        let failed = match rename_all(&old) {
+            Ok(_) => match rename_all(&renames) {
+                Ok(_) => None,
+                Err((done, to, e)) => {
+                    let back = undo_renames(&renames[..done]).and_then(|_| undo_renames(&old));
+                    Some((to, e, back))
+                }
+            },
+            Err((done, to, e)) => Some((to, e, undo_renames(&old[..done]))),
+        };
+        match failed {
+            None => {}
+            Some((to, e, Ok(_))) => {
+                self.remove_temporary_output();
+                return Err(replace_err(&to, &e))
+            }
+            Some((to, e, Err(undo))) => {
+                // neither archive is whole any more, so leave every piece where it is
+                self.keep_temporary_output = true;
+                return Err(ArchivalError(format!(
+                    "Could not replace \"{}\": {}\nand could not put the old archive back: {}\n\
+                    the new volumes are left next to \"{}\", and the old ones end in \".old\"",
+                    to.display(), e, undo, self.output.display()
+                )))
+            }
+        }
+
+        for (_, aside) in &old {
+            let _ = fs::remove_file(aside);
+        }
+        Ok(())
+    }
+
+    /// remove whatever was written of a new archive that won't be completed
+    pub(crate) fn remove_temporary_output(&mut self) {
+        self.archive_writer = None;
+        // whatever was already streamed out can't be taken back
+        if is_stdio(&self.output) || self.keep_temporary_output {
+            return
+        }
+        let _ = fs::remove_file(self.temporary_output());
+        for volume in existing_volumes(&self.temporary_output()) {
+            let _ = fs::remove_file(volume);
+        }
+    }
+}
+
+/// where an old volume is kept while a new archive is swapped into its place
+fn set_aside_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".old");
+    PathBuf::from(name)
+}
+
+/// rename each pair in turn, stopping at the first which fails with
+/// how many were renamed before it and where it was going
+fn rename_all(renames: &[(PathBuf, PathBuf)]) -> Result<(), (usize, PathBuf, io::Error)> {
+    for (i, (from, to)) in renames.iter().enumerate() {
+        if let Err(e) = fs::rename(from, to) {
+            return Err((i, to.clone(), e))
+        }
+    }
+    Ok(())
+}
+
+/// rename each pair back to where it came from, latest first
+fn undo_renames(renames: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    for (from, to) in renames.iter().rev() {
+        fs::rename(to, from)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::io;
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::directory::DirectoryEntry;
+use crate::archival::files::filter::EntryFilter;
+use crate::archival::files::header::ArchiveHeader;
+
+impl Archiver {
+    /// test the archive by decompressing every entry and checking it
+    /// against the size and checksum in its header. nothing is written to disk.
+    ///
+    /// prints a pass / fail line per entry and returns an error if any entry is corrupt
+    pub fn verify(&mut self) -> Result<u64, ArchivalError> {
+        let ArchiveHeader(files, version, _encrypted) = self.read_archive_header()?;
+        let filter = EntryFilter::new(&self.filters)?;
+
+        println!("Verifying {} (version {})", self.input.display(), version);
+        let (mut passed, mut failures) = (0, vec![]);
+        let mut check = |this: &mut Archiver, entry: &DirectoryEntry| {
+            if !filter.matches(&entry.name) {
+                return
+            }
+            match this.read_entry_to(entry, &mut io::sink()) {
+                Ok(_) => {
+                    println!("  ok    {}", entry.name);
+                    passed += 1;
+                }
+                Err(ArchivalError(e)) => {
+                    println!("  FAIL  {} :: {}", entry.name, e);
+                    failures.push(entry.name.clone());
+                }
+            }
+            this.files_processed += 1;
+        };
+
+        // a stream is checked as it is read, as it can't be gone back over
+        let found = match self.is_streamed() {
+            true => self.walk_stream(files, true, |this, entry| {
+                check(this, entry);
+                Ok(())
+            })?.len(),
+            false => {
+                let entries = self.read_entries(files)?;
+                for entry in &entries {
+                    check(self, entry);
+                }
+                entries.len()
+            }
+        };
+        let failed = failures.len();
+        if found != files {
+            failures.insert(0, format!(
+                "archive header records {} files but {} were found", files, found
+            ));
+        }
+
+        println!(
+            "Verification completed in {:.2}s: {} entries passed, {} failed",
+            self.start_time.unwrap().elapsed().as_secs_f64(),
+            passed, failed,
+        );
+
+        if !failures.is_empty() {
+            return Err(ArchivalError(
+                format!("{} is corrupt: {}", self.input.display(), failures.join(", "))
+            ))
+        }
+        Ok(self.archive_size)
+    }
+}
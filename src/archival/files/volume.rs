@@ -0,0 +1,311 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// whether `path` stands in for stdin or stdout, as in `Ark a "dir" "-"`
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// the path of volume `index` (starting at 1) of a split archive, e.g. `archive.ark.003`
+pub fn volume_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_os_string();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+/// the path a split archive was written to, if `path` names its first volume
+pub fn split_base(path: &Path) -> Option<PathBuf> {
+    let name = path.to_str()?;
+    name.strip_suffix(".001").map(PathBuf::from)
+}
+
+/// every volume of the split archive at `base`, in order
+pub fn existing_volumes(base: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|i| volume_path(base, i))
+        .take_while(|p| p.is_file())
+        .collect()
+}
+
+/// reads a set of volumes as though they were a single archive.
+///
+/// an archive which isn't split is simply a set of one volume, and one read
+/// from a stream is a set of none, which can only ever be read forwards
+pub struct VolumeReader {
+    volumes: Vec<File>,
+    paths: Vec<PathBuf>,
+    /// the position each volume starts at within the whole archive
+    starts: Vec<u64>,
+    len: u64,
+    current: usize,
+    position: u64,
+    /// the stream read from in place of any volume
+    stream: Option<Box<dyn Read>>,
+}
+
+impl VolumeReader {
+    /// open an archive, along with every volume following it when `path` is a first volume.
+    ///
+    /// volumes are collected until one can't be found, so a missing volume leaves
+    /// the archive short rather than failing here, see `VolumeReader::next_volume`.
+    /// only a missing first volume fails straight away
+    pub fn open(path: &Path) -> io::Result<VolumeReader> {
+        let paths = match split_base(path) {
+            Some(base) => existing_volumes(&base),
+            None => vec![path.to_path_buf()],
+        };
+
+        let mut reader = VolumeReader {
+            volumes: vec![],
+            paths: vec![],
+            starts: vec![],
+            len: 0,
+            current: 0,
+            position: 0,
+            stream: None,
+        };
+        if paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("the archive is incomplete, volume \"{}\" is missing", path.display())
+            ))
+        }
+        for path in paths {
+            let volume = File::open(&path)?;
+            reader.starts.push(reader.len);
+            reader.len += volume.metadata()?.len();
+            reader.volumes.push(volume);
+            reader.paths.push(path);
+        }
+        Ok(reader)
+    }
+
+    /// read an archive from a stream of unknown length, such as stdin.
+    ///
+    /// nothing is copied aside, so the stream can only be read forwards:
+    /// seeking ahead skips over what is in between, and seeking back fails
+    pub fn from_stream(source: impl Read + 'static) -> VolumeReader {
+        VolumeReader {
+            volumes: vec![],
+            paths: vec![],
+            starts: vec![],
+            len: 0,
+            current: 0,
+            position: 0,
+            stream: Some(Box::new(source)),
+        }
+    }
+
+    /// the combined size of every volume, which isn't known for a stream
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// whether the archive is read from a stream, and so can only be read forwards
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// whether the archive is made up of more than one file
+    pub fn is_split(&self) -> bool {
+        self.paths.first().is_some_and(|path| split_base(path).is_some())
+    }
+
+    /// the size of the first volume, which every volume but the last matches
+    pub fn volume_size(&self) -> u64 {
+        self.starts.get(1).copied().unwrap_or(self.len)
+    }
+
+    /// the path of the volume which would follow the last one found
+    pub fn next_volume(&self) -> Option<PathBuf> {
+        let base = split_base(self.paths.first()?)?;
+        Some(volume_path(&base, self.paths.len() + 1))
+    }
+
+    /// replace the bytes at `position` within the whole archive, without changing its size,
+    /// and wait for them to reach the disk.
+    ///
+    /// volumes are only opened for reading, so each volume written to is reopened,
+    /// and a stream can't be written back at all
+    pub fn overwrite(&mut self, position: u64, bytes: &[u8]) -> io::Result<()> {
+        self.write_at(position, bytes, true)
+    }
+
+    /// replace the bytes at `position` like `overwrite`, without waiting for them to
+    /// reach the disk, for an archive still being written
+    pub fn patch(&mut self, position: u64, bytes: &[u8]) -> io::Result<()> {
+        self.write_at(position, bytes, false)
+    }
+
+    fn write_at(&mut self, position: u64, bytes: &[u8], sync: bool) -> io::Result<()> {
+        if self.stream.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "an archive read from a stream can't be changed"))
+        }
+        if position.checked_add(bytes.len() as u64).is_none_or(|end| end > self.len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "write past the end of the archive"))
+        }
+
+        let mut position = position;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            self.select(position)?;
+            let local = position - self.starts[self.current];
+            let end = self.starts.get(self.current + 1).copied().unwrap_or(self.len);
+            let (here, rest) = bytes.split_at(bytes.len().min((end - position) as usize));
+
+            let mut volume = OpenOptions::new().write(true).open(&self.paths[self.current])?;
+            volume.seek(SeekFrom::Start(local))?;
+            volume.write_all(here)?;
+            if sync {
+                volume.sync_all()?;
+            }
+
+            position += here.len() as u64;
+            bytes = rest;
+        }
+        self.select(position)
+    }
+
+    /// move a stream forwards to `target` by reading past everything before it
+    fn skip_to(&mut self, target: u64) -> io::Result<u64> {
+        if target < self.position {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an archive read from a stream can only be read forwards"
+            ))
+        }
+        let stream = self.stream.as_mut().unwrap();
+        let skipped = io::copy(&mut stream.take(target - self.position), &mut io::sink())?;
+        self.position += skipped;
+        if self.position < target {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the stream ended early"))
+        }
+        Ok(target)
+    }
+
+    /// move to the volume holding `position` within the whole archive
+    fn select(&mut self, position: u64) -> io::Result<()> {
+        self.current = self.starts.iter()
+            .rposition(|start| *start <= position)
+            .unwrap_or(0);
+        self.position = position;
+        let local = position - self.starts[self.current];
+        self.volumes[self.current].seek(SeekFrom::Start(local))?;
+        Ok(())
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(stream) = self.stream.as_mut() {
+            let read = stream.read(buf)?;
+            self.position += read as u64;
+            return Ok(read)
+        }
+        while self.current < self.volumes.len() {
+            let read = self.volumes[self.current].read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.position += read as u64;
+                return Ok(read)
+            }
+
+            // reads carry straight on into the next volume
+            if self.current + 1 == self.volumes.len() {
+                break
+            }
+            self.current += 1;
+            self.volumes[self.current].seek(SeekFrom::Start(0))?;
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(_) if self.stream.is_some() => return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the end of an archive read from a stream isn't known until it is reached"
+            )),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match target {
+            Some(target) if self.stream.is_some() => self.skip_to(target),
+            Some(target) => {
+                self.select(target)?;
+                Ok(target)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position"
+            ))
+        }
+    }
+}
+
+/// writes an archive as a series of volumes, each no larger than `volume_size`
+pub struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    volumes: usize,
+    current: Option<File>,
+    /// bytes written to the current volume
+    written: u64,
+}
+
+impl VolumeWriter {
+    /// volumes are created as they're needed, named after `base`
+    pub fn new(base: PathBuf, volume_size: u64) -> VolumeWriter {
+        VolumeWriter {
+            base,
+            volume_size,
+            volumes: 0,
+            current: None,
+            written: 0,
+        }
+    }
+
+    fn next_volume(&mut self) -> io::Result<()> {
+        if let Some(volume) = self.current.as_mut() {
+            volume.flush()?;
+        }
+        self.volumes += 1;
+        self.current = Some(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(volume_path(&self.base, self.volumes))?
+        );
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        if self.current.is_none() || self.written == self.volume_size {
+            self.next_volume()?;
+        }
+
+        let space = (self.volume_size - self.written) as usize;
+        let len = buf.len().min(space);
+        let written = self.current.as_mut().unwrap().write(&buf[..len])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.current.as_mut() {
+            Some(volume) => volume.flush(),
+            None => Ok(()),
+        }
+    }
+}
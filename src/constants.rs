@@ -2,7 +2,7 @@
 pub const KILOBYTE: u64 = 1024;
 pub const MEGABYTE: u64 = 1048576;
 pub const GIGABYTE: u64 = 1073741824;
-pub const TERABYTE: u64 = 140737488355328;
+pub const TERABYTE: u64 = 1099511627776;
 
 #[macro_export] macro_rules! format_bytes {
     ($s: expr) => {
@@ -15,4 +15,25 @@ pub const TERABYTE: u64 = 140737488355328;
             _ => format!("{:.1} TB", $s as f64 / TERABYTE as f64),
         }
     };
-}
\ No newline at end of file
+}
+/// parse a size such as `4GB`, `700 mb` or `1.5TB`, using the same units as `format_bytes`.
+/// a plain number is taken as bytes
+pub fn parse_bytes(s: &str) -> Option<u64> {
+    let s = s.trim().to_uppercase();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let unit = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => KILOBYTE,
+        "M" | "MB" => MEGABYTE,
+        "G" | "GB" => GIGABYTE,
+        "T" | "TB" => TERABYTE,
+        _ => return None
+    };
+    let bytes = number.parse::<f64>().ok()? * unit as f64;
+    if !bytes.is_finite() || bytes < 1.0 {
+        return None
+    }
+    Some(bytes as u64)
+}
@@ -8,12 +8,32 @@ pub mod archival {
 
     pub mod files {
         pub mod archiver;
+        pub mod crypto;
+        pub mod dedup;
+        pub mod directory;
+        pub mod filter;
+        pub mod forward;
         pub mod header;
+        pub mod integrity;
+        pub mod keys;
+        pub mod metadata;
+        pub mod paths;
+        pub mod pipeline;
+        pub mod recovery;
+        pub mod remove;
+        pub mod salvage;
+        pub mod signature;
+        pub mod solid;
+        pub mod stream;
+        pub mod update;
+        pub mod verify;
+        pub mod volume;
         pub mod indexer;
     }
 
     pub mod compression {
         pub mod profiler;
+        pub mod chunker;
         pub mod file_compressor;
         pub mod rle;
     }
@@ -32,34 +52,63 @@ mod tests {
     mod archiver_tests;
 }
 
-pub fn run() {
+/// run a single command given as process arguments, e.g. `Ark verify "backup.ark"`,
+/// or when there are none, read and run commands from stdin until it is closed.
+///
+/// exits with a failure status if any command failed
+pub fn run() -> std::process::ExitCode {
     use std::io;
     use std::io::BufRead;
+    use std::process::ExitCode;
     use crate::archival::cli::input::Command;
-    use crate::archival::files::archiver::{ArchivalError, Archiver};
 
-    loop {
-        // read command from stdin
-        let stdin = io::stdin();
-        let src = stdin.lock().lines().next().unwrap().unwrap();
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if !args.is_empty() {
+        return match execute(Command::from_args(args)) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        }
+    }
 
-        // parse command
-        let command = Command::new(src);
-        println!("{}", &command);
+    let mut status = ExitCode::SUCCESS;
+    for src in io::stdin().lock().lines() {
+        // read command from stdin
+        let src = match src {
+            Ok(src) => src,
+            Err(_) => break
+        };
 
-        // perform specified actions
-        let mut archiver = Archiver::new(command);
-        let archival_result = archiver.operate();
-        if archival_result.is_err() {
-            let ArchivalError(res) = archival_result.err().unwrap();
-            println!("{}", res);
+        // parse command & perform specified actions
+        if !execute(Command::new(src)) {
+            status = ExitCode::FAILURE;
         }
+    }
+    status
+}
+
+/// perform the actions specified by `command`, returning false if they failed
+fn execute(command: archival::cli::input::Command) -> bool {
+    use crate::archival::files::archiver::{ArchivalError, Archiver};
 
-        // output desired item(s)
+    if let Some(error) = &command.error {
+        eprintln!("{}", error);
+        return false
     }
+    eprintln!("{}", &command);
+
+    let mut archiver = Archiver::new(command);
+    let archival_result = archiver.operate();
+    if archival_result.is_err() {
+        let ArchivalError(res) = archival_result.err().unwrap();
+        eprintln!("{}", res);
+        return false
+    }
+
+    // output desired item(s)
+    true
 }
 
 
-fn main() {
+fn main() -> std::process::ExitCode {
     run()
 }
\ No newline at end of file
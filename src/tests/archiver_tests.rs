@@ -1,6 +1,22 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use crate::archival::cli::input::{Command, Mode};
-use crate::archival::files::archiver::Archiver;
+use crate::archival::compression::chunker;
+use crate::archival::compression::chunker::StreamChunker;
+use crate::archival::compression::file_compressor::BLOCK_SIZE;
+use crate::archival::files::archiver::{ArchivalError, Archiver};
+use crate::archival::files::crypto::KeySlot;
+use crate::archival::files::directory::EntryKind;
+use crate::archival::files::header::{ArchiveHeader, Header, HeaderError};
+use crate::archival::files::integrity;
+use crate::archival::files::integrity::EntryError;
+use crate::archival::files::keys;
+use crate::archival::files::metadata::EntryMetadata;
+use crate::archival::files::paths;
+use crate::archival::files::solid::SOLID;
+use crate::archival::files::volume::VolumeReader;
+use crate::constants::parse_bytes;
 
 #[test]
 fn test_read_archive() {
@@ -10,6 +26,7 @@ fn test_read_archive() {
         // filler output
         output: Some(PathBuf::from("src/tests/archive.ark")),
         key: None,
+        ..Default::default()
     });
 
     if let Ok(head) = archiver.read_archive_header() {
@@ -23,5 +40,1553 @@ fn test_read_archive() {
         assert_eq!(0u8, head.1);
         assert_eq!(50, head.2);
         assert_eq!(60, head.3);
+        assert_eq!(0x9193c6b8, head.4);
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_file_header_round_trip() {
+    let header = Header::File {
+        name: "odd:name\nwith/separators.txt".to_owned(),
+        method: 0b1100_0000,
+        compressed_size: 12,
+        decompressed_size: 4096,
+        checksum: 0xdeadbeef,
+        metadata: EntryMetadata {
+            mode: 0o100640,
+            uid: 1000,
+            gid: 100,
+            mtime: -86_400,
+            mtime_nanos: 999_999_999,
+            atime: 1_700_000_000,
+            atime_nanos: 1,
+        },
+    };
+    let bytes = header.to_bytes();
+    assert_eq!(header.encoded_len(), bytes.len());
+
+    match Header::read_file(&mut bytes.as_slice()) {
+        Ok(Header::File
+           { name, method, compressed_size, decompressed_size, checksum, metadata }) => {
+            assert_eq!("odd:name\nwith/separators.txt", name);
+            assert_eq!(0b1100_0000, method);
+            assert_eq!(12, compressed_size);
+            assert_eq!(4096, decompressed_size);
+            assert_eq!(0xdeadbeef, checksum);
+            assert_eq!(-86_400, metadata.mtime);
+            assert_eq!(0o100640, metadata.mode);
+        }
+        _ => panic!("File header failed to round trip!")
+    }
+}
+
+#[test]
+fn test_rejects_bad_headers() {
+    let text_header = b"files:2\nver:0\nencrypted:false\n";
+    assert!(matches!(
+        Header::read_archive(&mut text_header.as_slice()),
+        Err(HeaderError::BadMagic { .. })
+    ));
+
+    let truncated = &Header::Archive { total_files: 1, version: 1, encrypted: false, encrypted_headers: false }
+        .to_bytes()[..10];
+    assert!(matches!(
+        Header::read_archive(&mut &truncated[..]),
+        Err(HeaderError::Io(_))
+    ));
+}
+
+/// create an empty directory under the system temp dir for a test to work in
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ark_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run_command(mode: Mode, input: &Path, output: &Path) -> Archiver {
+    let mut archiver = Archiver::new(Command {
+        mode: Some(mode),
+        input: Some(input.to_path_buf()),
+        output: Some(output.to_path_buf()),
+        key: None,
+        ..Default::default()
+    });
+    if archiver.operate().is_err() {
+        panic!("Archiver failed to operate on {}", input.display())
+    }
+    archiver
+}
+
+#[test]
+fn test_central_directory() {
+    let dir = test_dir("central_directory");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("nested")).unwrap();
+    fs::write(input.join("first.txt"), "first file").unwrap();
+    fs::write(input.join("nested/second.txt"), "the second file").unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(dir.join("output")),
+        key: None,
+        ..Default::default()
+    });
+    let directory = match archiver.read_directory() {
+        Ok(Some(directory)) => directory,
+        _ => panic!("Central directory failed to be read!")
+    };
+
+    let mut names = directory.entries.iter()
+        .map(|e| e.name.replace('\\', "/"))
+        .collect::<Vec<String>>();
+    names.sort();
+    assert_eq!(
+        vec!["first.txt".to_owned(), "nested".to_owned(), "nested/second.txt".to_owned()],
+        names
+    );
+
+    // every file entry should point directly at its own file header
+    for entry in directory.entries.iter().filter(|e| e.kind == EntryKind::File) {
+        archiver.archive_reader.as_mut().unwrap()
+            .seek(SeekFrom::Start(entry.offset)).unwrap();
+        let header = archiver.read_file_header().ok().unwrap();
+        assert_eq!(entry.name, header.0);
+        assert_eq!(entry.compressed_size, header.2);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_read_entries_without_directory() {
+    // the fixture predates the central directory, so its headers must be walked
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(PathBuf::from("src/tests/archive.ark")),
+        output: None,
+        key: None,
+        ..Default::default()
+    });
+
+    let ArchiveHeader(files, ..) = archiver.read_archive_header().ok().unwrap();
+    let entries = archiver.read_entries(files).ok().unwrap();
+
+    assert_eq!(2, entries.len());
+    assert_eq!("file1.mov", entries[0].name);
+    assert_eq!(23, entries[0].offset);
+    assert_eq!(50, entries[0].compressed_size);
+    assert_eq!("file2.mov", entries[1].name);
+    assert_eq!(entries[0].data_offset() + 50, entries[1].offset);
+}
+
+#[test]
+fn test_selective_extraction() {
+    let dir = test_dir("selective_extraction");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("videos/2024")).unwrap();
+    fs::create_dir_all(input.join("notes")).unwrap();
+    fs::write(input.join("videos/2024/trip.mp4"), "not really a video").unwrap();
+    fs::write(input.join("videos/trip.mov"), "not a video either").unwrap();
+    fs::write(input.join("notes/todo.txt"), "write more tests").unwrap();
+    fs::write(input.join("readme.txt"), "hello").unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    let output = dir.join("output");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        filters: vec!["videos/**/*.mp4".to_owned(), "notes".to_owned()],
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+
+    assert_eq!("not really a video", fs::read_to_string(output.join("videos/2024/trip.mp4")).unwrap());
+    assert_eq!("write more tests", fs::read_to_string(output.join("notes/todo.txt")).unwrap());
+    assert!(!output.join("videos/trip.mov").exists());
+    assert!(!output.join("readme.txt").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_command_errors() {
+    let parse = |args: &[&str]| Command::from_args(args.iter().map(|arg| arg.to_string()).collect());
+
+    // a flag missing its value is reported rather than run
+    let command = parse(&["x", "archive.ark", "output", "-f"]);
+    assert!(command.error.unwrap().contains("-f"));
+    let command = parse(&["x", "archive.ark", "output", "-f", "notes"]);
+    assert_eq!(None, command.error);
+    assert_eq!(vec!["notes".to_owned()], command.filters);
+    let command = parse(&["a", "input", "archive.ark", "-v", "4 parsecs"]);
+    assert!(command.error.unwrap().contains("-v"));
+    let command = parse(&["a", "input", "archive.ark", "--solid", "huge"]);
+    assert!(command.error.unwrap().contains("--solid"));
+    let command = parse(&["x", "archive.ark", "output", "-k"]);
+    assert!(command.error.unwrap().contains("-k"));
+    let command = parse(&["a", "input", "archive.ark", "--sign"]);
+    assert!(command.error.unwrap().contains("--sign"));
+    let command = parse(&["rekey", "archive.ark", "-k", "old", "--kdf-passes", "many"]);
+    assert!(command.error.unwrap().contains("--kdf-passes"));
+    let command = parse(&["a", "input", "archive.ark", "--recovery", "0%"]);
+    assert!(command.error.unwrap().contains("--recovery"));
+    let command = parse(&["a", "input", "archive.ark", "-j", "0"]);
+    assert!(command.error.unwrap().contains("-j"));
+
+    // the value of a flag is never taken for a mode, however short
+    let command = Command::new(String::from("Ark x \"archive.ark\" \"output\" -k l -f a x"));
+    assert_eq!(None, command.error);
+    assert_eq!(Some("l".to_owned()), command.key);
+    assert_eq!(vec!["a".to_owned(), "x".to_owned()], command.filters);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_restore_metadata() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    let dir = test_dir("restore_metadata");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    let file = input.join("script.sh");
+    fs::write(&file, "echo hello").unwrap();
+
+    let modified = SystemTime::UNIX_EPOCH + Duration::new(1_234_567_890, 500);
+    fs::File::options().write(true).open(&file).unwrap()
+        .set_times(fs::FileTimes::new().set_modified(modified)).unwrap();
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o750)).unwrap();
+    let setuid = input.join("setuid");
+    fs::write(&setuid, "#!/bin/sh").unwrap();
+    fs::set_permissions(&setuid, fs::Permissions::from_mode(0o4755)).unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    let output = dir.join("output");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        no_owner: true,
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+
+    let metadata = fs::metadata(output.join("script.sh")).unwrap();
+    assert_eq!(0o750, metadata.permissions().mode() & 0o7777);
+    assert_eq!(modified, metadata.modified().unwrap());
+
+    // a file not given back to its owner never keeps its setuid bit
+    let metadata = fs::metadata(output.join("setuid")).unwrap();
+    assert_eq!(0o755, metadata.permissions().mode() & 0o7777);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_directories_and_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let dir = test_dir("directories_and_symlinks");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("empty")).unwrap();
+    fs::create_dir_all(input.join("docs")).unwrap();
+    fs::write(input.join("docs/readme.txt"), "read me").unwrap();
+    symlink("docs/readme.txt", input.join("latest")).unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &archive, &output);
+    assert!(output.join("empty").is_dir());
+    assert_eq!(PathBuf::from("docs/readme.txt"), fs::read_link(output.join("latest")).unwrap());
+    assert_eq!("read me", fs::read_to_string(output.join("latest")).unwrap());
+
+    // links which lead outside of the output are refused before anything is written
+    symlink("../../outside", input.join("docs/escape")).unwrap();
+    let archive = dir.join("escape.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    let output = dir.join("escaped");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("docs/escape"), "{}", e),
+        Ok(_) => panic!("Escaping link was extracted!"),
+    }
+    assert!(!output.exists());
+
+    // nor can a target climb back out of another link
+    let links = [PathBuf::from("here")].into_iter().collect();
+    assert!(paths::check_link_target("up", "here/..", &links).is_err());
+    assert!(paths::check_link_target("up", "here/there/..", &links).is_err());
+    assert!(paths::check_link_target("here/up", "..", &links).is_err());
+    assert!(paths::check_link_target("a/b", "../c", &links).is_ok());
+
+    // even when the link passed through is further up the target
+    let input = dir.join("loop");
+    fs::create_dir_all(&input).unwrap();
+    symlink(".", input.join("s")).unwrap();
+    symlink("s/s/..", input.join("t")).unwrap();
+    let archive = dir.join("loop.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    let output = dir.join("looped");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("\"t\""), "{}", e),
+        Ok(_) => panic!("Escaping link was extracted!"),
+    }
+    assert!(!output.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hard_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = test_dir("hard_links");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("cache")).unwrap();
+    fs::write(input.join("cache/original.bin"), [3u8; 4096]).unwrap();
+    fs::hard_link(input.join("cache/original.bin"), input.join("cache/copy.bin")).unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    // the data is only stored once
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        ..Default::default()
+    });
+    let ArchiveHeader(files, ..) = archiver.read_archive_header().ok().unwrap();
+    let entries = archiver.read_entries(files).ok().unwrap();
+    let links = entries.iter()
+        .filter(|e| matches!(e.kind, EntryKind::HardLink(_)))
+        .count();
+    assert_eq!(1, links);
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &archive, &output);
+    let original = fs::metadata(output.join("cache/original.bin")).unwrap();
+    let copy = fs::metadata(output.join("cache/copy.bin")).unwrap();
+    assert_eq!(original.ino(), copy.ino());
+    assert_eq!(vec![3u8; 4096], fs::read(output.join("cache/copy.bin")).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_deduplication() {
+    let dir = test_dir("deduplication");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("tagged")).unwrap();
+
+    // pseudo random data, so nothing but deduplication can shrink it
+    let mut state = 1u32;
+    let export = (0..256 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect::<Vec<u8>>();
+    let mut re_export = b"a slightly different header".to_vec();
+    re_export.extend_from_slice(&export);
+    fs::write(input.join("export.raw"), &export).unwrap();
+    fs::write(input.join("tagged/export.raw"), &export).unwrap();
+    fs::write(input.join("tagged/re-export.raw"), &re_export).unwrap();
+
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        dedup: true,
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    assert!(archiver.dedup_estimate().ok().unwrap().savings() > 60.0);
+    // the three copies take up little more room than one
+    assert!(fs::metadata(&archive).unwrap().len() < export.len() as u64 * 5 / 4);
+
+    // removing one copy must keep the chunks the others still use
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Remove),
+        input: Some(archive.clone()),
+        filters: vec!["export.raw".to_owned()],
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &archive, &output);
+    assert!(!output.join("export.raw").exists());
+    assert_eq!(export, fs::read(output.join("tagged/export.raw")).unwrap());
+    assert_eq!(re_export, fs::read(output.join("tagged/re-export.raw")).unwrap());
+
+    // an encrypted archive names its chunks by a keyed hash, so known contents can't be spotted
+    let encrypted = dir.join("encrypted.ark");
+    let with_key = |mode: Mode, input: &PathBuf, output: &PathBuf| {
+        Archiver::new(Command {
+            mode: Some(mode),
+            input: Some(input.clone()),
+            output: Some(output.clone()),
+            dedup: true,
+            key: Some("hunter2".to_owned()),
+            ..Default::default()
+        }).operate()
+    };
+    assert!(with_key(Mode::Add, &input, &encrypted).is_ok());
+    let bytes = fs::read(&encrypted).unwrap();
+    let mut chunker = StreamChunker::new(Cursor::new(&export));
+    while let Some(chunk) = chunker.next_chunk().unwrap() {
+        let hash = chunker::hash(chunk);
+        assert!(!bytes.windows(32).any(|w| w == hash));
+    }
+    let output = dir.join("decrypted");
+    assert!(with_key(Mode::Extract, &encrypted, &output).is_ok());
+    assert_eq!(export, fs::read(output.join("tagged/export.raw")).unwrap());
+    assert_eq!(re_export, fs::read(output.join("tagged/re-export.raw")).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compressed_blocks() {
+    let dir = test_dir("compressed_blocks");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+
+    // long runs are worth compressing with rle, across several blocks
+    let scan = (0..BLOCK_SIZE * 5 / 2).map(|i| (i / 1000) as u8).collect::<Vec<u8>>();
+    fs::write(input.join("scan.bmp"), &scan).unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        ..Default::default()
+    });
+    let ArchiveHeader(files, ..) = archiver.read_archive_header().ok().unwrap();
+    let entry = archiver.read_entries(files).ok().unwrap().remove(0);
+    assert_ne!(0, entry.method);
+    assert!(entry.compressed_size < entry.decompressed_size / 100);
+
+    // each block is stored after its own compressed length
+    let mut bytes = fs::read(&archive).unwrap();
+    let first = entry.data_offset() as usize;
+    let len = u32::from_le_bytes(bytes[first..first + 4].try_into().unwrap()) as usize;
+    assert!(len < entry.compressed_size as usize / 2);
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &archive, &output);
+    assert_eq!(scan, fs::read(output.join("scan.bmp")).unwrap());
+
+    // a damaged block fails the file, and nothing is left of it
+    bytes[first + 4 + len + 4 + 10] ^= 0xff;
+    fs::write(&archive, bytes).unwrap();
+    let output = dir.join("damaged");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("scan.bmp"), "{}", e),
+        Ok(_) => panic!("Extracted a damaged block!"),
+    }
+    assert!(!output.join("scan.bmp").exists());
+
+    // chunks are cut in the same places whether the file is streamed or read at once
+    let mut state = 3u32;
+    let noise = (0..300 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect::<Vec<u8>>();
+    let mut chunker = StreamChunker::new(Cursor::new(&noise));
+    let mut streamed = vec![];
+    while let Some(chunk) = chunker.next_chunk().unwrap() {
+        streamed.push(chunk.to_vec());
+    }
+    assert_eq!(chunker::chunk(&noise), streamed);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_parallel_compression() {
+    let dir = test_dir("parallel_compression");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("notes")).unwrap();
+
+    let scan = (0..BLOCK_SIZE * 5 / 2).map(|i| (i / 1000) as u8).collect::<Vec<u8>>();
+    fs::write(input.join("scan.bmp"), &scan).unwrap();
+    fs::write(input.join("empty.txt"), b"").unwrap();
+    for i in 0..20 {
+        let note = format!("note {} ", i).repeat(i * 100);
+        fs::write(input.join("notes").join(format!("{}.txt", i)), note).unwrap();
+    }
+
+    let archive = |jobs: usize| {
+        let archive = dir.join(format!("{}.ark", jobs));
+        let mut archiver = Archiver::new(Command {
+            mode: Some(Mode::Add),
+            input: Some(input.clone()),
+            output: Some(archive.clone()),
+            jobs: Some(jobs),
+            ..Default::default()
+        });
+        archiver.operate().ok().unwrap();
+        archive
+    };
+
+    // the first read of a file just written moves its access time, which is stored as it
+    // was before the file is read, so every file is read once before comparing the two
+    archive(2);
+
+    // the writer puts everything the workers compress back in order
+    let single = archive(1);
+    let parallel = archive(4);
+    assert_eq!(fs::read(&single).unwrap(), fs::read(&parallel).unwrap());
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &parallel, &output);
+    assert_eq!(scan, fs::read(output.join("scan.bmp")).unwrap());
+    assert_eq!(
+        fs::read(input.join("notes").join("19.txt")).unwrap(),
+        fs::read(output.join("notes").join("19.txt")).unwrap()
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_parallel_extraction() {
+    use std::time::{Duration, SystemTime};
+
+    let dir = test_dir("parallel_extraction");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("small")).unwrap();
+
+    let scan = (0..BLOCK_SIZE * 5 / 2).map(|i| (i / 1000) as u8).collect::<Vec<u8>>();
+    fs::write(input.join("scan.bmp"), &scan).unwrap();
+    for i in 0..50 {
+        fs::write(input.join("small").join(format!("{}.txt", i)), format!("file {}", i).repeat(i)).unwrap();
+    }
+    let modified = SystemTime::UNIX_EPOCH + Duration::new(1_234_567_890, 0);
+    fs::File::options().write(true).open(input.join("small").join("7.txt")).unwrap()
+        .set_times(fs::FileTimes::new().set_modified(modified)).unwrap();
+    std::os::unix::fs::symlink("7.txt", input.join("small").join("latest")).unwrap();
+
+    // small files are read out of solid blocks, the scan block by block
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        key: Some(String::from("hunter2")),
+        solid_block_size: Some(64 * 1024),
+        ..Default::default()
+    });
+    archiver.operate().ok().unwrap();
+
+    let extract = |output: &PathBuf| Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        key: Some(String::from("hunter2")),
+        jobs: Some(4),
+        no_owner: true,
+        ..Default::default()
+    }).operate();
+
+    let output = dir.join("output");
+    assert!(extract(&output).is_ok());
+    assert_eq!(scan, fs::read(output.join("scan.bmp")).unwrap());
+    for i in 0..50 {
+        let name = PathBuf::from("small").join(format!("{}.txt", i));
+        assert_eq!(fs::read(input.join(&name)).unwrap(), fs::read(output.join(&name)).unwrap());
+    }
+    assert_eq!(modified, fs::metadata(output.join("small").join("7.txt")).unwrap().modified().unwrap());
+    assert_eq!("file 7".repeat(7), fs::read_to_string(output.join("small").join("latest")).unwrap());
+
+    // a damaged block is still caught by whichever worker decompresses it
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        key: Some(String::from("hunter2")),
+        ..Default::default()
+    });
+    let ArchiveHeader(files, ..) = archiver.read_archive_header().ok().unwrap();
+    let entry = archiver.read_entries(files).ok().unwrap()
+        .into_iter()
+        .find(|entry| entry.name == "scan.bmp")
+        .unwrap();
+    let mut bytes = fs::read(&archive).unwrap();
+    bytes[entry.data_offset() as usize + 40] ^= 0xff;
+    fs::write(&archive, bytes).unwrap();
+
+    let output = dir.join("damaged");
+    match extract(&output) {
+        Err(ArchivalError(e)) => assert!(e.contains("scan.bmp"), "{}", e),
+        Ok(_) => panic!("Extracted a damaged block!"),
+    }
+    assert!(!output.join("scan.bmp").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_split_volumes() {
+    assert_eq!(Some(4 * 1024 * 1024 * 1024), parse_bytes("4GB"));
+    assert_eq!(Some(1536), parse_bytes("1.5kb"));
+    assert_eq!(Some(1 << 40), parse_bytes("1TB"));
+    assert_eq!(None, parse_bytes("4 parsecs"));
+
+    let dir = test_dir("split_volumes");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("first.txt"), "spans volumes ".repeat(200)).unwrap();
+    fs::write(input.join("second.txt"), "so does this ".repeat(200)).unwrap();
+
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        volume_size: Some(1000),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    assert!(!archive.exists());
+    assert_eq!(1000, fs::metadata(dir.join("archive.ark.001")).unwrap().len());
+    assert!(dir.join("archive.ark.006").exists());
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &dir.join("archive.ark.001"), &output);
+    assert_eq!("spans volumes ".repeat(200), fs::read_to_string(output.join("first.txt")).unwrap());
+    assert_eq!("so does this ".repeat(200), fs::read_to_string(output.join("second.txt")).unwrap());
+
+    // a volume which can't be replaced leaves the old archive whole
+    let before = fs::read(dir.join("archive.ark.002")).unwrap();
+    fs::write(input.join("third.txt"), "needs more volumes ".repeat(400)).unwrap();
+    fs::create_dir(dir.join("archive.ark.009")).unwrap();
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        volume_size: Some(1000),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("archive.ark.009"), "{}", e),
+        Ok(_) => panic!("Replaced a volume which is a directory!"),
+    }
+    assert_eq!(before, fs::read(dir.join("archive.ark.002")).unwrap());
+    assert!(!dir.join("archive.ark.007").exists());
+    fs::remove_dir(dir.join("archive.ark.009")).unwrap();
+    fs::remove_file(input.join("third.txt")).unwrap();
+
+    fs::remove_file(dir.join("archive.ark.003")).unwrap();
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(dir.join("archive.ark.001")),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("archive.ark.003"), "{}", e),
+        Ok(_) => panic!("Incomplete archive was listed!"),
+    }
+
+    fs::remove_file(dir.join("archive.ark.001")).unwrap();
+    match VolumeReader::open(&dir.join("archive.ark.001")) {
+        Err(e) => assert!(e.to_string().contains("volume \"") && e.to_string().contains("archive.ark.001"), "{}", e),
+        Ok(_) => panic!("Opened an archive without its first volume!"),
+    }
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(dir.join("archive.ark.001")),
+        output: Some(output.clone()),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("is incomplete") && e.contains("archive.ark.001"), "{}", e),
+        Ok(_) => panic!("Extracted an archive without its first volume!"),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_solid_blocks() {
+    let dir = test_dir("solid_blocks");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    for i in 0..10 {
+        fs::write(input.join(format!("small{}.txt", i)), format!("small file {} ", i).repeat(20)).unwrap();
+    }
+    fs::write(input.join("large.txt"), "too large for a block ".repeat(100)).unwrap();
+
+    let archive = dir.join("archive.ark");
+    let add = |archive: &PathBuf| {
+        let mut archiver = Archiver::new(Command {
+            mode: Some(Mode::Add),
+            input: Some(input.clone()),
+            output: Some(archive.clone()),
+            solid_block_size: Some(1024),
+            ..Default::default()
+        });
+        assert!(archiver.operate().is_ok());
+    };
+    add(&archive);
+
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        ..Default::default()
+    });
+    let ArchiveHeader(files, _, _) = archiver.read_archive_header().ok().unwrap();
+    let entries = archiver.read_entries(files).ok().unwrap();
+    // the small files share a few blocks, the large one is stored on its own
+    assert!(archiver.blocks.len() > 1 && archiver.blocks.len() < 10);
+    for entry in &entries {
+        assert_eq!(entry.name != "large.txt", entry.method & SOLID != 0, "{}", entry.name);
+    }
+
+    // replacing one file keeps the blocks holding the others
+    fs::write(input.join("small3.txt"), "changed").unwrap();
+    add(&archive);
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &archive, &output);
+    for i in 0..10 {
+        let expected = match i {
+            3 => "changed".to_owned(),
+            i => format!("small file {} ", i).repeat(20),
+        };
+        assert_eq!(expected, fs::read_to_string(output.join(format!("small{}.txt", i))).unwrap());
+    }
+    assert_eq!("too large for a block ".repeat(100), fs::read_to_string(output.join("large.txt")).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_read_from_stream() {
+    let bytes = fs::read("src/tests/archive.ark").unwrap();
+    let mut reader = VolumeReader::from_stream(Cursor::new(bytes.clone()));
+    assert!(reader.is_stream() && !reader.is_split());
+    assert!(Header::read_archive(&mut reader).is_ok());
+
+    // a stream is only ever read forwards
+    reader.seek(SeekFrom::Start(40)).unwrap();
+    assert!(reader.seek(SeekFrom::Start(0)).is_err());
+    assert!(reader.seek(SeekFrom::End(-20)).is_err());
+    let mut rest = vec![];
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(&bytes[40..], rest.as_slice());
+
+    let dir = test_dir("read_from_stream");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("sub")).unwrap();
+    for i in 0..10 {
+        fs::write(input.join(format!("small{}.txt", i)), format!("small file {} ", i).repeat(20)).unwrap();
+    }
+    let large = (0..200_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect::<Vec<u8>>();
+    fs::write(input.join("large.bin"), &large).unwrap();
+    fs::write(input.join("sub/copy.bin"), &large).unwrap();
+
+    // chunks and solid blocks come before the files using them, so one pass is enough
+    let stream = |mode: Mode, archive: &PathBuf, output: &PathBuf, key: Option<&str>| {
+        let mut archiver = Archiver::new(Command {
+            mode: Some(mode),
+            input: Some(archive.clone()),
+            output: Some(output.clone()),
+            key: key.map(str::to_owned),
+            ..Default::default()
+        });
+        let bytes = fs::read(archive).unwrap();
+        archiver.archive_reader = Some(BufReader::new(VolumeReader::from_stream(Cursor::new(bytes))));
+        archiver.operate()
+    };
+    let variants = [
+        ("plain", false, None, None),
+        ("dedup", true, None, None),
+        ("solid", false, Some(1024), None),
+        ("encrypted", false, Some(1024), Some("hunter2")),
+    ];
+    for (name, dedup, solid_block_size, key) in variants {
+        let archive = dir.join(format!("{}.ark", name));
+        let mut archiver = Archiver::new(Command {
+            mode: Some(Mode::Add),
+            input: Some(input.clone()),
+            output: Some(archive.clone()),
+            dedup,
+            solid_block_size,
+            key: key.map(str::to_owned),
+            ..Default::default()
+        });
+        assert!(archiver.operate().is_ok(), "{}", name);
+
+        let output = dir.join(name);
+        assert!(stream(Mode::Extract, &archive, &output, key).is_ok(), "{}", name);
+        for i in 0..10 {
+            assert_eq!(
+                format!("small file {} ", i).repeat(20),
+                fs::read_to_string(output.join(format!("small{}.txt", i))).unwrap()
+            );
+        }
+        assert_eq!(large, fs::read(output.join("large.bin")).unwrap());
+        assert_eq!(large, fs::read(output.join("sub/copy.bin")).unwrap());
+        assert!(stream(Mode::Verify, &archive, &output, key).is_ok(), "{}", name);
+        assert!(stream(Mode::List, &archive, &output, key).is_ok(), "{}", name);
+    }
+
+    // nothing that has to go back over the archive is done to a stream
+    match stream(Mode::Remove, &dir.join("plain.ark"), &dir.join("removed.ark"), None) {
+        Err(ArchivalError(e)) => assert!(e.contains("can only be extracted"), "{}", e),
+        Ok(_) => panic!("Removed entries from a stream!"),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_encryption() {
+    let dir = test_dir("encryption");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("secret.txt"), "the launch codes are 0000".repeat(10)).unwrap();
+
+    let archive = dir.join("archive.ark");
+    let with_key = |mode: Mode, input: &PathBuf, output: &PathBuf, key: &str| {
+        Archiver::new(Command {
+            mode: Some(mode),
+            input: Some(input.clone()),
+            output: Some(output.clone()),
+            key: Some(key.to_owned()),
+            ..Default::default()
+        }).operate()
+    };
+    assert!(with_key(Mode::Add, &input, &archive, "hunter2").is_ok());
+    let bytes = fs::read(&archive).unwrap();
+    assert!(!bytes.windows(12).any(|w| w == b"launch codes"));
+
+    let output = dir.join("output");
+    match with_key(Mode::Extract, &archive, &output, "hunter3") {
+        Err(ArchivalError(e)) => assert!(e.contains("wrong password"), "{}", e),
+        Ok(_) => panic!("Extracted with the wrong password!"),
+    }
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_err());
+    assert!(!output.join("secret.txt").exists());
+
+    assert!(with_key(Mode::Extract, &archive, &output, "hunter2").is_ok());
+    assert_eq!(
+        "the launch codes are 0000".repeat(10),
+        fs::read_to_string(output.join("secret.txt")).unwrap()
+    );
+    fs::remove_dir_all(&output).unwrap();
+
+    // a slot asking for more memory or passes than allowed is refused before deriving anything
+    for field in [0, 4] {
+        let mut costly = bytes.clone();
+        let at = Header::ARCHIVE_HEADER_SIZE + 1 + 16 + field;
+        costly[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&archive, &costly).unwrap();
+        match with_key(Mode::Extract, &archive, &output, "hunter2") {
+            Err(ArchivalError(e)) => assert!(e.contains("key derivation costs"), "{}", e),
+            Ok(_) => panic!("Derived a key with excessive costs!"),
+        }
+        assert!(!output.join("secret.txt").exists());
+    }
+
+    // flip a bit of the sealed file data, just before the central directory
+    let mut tampered = bytes.clone();
+    let directory_offset = u64::from_le_bytes(tampered[tampered.len() - 16..tampered.len() - 8].try_into().unwrap());
+    tampered[directory_offset as usize - 20] ^= 1;
+    fs::write(&archive, tampered).unwrap();
+    match with_key(Mode::Extract, &archive, &output, "hunter2") {
+        Err(ArchivalError(e)) => assert!(e.contains("tampered"), "{}", e),
+        Ok(_) => panic!("Extracted tampered data!"),
+    }
+    assert!(!output.join("secret.txt").exists());
+
+    // set the setuid bit of the file in its header, then in the central directory as well
+    let names = bytes.windows(10)
+        .enumerate()
+        .filter(|(_, w)| w == b"secret.txt")
+        .map(|(i, _)| i + 10)
+        .collect::<Vec<usize>>();
+    let mut tampered = bytes.clone();
+    tampered[names[0] + 22] |= 0x08;
+    fs::write(&archive, &tampered).unwrap();
+    assert!(with_key(Mode::Extract, &archive, &output, "hunter2").is_err());
+    assert!(!output.join("secret.txt").exists());
+    tampered[names[1] + 31] |= 0x08;
+    fs::write(&archive, &tampered).unwrap();
+    match with_key(Mode::Extract, &archive, &output, "hunter2") {
+        Err(ArchivalError(e)) => assert!(e.contains("tampered"), "{}", e),
+        Ok(_) => panic!("Extracted a tampered header!"),
+    }
+    assert!(!output.join("secret.txt").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_encrypted_headers() {
+    let dir = test_dir("encrypted_headers");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("plans")).unwrap();
+    fs::write(input.join("plans/world-domination.txt"), "step one").unwrap();
+
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        key: Some("hunter2".to_owned()),
+        encrypt_headers: true,
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    let bytes = fs::read(&archive).unwrap();
+    assert!(!bytes.windows(5).any(|w| w == b"plans"));
+
+    // nothing can be listed without the password
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("-k"), "{}", e),
+        Ok(_) => panic!("Listed an archive with encrypted headers without its password!"),
+    }
+
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        key: Some("hunter2".to_owned()),
+        ..Default::default()
+    });
+    let ArchiveHeader(files, _, encrypted) = archiver.read_archive_header().ok().unwrap();
+    assert!(encrypted);
+    let names = archiver.read_entries(files).ok().unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect::<Vec<String>>();
+    assert_eq!(vec!["plans", "plans/world-domination.txt"], names);
+
+    let output = dir.join("output");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        key: Some("hunter2".to_owned()),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    assert_eq!("step one", fs::read_to_string(output.join("plans/world-domination.txt")).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_recipients() {
+    let dir = test_dir("recipients");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("note.txt"), "meet at noon").unwrap();
+
+    let (alice, bob) = (dir.join("alice.key"), dir.join("bob.key"));
+    for key in [&alice, &bob] {
+        let mut archiver = Archiver::new(Command {
+            mode: Some(Mode::Keygen),
+            input: Some(key.clone()),
+            ..Default::default()
+        });
+        assert!(archiver.operate().is_ok());
+    }
+    assert!(keys::public_key_path(&alice).is_file());
+    // a key pair is never overwritten
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Keygen),
+        input: Some(alice.clone()),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_err());
+
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        recipients: vec![keys::public_key_path(&alice), keys::public_key_path(&bob)],
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+
+    let extract_with = |identity: &PathBuf, output: &PathBuf| {
+        Archiver::new(Command {
+            mode: Some(Mode::Extract),
+            input: Some(archive.clone()),
+            output: Some(output.clone()),
+            identity: Some(identity.clone()),
+            ..Default::default()
+        }).operate()
+    };
+    for (key, output) in [(&alice, dir.join("alice")), (&bob, dir.join("bob"))] {
+        assert!(extract_with(key, &output).is_ok());
+        assert_eq!("meet at noon", fs::read_to_string(output.join("note.txt")).unwrap());
+    }
+
+    // bob can no longer open the archive, though alice still can
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Share),
+        input: Some(archive.clone()),
+        identity: Some(alice.clone()),
+        revoked: vec![keys::public_key_path(&bob)],
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    assert!(extract_with(&bob, &dir.join("revoked")).is_err());
+    assert!(!dir.join("revoked/note.txt").exists());
+    fs::remove_dir_all(dir.join("alice")).unwrap();
+    assert!(extract_with(&alice, &dir.join("alice")).is_ok());
+
+    // and alice can let bob back in
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Share),
+        input: Some(archive.clone()),
+        identity: Some(alice.clone()),
+        recipients: vec![keys::public_key_path(&bob)],
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    assert!(extract_with(&bob, &dir.join("readded")).is_ok());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rekey() {
+    let dir = test_dir("rekey");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("diary.txt"), "dear diary".repeat(100)).unwrap();
+
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        key: Some("hunter2".to_owned()),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    let before = fs::read(&archive).unwrap();
+
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Rekey),
+        input: Some(archive.clone()),
+        key: Some("hunter2".to_owned()),
+        new_key: Some("correct horse".to_owned()),
+        kdf_memory: Some(parse_bytes("8mb").unwrap()),
+        kdf_passes: Some(1),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+
+    // only the key slots change, every entry is left exactly as it was
+    let after = fs::read(&archive).unwrap();
+    let slots = Header::ARCHIVE_HEADER_SIZE..Header::ARCHIVE_HEADER_SIZE + KeySlot::TABLE_SIZE;
+    assert_eq!(before.len(), after.len());
+    assert_ne!(before[slots.clone()], after[slots.clone()]);
+    assert_eq!(before[slots.end..], after[slots.end..]);
+
+    let extract_with = |key: &str, output: &PathBuf| {
+        Archiver::new(Command {
+            mode: Some(Mode::Extract),
+            input: Some(archive.clone()),
+            output: Some(output.clone()),
+            key: Some(key.to_owned()),
+            ..Default::default()
+        }).operate()
+    };
+    match extract_with("hunter2", &dir.join("old")) {
+        Err(ArchivalError(e)) => assert!(e.contains("wrong password"), "{}", e),
+        Ok(_) => panic!("Extracted with the old password!"),
+    }
+    assert!(extract_with("correct horse", &dir.join("new")).is_ok());
+    assert_eq!("dear diary".repeat(100), fs::read_to_string(dir.join("new/diary.txt")).unwrap());
+
+    // a re-key interrupted after the new slot is written still opens with either password
+    fs::write(&archive, &before).unwrap();
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Rekey),
+        input: Some(archive.clone()),
+        key: Some("hunter2".to_owned()),
+        ..Default::default()
+    });
+    assert!(archiver.read_archive_header().is_ok());
+    assert!(archiver.cipher.as_mut().unwrap().replace_password("correct horse", 8 * 1024, 1).is_ok());
+    assert!(archiver.add_key_slots().is_ok());
+    drop(archiver);
+    assert_eq!(before[slots.end..], fs::read(&archive).unwrap()[slots.end..]);
+    assert!(extract_with("hunter2", &dir.join("interrupted_old")).is_ok());
+    assert!(extract_with("correct horse", &dir.join("interrupted_new")).is_ok());
+    assert_eq!("dear diary".repeat(100), fs::read_to_string(dir.join("interrupted_old/diary.txt")).unwrap());
+
+    // the recovery record follows the new key slots, so a repair can't bring the old ones back
+    fs::remove_file(&archive).unwrap();
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        key: Some("hunter2".to_owned()),
+        recovery: Some(10),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Rekey),
+        input: Some(archive.clone()),
+        key: Some("hunter2".to_owned()),
+        new_key: Some("correct horse".to_owned()),
+        kdf_memory: Some(parse_bytes("8mb").unwrap()),
+        kdf_passes: Some(1),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    let rekeyed = fs::read(&archive).unwrap();
+
+    let mut damaged = rekeyed.clone();
+    damaged[slots.start + 10] ^= 0xff;
+    fs::write(&archive, &damaged).unwrap();
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Repair),
+        input: Some(archive.clone()),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    assert_eq!(rekeyed, fs::read(&archive).unwrap());
+    match extract_with("hunter2", &dir.join("old")) {
+        Err(ArchivalError(e)) => assert!(e.contains("wrong password"), "{}", e),
+        Ok(_) => panic!("Extracted with the old password after a repair!"),
+    }
+    assert!(extract_with("correct horse", &dir.join("repaired")).is_ok());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_signatures() {
+    let dir = test_dir("signatures");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("installer.sh"), "echo installing").unwrap();
+    fs::write(input.join("readme.txt"), "run the installer").unwrap();
+
+    let (signer, stranger) = (dir.join("signer.key"), dir.join("stranger.key"));
+    for key in [&signer, &stranger] {
+        let mut archiver = Archiver::new(Command {
+            mode: Some(Mode::Keygen),
+            input: Some(key.clone()),
+            signing: true,
+            ..Default::default()
+        });
+        assert!(archiver.operate().is_ok());
+    }
+
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        sign: Some(signer.clone()),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+
+    let check = |trusted: &PathBuf| {
+        Archiver::new(Command {
+            mode: Some(Mode::VerifySignature),
+            input: Some(archive.clone()),
+            trust: Some(keys::public_key_path(trusted)),
+            ..Default::default()
+        }).operate()
+    };
+    assert!(check(&signer).is_ok());
+    match check(&stranger) {
+        Err(ArchivalError(e)) => assert!(e.contains("different key"), "{}", e),
+        Ok(_) => panic!("Trusted a signature from the wrong key!"),
+    }
+
+    // the signature doesn't get in the way of reading the archive
+    run_command(Mode::Extract, &archive, &dir.join("output"));
+    assert_eq!("echo installing", fs::read_to_string(dir.join("output/installer.sh")).unwrap());
+
+    // only the entry which was changed is reported
+    let mut bytes = fs::read(&archive).unwrap();
+    let at = bytes.windows(15).position(|w| w == b"echo installing").unwrap();
+    bytes[at] = b'E';
+    fs::write(&archive, bytes).unwrap();
+    match check(&signer) {
+        Err(ArchivalError(e)) => {
+            assert!(e.contains("installer.sh"), "{}", e);
+            assert!(!e.contains("readme.txt"), "{}", e);
+        }
+        Ok(_) => panic!("Modified archive passed its signature check!"),
+    }
+
+    // an unsigned archive has nothing to check
+    run_command(Mode::Add, &input, &archive);
+    match check(&signer) {
+        Err(ArchivalError(e)) => assert!(e.contains("isn't signed"), "{}", e),
+        Ok(_) => panic!("Unsigned archive passed its signature check!"),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_recovery_record() {
+    let dir = test_dir("recovery_record");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    let mut state = 7u32;
+    let photos = (0..200 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect::<Vec<u8>>();
+    fs::write(input.join("photos.raw"), &photos).unwrap();
+
+    let archive = dir.join("archive.ark");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(archive.clone()),
+        recovery: Some(10),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+    let healthy = fs::read(&archive).unwrap();
+
+    let repair = || {
+        Archiver::new(Command {
+            mode: Some(Mode::Repair),
+            input: Some(archive.clone()),
+            ..Default::default()
+        }).operate()
+    };
+    assert!(repair().is_ok());
+    assert_eq!(healthy, fs::read(&archive).unwrap());
+
+    // a run of damaged blocks in the middle, along with the footer
+    let mut damaged = healthy.clone();
+    for byte in &mut damaged[40_000..52_000] {
+        *byte ^= 0xff;
+    }
+    let len = damaged.len();
+    damaged[len - 10] ^= 1;
+    fs::write(&archive, &damaged).unwrap();
+    assert!(repair().is_ok());
+    assert_eq!(healthy, fs::read(&archive).unwrap());
+    run_command(Mode::Extract, &archive, &dir.join("output"));
+    assert_eq!(photos, fs::read(dir.join("output/photos.raw")).unwrap());
+
+    // far more damage than the parity covers is reported, and leaves the archive alone
+    let mut ruined = healthy.clone();
+    for byte in &mut ruined[10_000..120_000] {
+        *byte = 0;
+    }
+    fs::write(&archive, &ruined).unwrap();
+    match repair() {
+        Err(ArchivalError(e)) => assert!(e.contains("could not be recovered"), "{}", e),
+        Ok(_) => panic!("Repaired an archive beyond its parity!"),
+    }
+    assert_eq!(ruined, fs::read(&archive).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_checksum_mismatch() {
+    let dir = test_dir("checksum_mismatch");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("data.txt"), "some very important data").unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    // flip a bit in the middle of the stored file data
+    let mut bytes = fs::read(&archive).unwrap();
+    let data_start = Header::ARCHIVE_HEADER_SIZE + Header::FILE_HEADER_SIZE + "data.txt".len();
+    bytes[data_start + 5] ^= 0b0000_0100;
+    fs::write(&archive, bytes).unwrap();
+
+    let output = dir.join("output");
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(output.clone()),
+        ..Default::default()
+    });
+    match archiver.operate() {
+        Err(ArchivalError(e)) => assert!(e.contains("data.txt"), "{}", e),
+        Ok(_) => panic!("Corrupt entry was extracted!"),
+    }
+    assert!(!output.join("data.txt").exists());
+
+    assert_eq!(
+        Err(EntryError::ChecksumMismatch {
+            name: "data.txt".to_owned(),
+            expected: integrity::checksum(b"some very important data"),
+            actual: integrity::checksum(b"some very important dbta"),
+        }),
+        integrity::verify_entry(
+            "data.txt", b"some very important dbta", 24,
+            integrity::checksum(b"some very important data")
+        )
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_salvage() {
+    let dir = test_dir("salvage");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    for name in ["first.txt", "second.txt", "third.txt"] {
+        fs::write(input.join(name), format!("the contents of {}", name).repeat(50)).unwrap();
+    }
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+    let healthy = fs::read(&archive).unwrap();
+
+    let salvage = |output: &PathBuf| {
+        Archiver::new(Command {
+            mode: Some(Mode::Extract),
+            input: Some(archive.clone()),
+            output: Some(output.clone()),
+            salvage: true,
+            ..Default::default()
+        }).operate()
+    };
+    assert!(salvage(&dir.join("healthy")).is_ok());
+
+    // break the signature of the second file's header, which stops a normal extraction
+    let name = healthy.windows(10).position(|w| w == b"second.txt").unwrap();
+    let mut damaged = healthy.clone();
+    damaged[name - 8] ^= 0xff;
+    fs::write(&archive, &damaged).unwrap();
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Extract),
+        input: Some(archive.clone()),
+        output: Some(dir.join("output")),
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_err());
+
+    // with and without the central directory to find the next header,
+    // which also names the entry that was lost
+    let mut no_directory = damaged.clone();
+    let len = no_directory.len();
+    no_directory[len - 20] ^= 0xff;
+    for (i, bytes) in [damaged, no_directory].iter().enumerate() {
+        fs::write(&archive, bytes).unwrap();
+        let output = dir.join(format!("salvaged{}", i));
+        match salvage(&output) {
+            Err(ArchivalError(e)) => {
+                assert!(e.contains("1 ranges skipped"), "{}", e);
+                assert_eq!(i == 0, e.contains("1 entries were lost"), "{}", e);
+            }
+            Ok(_) => panic!("Salvaged a damaged archive without reporting it!"),
+        }
+        for name in ["first.txt", "third.txt"] {
+            assert_eq!(fs::read(input.join(name)).unwrap(), fs::read(output.join(name)).unwrap());
+        }
+        assert!(!output.join("second.txt").exists());
+    }
+
+    // a damaged archive header is skipped over, whether the archive is encrypted or not
+    let mut encrypted = Archiver::new(Command {
+        mode: Some(Mode::Add),
+        input: Some(input.clone()),
+        output: Some(dir.join("encrypted.ark")),
+        key: Some("hunter2".to_owned()),
+        ..Default::default()
+    });
+    assert!(encrypted.operate().is_ok());
+    for (i, (bytes, key)) in [(healthy, None), (fs::read(dir.join("encrypted.ark")).unwrap(), Some("hunter2"))]
+        .into_iter()
+        .enumerate()
+    {
+        let mut headless = bytes.clone();
+        headless[..8].fill(0);
+        fs::write(&archive, &headless).unwrap();
+        let output = dir.join(format!("headless{}", i));
+        let res = Archiver::new(Command {
+            mode: Some(Mode::Extract),
+            input: Some(archive.clone()),
+            output: Some(output.clone()),
+            key: key.map(str::to_owned),
+            salvage: true,
+            ..Default::default()
+        }).operate();
+        match res {
+            Err(ArchivalError(e)) => assert!(e.contains("0 entries were lost and 1 ranges skipped"), "{}", e),
+            Ok(_) => panic!("Salvaged an archive with a damaged header without reporting it!"),
+        }
+        for name in ["first.txt", "second.txt", "third.txt"] {
+            assert_eq!(fs::read(input.join(name)).unwrap(), fs::read(output.join(name)).unwrap());
+        }
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_verify() {
+    let dir = test_dir("verify");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("data.txt"), "some very important data").unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    let verify = |archive: &PathBuf| Archiver::new(Command {
+        mode: Some(Mode::Verify),
+        input: Some(archive.clone()),
+        ..Default::default()
+    }).operate();
+    assert!(verify(&archive).is_ok());
+
+    // flip a bit in the middle of the stored file data
+    let mut bytes = fs::read(&archive).unwrap();
+    let data_start = Header::ARCHIVE_HEADER_SIZE + Header::FILE_HEADER_SIZE + "data.txt".len();
+    bytes[data_start + 5] ^= 0b0000_0100;
+    fs::write(&archive, bytes).unwrap();
+
+    match verify(&archive) {
+        Err(ArchivalError(e)) => assert!(e.contains("data.txt"), "{}", e),
+        Ok(_) => panic!("Corrupt archive passed verification!"),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_update_archive() {
+    let dir = test_dir("update_archive");
+    let input = dir.join("input");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("kept.txt"), "only in the first version").unwrap();
+    fs::write(input.join("changed.txt"), "before").unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+
+    fs::remove_file(input.join("kept.txt")).unwrap();
+    fs::write(input.join("changed.txt"), "after the update").unwrap();
+    fs::write(input.join("new.txt"), "added later").unwrap();
+    run_command(Mode::Add, &input, &archive);
+
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        ..Default::default()
+    });
+    let ArchiveHeader(files, version, _) = archiver.read_archive_header().ok().unwrap();
+    assert_eq!(3, files);
+    assert_eq!(2, version);
+    assert_eq!(3, archiver.read_entries(files).ok().unwrap().len());
+    assert!(!dir.join("archive.ark.tmp").exists());
+
+    let output = dir.join("output");
+    run_command(Mode::Extract, &archive, &output);
+    assert_eq!("only in the first version", fs::read_to_string(output.join("kept.txt")).unwrap());
+    assert_eq!("after the update", fs::read_to_string(output.join("changed.txt")).unwrap());
+    assert_eq!("added later", fs::read_to_string(output.join("new.txt")).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_remove_entries() {
+    let dir = test_dir("remove_entries");
+    let input = dir.join("input");
+    fs::create_dir_all(input.join("mistakes")).unwrap();
+    fs::write(input.join("keep.txt"), "keep me").unwrap();
+    fs::write(input.join("mistakes/oops.txt"), "should not be here").unwrap();
+    fs::write(input.join("mistakes/oops.bmp"), [7u8; 600]).unwrap();
+
+    let archive = dir.join("archive.ark");
+    run_command(Mode::Add, &input, &archive);
+    let before = fs::read(&archive).unwrap();
+
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::Remove),
+        input: Some(archive.clone()),
+        filters: vec!["mistakes".to_owned()],
+        ..Default::default()
+    });
+    assert!(archiver.operate().is_ok());
+
+    let mut archiver = Archiver::new(Command {
+        mode: Some(Mode::List),
+        input: Some(archive.clone()),
+        ..Default::default()
+    });
+    let ArchiveHeader(files, version, _) = archiver.read_archive_header().ok().unwrap();
+    let entries = archiver.read_entries(files).ok().unwrap();
+    assert_eq!(1, files);
+    assert_eq!(2, version);
+    assert_eq!("keep.txt", entries[0].name);
+
+    // the remaining entry is carried over without being recompressed
+    let after = fs::read(&archive).unwrap();
+    let entry = &after[entries[0].offset as usize..(entries[0].data_offset() + 7) as usize];
+    assert!(before.windows(entry.len()).any(|w| w == entry));
+
+    fs::remove_dir_all(&dir).unwrap();
+}